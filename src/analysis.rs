@@ -0,0 +1,1044 @@
+/*
+ * Copyright (C) Simon Werner, 2022.
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Analysis of a computed spectrogram
+//!
+//! This module contains functions that extract features from the linear
+//! magnitudes of a [Spectrogram], for example the average spectrum and its
+//! peaks.  They expect the spectrogram was not converted to dB.
+
+use std::f32::consts::PI;
+
+use crate::{mel_filterbank, FrequencyScale, Spectrogram};
+
+/// Onsets closer together than this, in seconds, are merged into one.
+const MIN_ONSET_INTERVAL: f32 = 0.05;
+
+/// The percentile of the dB values taken as the noise floor by
+/// [Spectrogram::dynamic_range_db].
+const NOISE_FLOOR_PERCENTILE: f32 = 0.05;
+
+/// A frame is voiced when its harmonic product spectrum peak is this many
+/// times the mean magnitude of the frame.
+const VOICING_THRESHOLD: f32 = 5.0;
+
+impl Spectrogram {
+    ///
+    /// Compute the time-averaged magnitude spectrum.  The result has one value
+    /// per frequency bin, index 0 is DC and the last index is the bin just
+    /// below the nyquist frequency.  For a two-sided spectrogram index 0 is
+    /// the most negative frequency.
+    ///
+    pub fn average_spectrum(&self) -> Vec<f32> {
+        let mut avg = vec![0.0; self.height];
+        if self.width == 0 {
+            return avg;
+        }
+
+        // The rows are stored with the highest frequency first
+        for (k, val) in avg.iter_mut().enumerate() {
+            let row = self.height - 1 - k;
+            let sum: f32 = self.spec[row * self.width..(row + 1) * self.width]
+                .iter()
+                .sum();
+            *val = sum / self.width as f32;
+        }
+
+        avg
+    }
+
+    ///
+    /// Whiten the spectrogram by dividing each frequency bin by its average
+    /// magnitude over time, see [Spectrogram::average_spectrum].  This
+    /// flattens a coloured noise floor, so faint events in the quieter bins
+    /// become visible.  Bins that are always zero are left unchanged.
+    ///
+    /// If the spectrogram is in dB, see [Spectrogram::is_db], the average is
+    /// taken of the linear magnitudes and subtracted in dB, so the values
+    /// stay in dB.
+    ///
+    pub fn whiten(&mut self) {
+        let width = self.width.max(1);
+        let is_db = self.is_db;
+
+        for row in self.spec.chunks_mut(width) {
+            let mean = row
+                .iter()
+                .map(|val| {
+                    if is_db {
+                        10.0_f32.powf(val / 20.0)
+                    } else {
+                        *val
+                    }
+                })
+                .sum::<f32>()
+                / row.len() as f32;
+            if mean > 0.0 {
+                if is_db {
+                    let mean_db = 20.0 * mean.log10();
+                    row.iter_mut().for_each(|val| *val -= mean_db);
+                } else {
+                    row.iter_mut().for_each(|val| *val /= mean);
+                }
+            }
+        }
+    }
+
+    ///
+    /// Smooth each frequency bin over time with a centered moving average of
+    /// `window` columns, to reduce the flicker of a noisy spectrogram.  At the
+    /// edges the window is shrunk to the columns that exist.
+    ///
+    /// # Arguments
+    ///
+    ///  * `window` - The width of the moving average, in columns.
+    ///
+    pub fn smooth_time(&mut self, window: usize) {
+        if window <= 1 || self.width == 0 {
+            return;
+        }
+        let (before, after) = ((window - 1) / 2, window / 2);
+
+        let mut cumulative = vec![0.0; self.width + 1];
+        for row in self.spec.chunks_mut(self.width) {
+            for (w, val) in row.iter().enumerate() {
+                cumulative[w + 1] = cumulative[w] + val;
+            }
+            for (w, val) in row.iter_mut().enumerate() {
+                let (start, end) = (
+                    w.saturating_sub(before),
+                    usize::min(w + after + 1, self.width),
+                );
+                *val = (cumulative[end] - cumulative[start]) / (end - start) as f32;
+            }
+        }
+    }
+
+    ///
+    /// Blur the whole spectrogram with a 2D Gaussian, over both time and
+    /// frequency, for a softer look.  The blur is separable, so it is applied
+    /// along the rows and then the columns.  The kernel extends to 3 `sigma`
+    /// and near the edges it is shrunk to the cells that exist.
+    ///
+    /// # Arguments
+    ///
+    ///  * `sigma` - The standard deviation of the Gaussian, in cells.
+    ///
+    pub fn blur(&mut self, sigma: f32) {
+        if sigma <= 0.0 || self.spec.is_empty() {
+            return;
+        }
+
+        let radius = (3.0 * sigma).ceil() as usize;
+        let kernel: Vec<f32> = (0..=radius)
+            .map(|i| (-((i * i) as f32) / (2.0 * sigma * sigma)).exp())
+            .collect();
+
+        // Along the rows, then along the columns
+        let mut line = vec![];
+        for row in self.spec.chunks_mut(self.width) {
+            line.clear();
+            line.extend_from_slice(row);
+            blur_line(&line, &kernel, row.iter_mut());
+        }
+        for w in 0..self.width {
+            line.clear();
+            line.extend(self.spec[w..].iter().step_by(self.width));
+            blur_line(
+                &line,
+                &kernel,
+                self.spec[w..].iter_mut().step_by(self.width),
+            );
+        }
+    }
+
+    ///
+    /// Compute the energy of each time frame, the sum of the squared
+    /// magnitudes in each column.  By Parseval's theorem this is proportional
+    /// to the energy of the windowed samples.
+    ///
+    pub fn frame_energy(&self) -> Vec<f32> {
+        let mut energy = vec![0.0; self.width];
+        for row in self.row_iter() {
+            for (e, val) in energy.iter_mut().zip(row) {
+                *e += val * val;
+            }
+        }
+        energy
+    }
+
+    ///
+    /// Compute the total energy of the spectrogram, the sum of the squared
+    /// magnitudes of every cell, e.g. to compare the loudness of recordings
+    /// analysed with the same settings.  The spectrogram must not be in dB.
+    ///
+    /// By Parseval's theorem this is related to the energy of the samples,
+    /// but it is not equal to it:
+    ///
+    ///  * Each window is weighted by the window function, so the energy is
+    ///    scaled by the mean of the squared window (1.0 for rectangular,
+    ///    0.375 for Hann).
+    ///  * Overlapping windows count each sample `num_bins / step_size` times.
+    ///  * Without [FftNormalisation](crate::FftNormalisation) each window's
+    ///    energy is multiplied by `num_bins`.
+    ///  * A one-sided spectrogram only keeps the positive frequencies, which
+    ///    hold half the energy of a real signal.
+    ///
+    /// So a sine of amplitude `A` with a rectangular window, no overlap and
+    /// no normalisation gives `num_bins^2 * A^2 / 4` per window.
+    ///
+    pub fn total_energy(&self) -> f32 {
+        self.spec.iter().map(|val| val * val).sum()
+    }
+
+    ///
+    /// Compute the dynamic range of the spectrogram in dB, the difference
+    /// between the loudest cell and the noise floor, e.g. to flag recordings
+    /// that are too quiet or clipped.  The values are converted to dB the
+    /// same way as when rendering, and the noise floor is the 5th percentile
+    /// of the dB values.  As rendering floors the values at 80 dB below the
+    /// peak the result is at most 80 dB.
+    ///
+    pub fn dynamic_range_db(&self) -> f32 {
+        let mut db = self.to_db_buffer(FrequencyScale::Linear, None);
+        if db.is_empty() {
+            return 0.0;
+        }
+
+        let max_db = db.iter().copied().fold(f32::MIN, f32::max);
+        max_db - percentile(&mut db, NOISE_FLOOR_PERCENTILE)
+    }
+
+    ///
+    /// Compute the spectral flux of each time frame, the sum of the increases
+    /// in magnitude of each bin since the previous frame.  The first frame has
+    /// no predecessor so its flux is zero.
+    ///
+    pub fn spectral_flux(&self) -> Vec<f32> {
+        let mut flux = vec![0.0; self.width];
+        for row in self.row_iter() {
+            for (f, pair) in flux.iter_mut().skip(1).zip(row.windows(2)) {
+                *f += f32::max(0.0, pair[1] - pair[0]);
+            }
+        }
+        flux
+    }
+
+    ///
+    /// Detect the onsets of events, e.g. notes or beats.  The onsets are the
+    /// peaks in the [spectral flux](Spectrogram::spectral_flux) that exceed
+    /// `threshold`.  Onsets within 50 ms of each other are merged, keeping the
+    /// strongest.
+    ///
+    /// # Arguments
+    ///
+    ///  * `threshold` - The minimum flux, relative to the maximum flux, in the
+    ///    range 0.0 to 1.0.
+    ///
+    /// # Returns
+    ///
+    /// The time of each onset in seconds, see [Spectrogram::column_to_time].
+    /// This is the middle of the window by default, rather than the start as
+    /// in versions up to 0.7, see [TimeReference](crate::TimeReference).
+    ///
+    pub fn detect_onsets(&self, threshold: f32) -> Vec<f32> {
+        let flux = self.spectral_flux();
+        let max = flux.iter().copied().fold(0.0, f32::max);
+        if max <= 0.0 {
+            return vec![];
+        }
+
+        let mut onsets: Vec<(f32, f32)> = vec![]; // (time, flux)
+        for w in 1..flux.len() {
+            let val = flux[w] / max;
+            let is_peak = flux[w] >= flux[w - 1] && (w + 1 == flux.len() || flux[w] > flux[w + 1]);
+            if !is_peak || val < threshold {
+                continue;
+            }
+
+            let time = self.column_to_time(w);
+            match onsets.last_mut() {
+                Some(last) if time - last.0 < MIN_ONSET_INTERVAL => {
+                    if val > last.1 {
+                        *last = (time, val);
+                    }
+                }
+                _ => onsets.push((time, val)),
+            }
+        }
+
+        onsets.into_iter().map(|(time, _)| time).collect()
+    }
+
+    ///
+    /// Estimate the fundamental frequency (pitch) of each time frame using
+    /// the Harmonic Product Spectrum.  The spectrum is multiplied by copies
+    /// of itself compressed by 2, 3, ... `harmonics`, so the harmonics of the
+    /// fundamental line up and its bin has the largest product, even when
+    /// the fundamental itself is weak.  Frames where the peak of the product
+    /// does not stand out from the rest of the frame, such as silence or
+    /// noise, have no pitch.
+    ///
+    /// # Arguments
+    ///
+    ///  * `sample_rate` - The sample rate, in Hz, of the data.
+    ///  * `harmonics` - The number of harmonics to multiply, including the
+    ///    fundamental.
+    ///
+    /// # Returns
+    ///
+    /// The pitch, in Hz, of each time frame, or 0.0 if the frame is unvoiced.
+    ///
+    pub fn pitch_hps(&self, sample_rate: u32, harmonics: usize) -> Vec<f32> {
+        let num_bins = self.num_bins();
+        let harmonics = harmonics.max(1);
+        // The positive frequencies start this many rows from the bottom
+        let dc_offset = if self.two_sided { num_bins / 2 } else { 0 };
+        let num_positive = num_bins / 2;
+
+        (0..self.width)
+            .map(|w| {
+                // The magnitude spectrum, starting at DC
+                let mags: Vec<f32> = (0..num_positive)
+                    .map(|k| self.spec[(self.height - 1 - dc_offset - k) * self.width + w])
+                    .collect();
+                let mean = mags.iter().sum::<f32>() / num_positive as f32;
+                if mean <= 0.0 {
+                    return 0.0;
+                }
+
+                // The geometric mean of the harmonics, so it's comparable
+                // with the magnitudes, skipping DC
+                let (bin, peak) = (1..num_positive.div_ceil(harmonics))
+                    .map(|k| {
+                        let product: f32 = (1..=harmonics).map(|h| mags[k * h]).product();
+                        (k, product.powf(1.0 / harmonics as f32))
+                    })
+                    .fold((0, 0.0), |max, x| if x.1 > max.1 { x } else { max });
+
+                if peak > VOICING_THRESHOLD * mean {
+                    bin as f32 * sample_rate as f32 / num_bins as f32
+                } else {
+                    0.0
+                }
+            })
+            .collect()
+    }
+
+    ///
+    /// Compute the spectral rolloff of each time frame, the frequency below
+    /// which `percentile` of the energy of the frame lies.  The energy is
+    /// accumulated from DC upwards, only the positive frequencies of a
+    /// two-sided spectrogram are used.
+    ///
+    /// # Arguments
+    ///
+    ///  * `sample_rate` - The sample rate, in Hz, of the data.
+    ///  * `percentile` - The fraction of the energy, in the range 0.0 to 1.0,
+    ///    typically 0.85.
+    ///
+    /// # Returns
+    ///
+    /// The rolloff frequency, in Hz, of each time frame, or 0.0 if the frame
+    /// is silent.
+    ///
+    pub fn spectral_rolloff(&self, sample_rate: u32, percentile: f32) -> Vec<f32> {
+        let num_bins = self.num_bins();
+        // The positive frequencies start this many rows from the bottom
+        let dc_offset = if self.two_sided { num_bins / 2 } else { 0 };
+        let num_positive = num_bins / 2;
+
+        (0..self.width)
+            .map(|w| {
+                // The power spectrum, starting at DC
+                let power: Vec<f32> = (0..num_positive)
+                    .map(|k| self.spec[(self.height - 1 - dc_offset - k) * self.width + w].powi(2))
+                    .collect();
+                let total: f32 = power.iter().sum();
+                if total <= 0.0 {
+                    return 0.0;
+                }
+
+                let threshold = percentile.clamp(0.0, 1.0) * total;
+                let mut cumulative = 0.0;
+                let bin = power
+                    .iter()
+                    .position(|p| {
+                        cumulative += p;
+                        cumulative >= threshold
+                    })
+                    .unwrap_or(num_positive - 1);
+                bin as f32 * sample_rate as f32 / num_bins as f32
+            })
+            .collect()
+    }
+
+    ///
+    /// Compute the spectral flatness (Wiener entropy) of each time frame, the
+    /// geometric mean of the power spectrum divided by its arithmetic mean.
+    /// The result is in the range 0.0 to 1.0, close to 1.0 for noise and close
+    /// to 0.0 for a tone.  Silent frames are 0.0.  If the spectrogram is in
+    /// dB, see [Spectrogram::is_db], it is converted back to power first.
+    ///
+    pub fn spectral_flatness(&self) -> Vec<f32> {
+        let to_power = |val: f32| {
+            if self.is_db {
+                10.0_f32.powf(val / 10.0)
+            } else {
+                val * val
+            }
+        };
+        (0..self.width)
+            .map(|w| {
+                let power = (0..self.height).map(|row| to_power(self.spec[row * self.width + w]));
+                let mean = power.clone().sum::<f32>() / self.height as f32;
+                if mean <= 0.0 {
+                    return 0.0;
+                }
+
+                // Sum the logs, the product of many small values underflows
+                let floor = mean * 1e-20;
+                let log_mean = power.map(|p| p.max(floor).ln()).sum::<f32>() / self.height as f32;
+                (log_mean.exp() / mean).min(1.0)
+            })
+            .collect()
+    }
+
+    ///
+    /// Compute the mel-frequency cepstral coefficients (MFCCs) of each time
+    /// frame.  The power spectrum of each frame is passed through a mel
+    /// filterbank, the log is taken (floored 80 dB below the strongest
+    /// filter) and then the DCT-II (orthonormal) gives the coefficients.
+    ///
+    /// # Arguments
+    ///
+    ///  * `sample_rate` - The sample rate, in Hz, of the data.
+    ///  * `num_filters` - The number of mel filters.
+    ///  * `num_coeffs` - The number of coefficients to return for each frame,
+    ///    at most `num_filters`.
+    ///
+    /// # Returns
+    ///
+    /// `num_coeffs` coefficients for each time frame.
+    ///
+    pub fn mfcc(&self, sample_rate: u32, num_filters: usize, num_coeffs: usize) -> Vec<Vec<f32>> {
+        let num_coeffs = num_coeffs.min(num_filters);
+
+        self.mel_energies(sample_rate, num_filters)
+            .iter()
+            .map(|energy| {
+                // Floor the energies 80 dB below the strongest, as `to_db` does
+                let floor = energy.iter().fold(1e-10, |a: f32, &b| a.max(b)) * 1e-8;
+                let log_energy: Vec<f32> = energy.iter().map(|e| e.max(floor).ln()).collect();
+
+                dct2(&log_energy, num_coeffs)
+            })
+            .collect()
+    }
+
+    ///
+    /// Apply a bank of triangular mel filters to each time frame, see
+    /// [crate::mel_filterbank].  The result is a spectrogram with
+    /// `num_mel_bins` rows, the first row is the highest mel band.  Each value
+    /// is the square root of the filtered power, so it is a magnitude like
+    /// the values of the original spectrogram and can be converted to dB with
+    /// [Spectrogram::to_db_inplace].
+    ///
+    /// The rows are evenly spaced on the mel scale, not in Hz, so the
+    /// frequency based analysis functions do not apply to the result.
+    ///
+    /// # Arguments
+    ///
+    ///  * `sample_rate` - The sample rate, in Hz, of the data.
+    ///  * `num_mel_bins` - The number of mel filters, the height of the result.
+    ///
+    pub fn mel_spectrogram(&self, sample_rate: u32, num_mel_bins: usize) -> Spectrogram {
+        let energies = self.mel_energies(sample_rate, num_mel_bins);
+
+        let mut spec = vec![0.0; num_mel_bins * self.width];
+        for (w, energy) in energies.iter().enumerate() {
+            for (m, e) in energy.iter().enumerate() {
+                spec[(num_mel_bins - 1 - m) * self.width + w] = e.sqrt();
+            }
+        }
+
+        Spectrogram {
+            spec,
+            width: self.width,
+            height: num_mel_bins,
+            sample_rate,
+            step_size: self.step_size,
+            is_db: false,
+            two_sided: false,
+            window_len: self.window_len,
+            window_padding: self.window_padding,
+            time_reference: self.time_reference,
+        }
+    }
+
+    ///
+    /// The power in each mel filter, for each time frame.
+    ///
+    fn mel_energies(&self, sample_rate: u32, num_filters: usize) -> Vec<Vec<f32>> {
+        let filters = mel_filterbank(sample_rate, self.num_bins(), num_filters);
+
+        (0..self.width)
+            .map(|w| {
+                // The power spectrum, starting at DC
+                let power: Vec<f32> = (0..self.height)
+                    .rev()
+                    .map(|row| self.spec[row * self.width + w].powi(2))
+                    .collect();
+
+                filters
+                    .iter()
+                    .map(|filter| filter.iter().zip(&power).map(|(f, p)| f * p).sum())
+                    .collect()
+            })
+            .collect()
+    }
+
+    ///
+    /// Get the frequency, in Hz, of the given frequency bin, counted from the
+    /// bottom row.  The bin index may be fractional.
+    ///
+    pub(crate) fn bin_to_freq(&self, bin: f32) -> f32 {
+        let num_bins = self.num_bins();
+        let bin = if self.two_sided {
+            // The bottom row is the most negative frequency
+            bin - (num_bins / 2) as f32
+        } else {
+            bin
+        };
+        bin * self.sample_rate as f32 / num_bins as f32
+    }
+
+    ///
+    /// Get the frequency bin, counted from the bottom row, of the given
+    /// frequency in Hz.  This is the inverse of [Spectrogram::bin_to_freq],
+    /// the bin is fractional.
+    ///
+    pub(crate) fn freq_to_bin(&self, freq: f32) -> f32 {
+        let num_bins = self.num_bins();
+        let bin = freq * num_bins as f32 / self.sample_rate as f32;
+        if self.two_sided {
+            bin + (num_bins / 2) as f32
+        } else {
+            bin
+        }
+    }
+
+    /// The number of FFT bins used to compute the spectrogram.
+    pub(crate) fn num_bins(&self) -> usize {
+        if self.two_sided {
+            self.height
+        } else {
+            2 * self.height
+        }
+    }
+
+    ///
+    /// Find the most prominent peaks in the time-averaged spectrum.
+    ///
+    /// A peak is a local maximum, adjacent bins with the same value are merged
+    /// into a single peak located at their centre.  The first and last bins
+    /// can also be peaks.  The prominence of a peak is how far it rises above
+    /// the higher of the two minima found between it and the nearest higher
+    /// bin on either side (or the end of the spectrum).
+    ///
+    /// # Arguments
+    ///
+    ///  * `min_prominence` - Peaks with a lower prominence are discarded.
+    ///  * `max_peaks` - The maximum number of peaks to return.
+    ///
+    /// # Returns
+    ///
+    /// A list of `(frequency_hz, magnitude)` pairs, the most prominent first.
+    ///
+    pub fn find_peaks(&self, min_prominence: f32, max_peaks: usize) -> Vec<(f32, f32)> {
+        let avg = self.average_spectrum();
+
+        let mut peaks: Vec<(f32, f32, f32)> = find_peaks(&avg)
+            .into_iter()
+            .filter(|p| p.prominence >= min_prominence)
+            .map(|p| (self.bin_to_freq(p.bin), avg[p.start], p.prominence))
+            .collect();
+
+        peaks.sort_by(|a, b| b.2.total_cmp(&a.2));
+        peaks.truncate(max_peaks);
+
+        peaks.into_iter().map(|(f, mag, _)| (f, mag)).collect()
+    }
+}
+
+///
+/// Get the value at the given `percentile`, from 0.0 to 1.0, of `data`, using
+/// the nearest rank.  The data is sorted in place.
+///
+fn percentile(data: &mut [f32], percentile: f32) -> f32 {
+    data.sort_by(f32::total_cmp);
+    let rank = (percentile.clamp(0.0, 1.0) * (data.len() - 1) as f32).round();
+    data[rank as usize]
+}
+
+///
+/// The orthonormal type-II discrete cosine transform of `data`, returning the
+/// first `num_coeffs` coefficients.
+///
+fn dct2(data: &[f32], num_coeffs: usize) -> Vec<f32> {
+    let len = data.len() as f32;
+    (0..num_coeffs)
+        .map(|n| {
+            let sum: f32 = data
+                .iter()
+                .enumerate()
+                .map(|(m, val)| val * (PI * n as f32 * (m as f32 + 0.5) / len).cos())
+                .sum();
+            let scale = if n == 0 { 1.0 / len } else { 2.0 / len };
+            sum * scale.sqrt()
+        })
+        .collect()
+}
+
+/// A local maximum of a series of values.
+struct Peak {
+    start: usize,    // The first index of the peak (plateau)
+    bin: f32,        // The centre of the peak
+    prominence: f32, // The height above the surrounding minima
+}
+
+///
+/// Find all the local maxima in `data`, plateaus are treated as one peak.
+///
+fn find_peaks(data: &[f32]) -> Vec<Peak> {
+    let mut peaks = vec![];
+    let len = data.len();
+
+    let mut i = 0;
+    while i < len {
+        // Find the extent of the plateau starting at i
+        let start = i;
+        let mut end = i;
+        while end + 1 < len && data[end + 1] == data[start] {
+            end += 1;
+        }
+        i = end + 1;
+
+        let higher_left = start > 0 && data[start - 1] > data[start];
+        let higher_right = end + 1 < len && data[end + 1] > data[start];
+        if higher_left || higher_right {
+            continue;
+        }
+
+        // Walk each side until a higher value is found, tracking the minimum.  A
+        // side with no values (a peak at the boundary) is ignored.
+        let value = data[start];
+        let left_min = data[..start]
+            .iter()
+            .rev()
+            .take_while(|&&v| v <= value)
+            .copied()
+            .reduce(f32::min);
+        let right_min = data[end + 1..]
+            .iter()
+            .take_while(|&&v| v <= value)
+            .copied()
+            .reduce(f32::min);
+        let base = match (left_min, right_min) {
+            (Some(l), Some(r)) => f32::max(l, r),
+            (Some(b), None) | (None, Some(b)) => b,
+            (None, None) => 0.0,
+        };
+
+        peaks.push(Peak {
+            start,
+            bin: (start + end) as f32 / 2.0,
+            prominence: value - base,
+        });
+    }
+
+    peaks
+}
+
+///
+/// Convolve `line` with the symmetric `kernel`, given from its centre out,
+/// and write it to `out`.  The kernel is normalised over the part that
+/// overlaps the line.
+///
+fn blur_line<'a>(line: &[f32], kernel: &[f32], out: impl Iterator<Item = &'a mut f32>) {
+    let radius = kernel.len() as isize - 1;
+    for (i, val) in out.enumerate() {
+        let (mut sum, mut weight) = (0.0, 0.0);
+        for offset in -radius..=radius {
+            let j = usize::try_from(i as isize + offset).ok();
+            if let Some(x) = j.and_then(|j| line.get(j)) {
+                let k = kernel[offset.unsigned_abs()];
+                sum += x * k;
+                weight += k;
+            }
+        }
+        *val = sum / weight;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{hann_function, hz_to_mel, mel_to_hz, rectangular, test_spectrogram, SpecCompute};
+
+    #[test]
+    fn test_find_peaks() {
+        let data = vec![3.0, 1.0, 2.0, 2.0, 1.5, 5.0, 0.0];
+        let peaks = find_peaks(&data);
+
+        // Boundary peak
+        assert_eq!(peaks[0].start, 0);
+        assert!((peaks[0].prominence - 2.0).abs() < 0.0001);
+
+        // Plateau is merged
+        assert!((peaks[1].bin - 2.5).abs() < 0.0001);
+        assert!((peaks[1].prominence - 0.5).abs() < 0.0001);
+
+        assert_eq!(peaks[2].start, 5);
+        assert!((peaks[2].prominence - 4.0).abs() < 0.0001);
+        assert_eq!(peaks.len(), 3);
+    }
+
+    #[test]
+    fn test_whiten() {
+        // Noise with a falling spectrum, like pink noise, and a silent bin
+        let mut spec = test_spectrogram(vec![0.0; 4 * 8], 8, 4);
+        for (row, level) in [0.0, 1.0, 4.0, 16.0].iter().enumerate() {
+            for w in 0..8 {
+                let noise = if (w * 7 + row) % 3 == 0 { 1.5 } else { 0.75 };
+                spec.spec[row * 8 + w] = level * noise;
+            }
+        }
+
+        spec.whiten();
+
+        // Each bin now has an average of 1.0, other than the silent bin
+        let avg = spec.average_spectrum();
+        assert_eq!(avg[3], 0.0);
+        for val in &avg[..3] {
+            assert!((val - 1.0).abs() < 0.0001);
+        }
+
+        // In dB the linear magnitudes are averaged, and the result stays in dB
+        let mut db = test_spectrogram(spec.spec.clone(), 8, 4);
+        db.spec[..24].iter_mut().for_each(|val| *val *= 3.0);
+        db.to_db_inplace();
+        db.whiten();
+        assert!(db.is_db());
+        for row in db.spec[..24].chunks(8) {
+            let mean = row.iter().map(|val| 10.0_f32.powf(val / 20.0)).sum::<f32>() / 8.0;
+            assert!((mean - 1.0).abs() < 0.0001);
+        }
+    }
+
+    #[test]
+    fn test_smooth_time() {
+        let mut spec = test_spectrogram(vec![0.0; 2 * 20], 20, 2);
+        spec.spec[10] = 5.0;
+        spec.spec[20] = 3.0; // The first column of the second row
+
+        spec.smooth_time(5);
+
+        // The spike is spread evenly over 5 columns
+        for (w, val) in spec.spec[..20].iter().enumerate() {
+            let expected = if (8..=12).contains(&w) { 1.0 } else { 0.0 };
+            assert!((val - expected).abs() < 1e-6);
+        }
+
+        // At the edge the window only has 3 columns
+        assert!((spec.spec[20] - 1.0).abs() < 1e-6);
+        assert!((spec.spec[22] - 0.6).abs() < 1e-6);
+        assert!(spec.spec[23].abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_blur() {
+        let mut spec = test_spectrogram(vec![0.0; 21 * 21], 21, 21);
+        spec.spec[10 * 21 + 10] = 1.0;
+
+        spec.blur(1.5);
+
+        // Spread symmetrically in time and frequency
+        let cell = |row: usize, w: usize| spec.spec[row * 21 + w];
+        assert!(cell(10, 10) < 1.0);
+        for d in 1..5 {
+            assert!(cell(10, 10 + d) > 0.0);
+            assert!(cell(10, 10 + d) < cell(10, 10 + d - 1));
+            assert!((cell(10, 10 + d) - cell(10, 10 - d)).abs() < 1e-6);
+            assert!((cell(10 + d, 10) - cell(10 - d, 10)).abs() < 1e-6);
+            assert!((cell(10 + d, 10) - cell(10, 10 + d)).abs() < 1e-6);
+        }
+
+        // The energy is conserved away from the edges
+        let total: f32 = spec.spec.iter().sum();
+        assert!((total - 1.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_frame_energy() {
+        // A quiet frame followed by a loud frame
+        let data: Vec<f32> = (0..256)
+            .map(|i| (i as f32 * 0.4).sin() * if i < 128 { 0.1 } else { 1.0 })
+            .collect();
+        let mut spec_compute = SpecCompute::new(128, 128, data, hann_function);
+        spec_compute.set_include_partial_window(true);
+        let energy = spec_compute.compute().frame_energy();
+
+        assert_eq!(energy.len(), 2);
+        assert!((energy[1] / energy[0] - 100.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn test_total_energy() {
+        // A 0.5 amplitude sine, exactly 16 cycles in each window
+        let data: Vec<f32> = (0..256 * 5)
+            .map(|i| 0.5 * (2.0 * std::f32::consts::PI * 16.0 * i as f32 / 256.0).sin())
+            .collect();
+        let spec = SpecCompute::new(256, 256, data, rectangular).compute();
+
+        let expected = spec.width as f32 * 256.0 * 256.0 * 0.25 / 4.0;
+        assert!((spec.total_energy() / expected - 1.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_dynamic_range_db() {
+        // A floor at 0.001 (-60 dB) with a few cells at the 1.0 peak
+        let mut spec = test_spectrogram(
+            (0..200)
+                .map(|i| if i % 50 == 0 { 1.0 } else { 0.001 })
+                .collect(),
+            50,
+            4,
+        );
+        assert!((spec.dynamic_range_db() - 60.0).abs() < 1e-3);
+
+        // Below the rendering floor of 80 dB
+        spec.spec
+            .iter_mut()
+            .filter(|v| **v < 1.0)
+            .for_each(|v| *v = 1e-6);
+        assert!((spec.dynamic_range_db() - 80.0).abs() < 1e-3);
+
+        // A constant spectrogram has no dynamic range
+        spec.spec.iter_mut().for_each(|v| *v = 0.5);
+        assert_eq!(spec.dynamic_range_db(), 0.0);
+    }
+
+    #[test]
+    fn test_detect_onsets() {
+        // Two decaying notes, 32 ms per column
+        let data: Vec<f32> = (0..8000)
+            .map(|i| match i {
+                1000..=4999 => (-((i - 1000) as f32) / 400.0).exp(),
+                5000.. => (-((i - 5000) as f32) / 400.0).exp(),
+                _ => 0.0,
+            } * (i as f32 * 0.5).sin())
+            .collect();
+        let mut spec_compute = SpecCompute::new(256, 256, data, hann_function);
+        spec_compute.set_sample_rate(8000);
+        let spec = spec_compute.compute();
+
+        assert_eq!(spec.spectral_flux()[0], 0.0);
+
+        let onsets = spec.detect_onsets(0.3);
+        assert_eq!(onsets.len(), 2);
+        assert!((onsets[0] - 0.125).abs() < 0.04);
+        assert!((onsets[1] - 0.625).abs() < 0.04);
+    }
+
+    #[test]
+    fn test_pitch_hps() {
+        // A weak fundamental on bin 25 with strong harmonics, then silence,
+        // then noise
+        let f0 = 25.0 * 8000.0 / 1024.0;
+        let mut seed = 12345u32;
+        let data: Vec<f32> = (0..3 * 4096)
+            .map(|i| match i / 4096 {
+                0 => [0.1, 1.0, 1.0, 0.8, 0.6]
+                    .iter()
+                    .enumerate()
+                    .map(|(h, amp)| {
+                        amp * (2.0 * PI * f0 * (h + 1) as f32 * i as f32 / 8000.0).sin()
+                    })
+                    .sum(),
+                1 => 0.0,
+                _ => {
+                    seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+                    (seed >> 8) as f32 / (1 << 24) as f32 - 0.5
+                }
+            })
+            .collect();
+        let spec = SpecCompute::new(1024, 1024, data, hann_function).compute();
+        let pitch = spec.pitch_hps(8000, 4);
+
+        assert_eq!(pitch.len(), spec.width);
+        assert!(pitch[..3].iter().all(|&p| (p - f0).abs() < 0.01));
+        assert!(pitch[4..7].iter().all(|&p| p == 0.0));
+        assert!(pitch[8..].iter().all(|&p| p == 0.0));
+    }
+
+    #[test]
+    fn test_spectral_rolloff() {
+        // Band-limited noise, equal power from 20 Hz to 1000 Hz with random
+        // phases, then silence
+        let mut seed = 12345u32;
+        let phases: Vec<f32> = (0..50)
+            .map(|_| {
+                seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+                (seed >> 8) as f32 / (1 << 24) as f32 * 2.0 * PI
+            })
+            .collect();
+        let data: Vec<f32> = (0..8192)
+            .map(|i| match i {
+                0..=4095 => phases
+                    .iter()
+                    .enumerate()
+                    .map(|(n, phase)| {
+                        let freq = 20.0 * (n + 1) as f32;
+                        (2.0 * PI * freq * i as f32 / 8000.0 + phase).sin()
+                    })
+                    .sum(),
+                _ => 0.0,
+            })
+            .collect();
+        let spec = SpecCompute::new(1024, 1024, data, hann_function).compute();
+        let rolloff = spec.spectral_rolloff(8000, 0.85);
+
+        assert_eq!(rolloff.len(), spec.width);
+        assert!(rolloff[..3].iter().all(|&r| (r - 850.0).abs() < 30.0));
+        assert!(rolloff[4..].iter().all(|&r| r == 0.0));
+        assert!(spec.spectral_rolloff(8000, 1.0)[0] < 1040.0);
+    }
+
+    #[test]
+    fn test_spectral_flatness() {
+        // A tone, then white noise, then silence
+        let mut seed = 12345u32;
+        let data: Vec<f32> = (0..3 * 4096)
+            .map(|i| match i / 4096 {
+                0 => (2.0 * PI * 500.0 * i as f32 / 8000.0).sin(),
+                1 => {
+                    seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+                    (seed >> 8) as f32 / (1 << 24) as f32 - 0.5
+                }
+                _ => 0.0,
+            })
+            .collect();
+        let spec = SpecCompute::new(1024, 1024, data.clone(), hann_function).compute();
+        let flatness = spec.spectral_flatness();
+
+        assert_eq!(flatness.len(), spec.width);
+        assert!(flatness[..3].iter().all(|&f| f < 0.01));
+        assert!(flatness[4..7].iter().all(|&f| f > 0.3 && f <= 1.0));
+        assert!(flatness[8..].iter().all(|&f| f == 0.0));
+
+        // The same flatness in dB, other than the floor of the silent frames
+        let mut db = SpecCompute::new(1024, 1024, data, hann_function).compute();
+        db.to_db_inplace();
+        for (a, b) in db.spectral_flatness().iter().zip(&flatness).take(8) {
+            assert!((a - b).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn test_mfcc() {
+        // 500 Hz has a whole number of cycles in each 512 sample step
+        let data: Vec<f32> = (0..8192)
+            .map(|i| (2.0 * PI * 500.0 * i as f32 / 8000.0).sin())
+            .collect();
+        let spec = SpecCompute::new(512, 512, data, hann_function).compute();
+        let mfcc = spec.mfcc(8000, 26, 13);
+
+        assert_eq!(mfcc.len(), spec.width);
+        assert!(mfcc.iter().all(|coeffs| coeffs.len() == 13));
+
+        // A steady tone gives the same coefficients in every frame
+        for coeffs in mfcc.iter().skip(1) {
+            for (a, b) in coeffs.iter().zip(&mfcc[0]) {
+                assert!((a - b).abs() < 0.01 * b.abs().max(1.0));
+            }
+        }
+    }
+
+    #[test]
+    fn test_mel_spectrogram() {
+        // A 500 Hz tone at 8 kHz
+        let data: Vec<f32> = (0..8192)
+            .map(|i| (2.0 * PI * 500.0 * i as f32 / 8000.0).sin())
+            .collect();
+        let spec = SpecCompute::new(512, 512, data, hann_function).compute();
+        let mel = spec.mel_spectrogram(8000, 40);
+
+        assert_eq!(mel.height, 40);
+        assert_eq!(mel.width, spec.width);
+
+        // The strongest band of each frame is the one centred closest to 500 Hz
+        let mel_max = hz_to_mel(4000.0);
+        let expected = (0..40)
+            .min_by(|&a, &b| {
+                let centre = |m: usize| (mel_to_hz(mel_max * (m + 1) as f32 / 41.0) - 500.0).abs();
+                centre(a).total_cmp(&centre(b))
+            })
+            .unwrap();
+        for w in 0..mel.width {
+            let strongest = (0..40)
+                .max_by(|&a, &b| {
+                    let val = |m: usize| mel.spec[(39 - m) * mel.width + w];
+                    val(a).total_cmp(&val(b))
+                })
+                .unwrap();
+            assert_eq!(strongest, expected);
+        }
+    }
+
+    #[test]
+    fn test_dct2() {
+        // A constant only has a DC component
+        let coeffs = dct2(&[2.0; 4], 3);
+        assert!((coeffs[0] - 4.0).abs() < 0.0001);
+        assert!(coeffs[1].abs() < 0.0001);
+        assert!(coeffs[2].abs() < 0.0001);
+    }
+
+    #[test]
+    fn test_spectrogram_find_peaks() {
+        // Two rows, the highest frequency is stored first
+        let spec = Spectrogram {
+            step_size: 8,
+            ..test_spectrogram(
+                vec![
+                    0.0, 0.0, // 3000 Hz
+                    4.0, 4.0, // 2000 Hz
+                    1.0, 1.0, // 1000 Hz
+                    2.0, 2.0, // DC
+                ],
+                2,
+                4,
+            )
+        };
+
+        assert_eq!(spec.average_spectrum(), vec![2.0, 1.0, 4.0, 0.0]);
+        assert_eq!(spec.find_peaks(0.5, 10), vec![(2000.0, 4.0), (0.0, 2.0)]);
+        assert_eq!(spec.find_peaks(1.5, 10), vec![(2000.0, 4.0)]);
+        assert_eq!(spec.find_peaks(0.0, 1), vec![(2000.0, 4.0)]);
+    }
+}
@@ -0,0 +1,166 @@
+/*
+ * Copyright (C) Simon Werner, 2022.
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Create spectrograms for all the wav files in a directory
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::{ColourGradient, FftPlanner, FrequencyScale, SonogramError, SpecOptionsBuilder};
+
+/// The path of a wav file and the result of processing it.
+pub type BatchResult = (PathBuf, Result<(), SonogramError>);
+
+///
+/// Create a PNG spectrogram for each `.wav` file in a directory.  The files
+/// are processed in name order and one FFT planner is shared by all of them,
+/// so the FFT is only planned once.  An error with one file does not stop
+/// the others from being processed.
+///
+/// # Arguments
+///
+///  * `dir` - The directory containing the wav files.
+///  * `out_dir` - The directory to save the PNGs to, each PNG has the same
+///    name as its wav file with a `.png` extension.
+///  * `opts` - Creates the builder for each wav file, it's called with the
+///    path of the file and should load it, e.g. with
+///    [SpecOptionsBuilder::load_data_from_file], and apply the options.
+///  * `freq_scale` - The type of frequency scale to use for the spectrograms.
+///  * `gradient` - The colour gradient to use for the spectrograms.
+///  * `w_img` - The output image width.
+///  * `h_img` - The output image height.
+///
+/// # Returns
+///
+/// The path of each wav file with the result of processing it, or an error
+/// if the directory could not be read.
+///
+/// # Example
+///
+/// ```Rust
+///   let results = batch_process(
+///     Path::new("samples"),
+///     Path::new("images"),
+///     |path| Ok(SpecOptionsBuilder::new(512).load_data_from_file(path)?.downsample(2)),
+///     FrequencyScale::Log,
+///     &ColourGradient::default_theme(),
+///     1024,
+///     256,
+///   )?;
+/// ```
+///
+pub fn batch_process<F>(
+    dir: &Path,
+    out_dir: &Path,
+    opts: F,
+    freq_scale: FrequencyScale,
+    gradient: &ColourGradient,
+    w_img: usize,
+    h_img: usize,
+) -> Result<Vec<BatchResult>, SonogramError>
+where
+    F: Fn(&Path) -> Result<SpecOptionsBuilder, SonogramError>,
+{
+    let mut wav_files: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.is_file()
+                && path
+                    .extension()
+                    .and_then(|ext| ext.to_str())
+                    .is_some_and(|ext| ext.eq_ignore_ascii_case("wav"))
+        })
+        .collect();
+    wav_files.sort();
+
+    let mut planner = FftPlanner::new();
+    let results = wav_files
+        .into_iter()
+        .map(|wav_file| {
+            let result = opts(&wav_file)
+                .and_then(|builder| builder.build_with_planner(&mut planner))
+                .and_then(|mut spec_compute| {
+                    let png_file = out_dir
+                        .join(wav_file.file_stem().unwrap_or_default())
+                        .with_extension("png");
+                    spec_compute
+                        .compute()
+                        .to_png(&png_file, freq_scale, gradient, w_img, h_img)?;
+                    Ok(())
+                });
+            (wav_file, result)
+        })
+        .collect();
+
+    Ok(results)
+}
+
+#[cfg(test)]
+#[cfg(feature = "hound")]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_batch_process() {
+        let dir = std::env::temp_dir().join(format!("sonogram_batch_{}", std::process::id()));
+        let out_dir = dir.join("out");
+        fs::create_dir_all(&out_dir).unwrap();
+
+        // Two good wav files, a bad one and a file that isn't a wav
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 8000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        for name in ["a.wav", "b.WAV"] {
+            let mut writer = hound::WavWriter::create(dir.join(name), spec).unwrap();
+            for i in 0..2048 {
+                let val = (i as f32 * 0.3).sin() * 10000.0;
+                writer.write_sample(val as i16).unwrap();
+            }
+            writer.finalize().unwrap();
+        }
+        fs::write(dir.join("bad.wav"), b"not a wav").unwrap();
+        fs::write(dir.join("notes.txt"), b"not a wav").unwrap();
+
+        let results = batch_process(
+            &dir,
+            &out_dir,
+            |path| SpecOptionsBuilder::new(256).load_data_from_file(path),
+            FrequencyScale::Linear,
+            &ColourGradient::default_theme(),
+            64,
+            32,
+        )
+        .unwrap();
+
+        let names: Vec<_> = results
+            .iter()
+            .map(|(path, _)| path.file_name().unwrap().to_str().unwrap())
+            .collect();
+        assert_eq!(names, vec!["a.wav", "b.WAV", "bad.wav"]);
+        assert!(results[0].1.is_ok());
+        assert!(results[1].1.is_ok());
+        assert!(results[2].1.is_err());
+        assert!(out_dir.join("a.png").is_file());
+        assert!(out_dir.join("b.png").is_file());
+        assert!(!out_dir.join("bad.png").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
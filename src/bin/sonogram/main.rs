@@ -18,11 +18,17 @@
 extern crate clap;
 extern crate sonogram;
 
-use std::{fs::File, io::BufWriter, path::PathBuf};
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::PathBuf,
+};
 
 use clap::{ArgEnum, Parser};
 use png::HasParameters;
-use sonogram::{ColourGradient, ColourTheme, FrequencyScale, SpecOptionsBuilder};
+use sonogram::{
+    AmplitudeScale, ColourGradient, ColourTheme, FrequencyScale, ResizeFilter, SpecOptionsBuilder,
+};
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ArgEnum)]
 enum WinFunc {
@@ -38,6 +44,7 @@ enum ArgColourTheme {
     Rainbow,
     BlackWhite,
     WhiteBlack,
+    Diverging,
 }
 
 impl From<ArgColourTheme> for ColourTheme {
@@ -48,6 +55,7 @@ impl From<ArgColourTheme> for ColourTheme {
             ArgColourTheme::Rainbow => ColourTheme::Rainbow,
             ArgColourTheme::BlackWhite => ColourTheme::BlackWhite,
             ArgColourTheme::WhiteBlack => ColourTheme::WhiteBlack,
+            ArgColourTheme::Diverging => ColourTheme::Diverging,
         }
     }
 }
@@ -112,6 +120,10 @@ struct Args {
     #[clap(short, long, default_value_t = 512, value_name = "PIXELS")]
     height: usize,
 
+    /// Render each bin as a solid block, rather than interpolating
+    #[clap(long)]
+    no_interpolation: bool,
+
     /// The colour gradient to implement
     #[clap(arg_enum, long, default_value_t = ArgColourTheme::Default, value_name = "GRADIENT")]
     gradient: ArgColourTheme,
@@ -133,9 +145,9 @@ fn main() {
         _ => panic!("Invalid window function"),
     };
 
-    if args.bins < 16 {
+    if args.bins < 16 || args.bins % 2 != 0 {
         panic!(
-            "Invalid bins value ({}), it must be an integer greater than 16",
+            "Invalid bins value ({}), it must be an even integer of at least 16",
             args.bins
         );
     }
@@ -152,7 +164,7 @@ fn main() {
         WinFunc::Hann => sonogram::hann_function,
     };
 
-    let mut gradient = ColourGradient::create(ColourTheme::from(args.gradient));
+    let gradient = ColourGradient::create(ColourTheme::from(args.gradient));
 
     //
     // Apply the options
@@ -167,7 +179,6 @@ fn main() {
 
     let overlap = 1.0 - stepsize as f32 / args.bins as f32;
 
-    println!("Computing spectrogram...");
     println!("Bins: {}", args.bins);
     println!("Overlap: {}", overlap);
     println!("Step size: {}", stepsize);
@@ -175,41 +186,59 @@ fn main() {
     //
     // Do the spectrograph
     //
-    let mut spectrograph = spec_builder.build().unwrap().compute();
+    let spectrograph = spec_builder
+        .build()
+        .unwrap()
+        .compute_with_progress(|progress| {
+            print!("\rComputing spectrogram... {:3.0}%", progress * 100.0);
+            std::io::stdout().flush().unwrap();
+        });
+    println!();
+    let filter = if args.no_interpolation {
+        ResizeFilter::NearestNeighbour
+    } else {
+        ResizeFilter::Lanczos3
+    };
 
-    if args.png.is_some() {
+    if let Some(png_file) = &args.png {
         spectrograph
-            .to_png(
-                &args.png.unwrap(),
+            .to_png_with_filter(
+                png_file,
                 freq_scale,
-                &mut gradient,
+                &gradient,
                 args.width,
                 args.height,
+                filter,
             )
             .unwrap()
     }
 
-    if args.csv.is_some() {
+    if let Some(csv_file) = &args.csv {
         spectrograph
-            .to_csv(&args.csv.unwrap(), freq_scale, args.width, args.height)
+            .to_csv_with_filter(
+                csv_file,
+                freq_scale,
+                args.width,
+                args.height,
+                AmplitudeScale::Db,
+                filter,
+            )
             .unwrap()
     }
 
-    if args.legend.is_some() {
+    if let Some(legend_file) = &args.legend {
         let (min, max) = spectrograph.get_min_max();
-        gradient.set_min(min);
-        gradient.set_max(max);
 
         let width = 20;
         let height = 250;
-        let legend = gradient.to_legend(width, height);
+        let legend = gradient.to_legend_in_range(width, height, min, max);
 
         let img = legend
             .iter()
             .flat_map(|colour| [colour.r, colour.g, colour.b, colour.a].into_iter())
             .collect::<Vec<u8>>();
 
-        let file = File::create(&args.legend.unwrap()).unwrap();
+        let file = File::create(legend_file).unwrap();
         let buf = &mut BufWriter::new(file);
         let mut encoder = png::Encoder::new(buf, width as u32, height as u32);
         encoder.set(png::ColorType::RGBA).set(png::BitDepth::Eight);
@@ -16,49 +16,106 @@
  */
 
 use std::f32;
-#[cfg(feature = "png")]
+#[cfg(any(feature = "vorbis", feature = "mp3"))]
+use std::fs::File;
+#[cfg(any(feature = "hound", feature = "vorbis", feature = "mp3"))]
 use std::path::Path;
 
+use std::iter::Sum;
+use std::ops::{Add, Div, Mul, MulAssign, Range, Sub};
+
+use rustfft::{num_complex::Complex, FftPlanner};
+
 use crate::errors::SonogramError;
 use crate::window_fn;
-use crate::SpecCompute;
+use crate::{DbConvention, FftNormalisation, MultiSpecCompute, PaddingMode, SpecCompute};
 
 type WindowFn = fn(usize, usize) -> f32;
 
+/// Samples at or below this magnitude are treated as silence.
+const SILENCE_THRESHOLD: f32 = f32::EPSILON;
+
+/// The largest FFT size, 2^20 bins, chosen from a frequency resolution or
+/// window length.
+const MAX_NUM_BINS_LOG2: f32 = 20.0;
+
+/// Samples within this fraction of the peak of the data hold the peak, i.e.
+/// may be clipped.
+const CLIP_TOLERANCE: f32 = 1e-5;
+
 ///
 /// A builder struct that will output a spectrogram creator when complete.
-/// This builder will require the height and width of the final spectrogram,
-/// at a minimum.  However you can load data from a .wav file, or directly
-/// from a Vec<i16> memory object.
+/// This builder will require the number of FFT bins, at a minimum.  The
+/// computed spectrogram has `num_bins / 2` rows, one per frequency bin, and
+/// is resized to the final image height when it is rendered, e.g. by
+/// [crate::Spectrogram::to_png].  You can load data from a .wav file, or
+/// directly from a Vec<i16> memory object.
 ///
 /// # Example
 ///
 /// ```Rust
-///   let mut spectrograph = SpecOptionsBuilder::new(512, 128)
-///     .set_window_fn(utility::blackman_harris)
+///   // 512 FFT bins, which gives 256 frequency rows
+///   let mut spectrograph = SpecOptionsBuilder::new(512)
+///     .set_window_fn(blackman_harris)
 ///     .load_data_from_file(&std::path::Path::new("test.wav"))?
-///     .build();
+///     .build()?;
 /// ```
 ///
 pub struct SpecOptionsBuilder {
     // Inputs
-    data: Vec<f32>,                    // Our time-domain data (audio samples)
-    sample_rate: u32,                  // The sample rate of the wav data
-    channel: u16,                      // The audio channel
-    scale_factor: Option<f32>,         // How much to scale the sample amplitude by
-    do_normalise: bool,                // Normalise the samples to between -1.0...1.0
-    downsample_divisor: Option<usize>, // Downsample the samples by a given amount
+    data: Vec<f32>,                          // Our time-domain data (audio samples)
+    complex_data: Option<Vec<Complex<f32>>>, // Complex time-domain data, used instead of `data`
+    sample_rate: u32,                        // The sample rate of the wav data
+    channel: u16,                            // The audio channel
+    mixdown: bool,                           // Average all the channels, rather than use `channel`
+    channel_weights: Option<Vec<f32>>,       // Combine the channels with these weights
+    all_channels: bool,                      // Keep every channel, rather than combine them
+    channel_data: Option<Vec<Vec<f32>>>,     // The de-interleaved channels, when `all_channels`
+    combined_channels: bool,                 // Multichannel data was combined into one when loaded
+    max_samples: Option<usize>,              // Only read this many samples from a file
+    num_threads: usize,                      // The number of threads to compute with
+    scale_factor: Option<f32>,               // How much to scale the sample amplitude by
+    do_normalise: bool,                      // Normalise the samples to between -1.0...1.0
+    noise_gate: Option<f32>,                 // Zero the samples quieter than this
+    declip: bool,                            // Interpolate across the clipped samples
+    downsample_divisor: Option<usize>,       // Downsample the samples by a given amount
+    downsample_filtered: bool,               // Low-pass filter the samples before downsampling
+    bandpass: Option<(f32, f32)>,            // The low and high frequency of the bandpass filter
+    sample_map: Option<Box<dyn Fn(f32) -> f32 + Send + Sync>>, // A custom transform applied to every sample
 
     // FFT info
-    num_bins: usize,     // The number of FFT bins
-    step_size: usize,    // How far to step between each window function
-    window_fn: WindowFn, // The windowing function to use.
+    num_bins: usize,                 // The number of FFT bins
+    invalid_freq_resolution: bool,   // `with_freq_resolution` was given a bad resolution
+    step_size: usize,                // How far to step between each window function
+    explicit_step: bool,             // The step size was set, rather than the default
+    step_seconds: Option<f32>,       // The step size in seconds, replaces `step_size`
+    window_ms: Option<f32>,          // The window length in milliseconds, replaces `num_bins`
+    window_fn: WindowFn,             // The windowing function to use.
+    window_coeffs: Option<Vec<f32>>, // Window coefficients, used instead of `window_fn`
+    include_partial_window: bool,    // Zero-pad the last partial window
+    center_windows: bool,            // Center the first window on the first sample
+    padding_mode: PaddingMode,       // How to pad past the ends of the data
+    db_output: bool,                 // Compute the spectrogram in dB
+    db_convention: DbConvention,     // How to convert to dB
+    normalisation: FftNormalisation, // How to normalise the FFT output
+    two_sided: Option<bool>,         // Keep the negative frequencies
 }
 
 impl SpecOptionsBuilder {
-    /// Create a new SpecOptionsBuilder.  The final height and width of
-    /// the spectrogram must be supplied.  Before the `build` function
-    /// can be called a `load_data_from_*` function needs to be called.
+    /// Create a new SpecOptionsBuilder.  The number of FFT bins must be
+    /// supplied, the spectrogram will have `num_bins / 2` frequency rows.
+    /// Before the `build` function can be called a `load_data_from_*`
+    /// function needs to be called.
+    ///
+    /// The rows are the bins from DC up to, but not including, the Nyquist
+    /// frequency, so the top row is `(num_bins / 2 - 1) * sample_rate / num_bins`
+    /// Hz.  Unless the spectrogram is two-sided, `num_bins` must be even,
+    /// otherwise `build` returns [SonogramError::InvalidNumBins].
+    ///
+    /// Any size works, it doesn't need to be a power of two.  The FFT is
+    /// fastest for sizes made of small prime factors, e.g. 2000 = 2^4 * 5^3,
+    /// while a size with a large prime factor, e.g. 2 * 1009, is several
+    /// times slower.
     ///
     /// # Arguments
     ///  
@@ -67,55 +124,284 @@ impl SpecOptionsBuilder {
     pub fn new(num_bins: usize) -> Self {
         SpecOptionsBuilder {
             data: vec![],
+            complex_data: None,
             sample_rate: 11025,
             channel: 1,
+            mixdown: false,
+            channel_weights: None,
+            all_channels: false,
+            channel_data: None,
+            combined_channels: false,
+            max_samples: None,
+            num_threads: 1,
             scale_factor: None,
             do_normalise: false,
+            noise_gate: None,
+            declip: false,
             downsample_divisor: None,
+            downsample_filtered: false,
+            bandpass: None,
+            sample_map: None,
             num_bins,
+            invalid_freq_resolution: false,
             window_fn: window_fn::rectangular,
+            window_coeffs: None,
             step_size: num_bins,
+            explicit_step: false,
+            step_seconds: None,
+            window_ms: None,
+            include_partial_window: false,
+            center_windows: false,
+            padding_mode: PaddingMode::Zero,
+            db_output: false,
+            db_convention: DbConvention::PeakRelative,
+            normalisation: FftNormalisation::None,
+            two_sided: None,
+        }
+    }
+
+    ///
+    /// Create a new SpecOptionsBuilder with exactly `height` frequency rows in
+    /// the computed spectrogram, see [crate::Spectrogram::height].  The FFT
+    /// uses `2 * height` bins.  If the spectrogram is made two-sided, see
+    /// [SpecOptionsBuilder::two_sided], it has `2 * height` rows instead.
+    ///
+    /// # Arguments
+    ///
+    ///  * `height` - The number of frequency rows.
+    ///
+    pub fn with_height(height: usize) -> Self {
+        Self::new(height * 2)
+    }
+
+    ///
+    /// Create a new SpecOptionsBuilder with the number of FFT bins chosen
+    /// from the frequency resolution you want, rather than [SpecOptionsBuilder::new].
+    /// The number of bins is rounded to the nearest power of two, so the
+    /// actual resolution is `sample_rate / num_bins` Hz per bin.  For example
+    /// 10 Hz at 44100 Hz gives 4096 bins, which is about 10.8 Hz per bin.
+    /// The FFT is at most 2^20 bins, however fine the resolution, and at
+    /// least 2 bins.  If `hz_per_bin` is not a positive number `build`
+    /// returns [SonogramError::InvalidFrequency].
+    ///
+    /// # Arguments
+    ///
+    ///  * `hz_per_bin` - The frequency resolution, in Hz.
+    ///  * `sample_rate` - The sample rate, in Hz, of the data that will be
+    ///    loaded.
+    ///
+    pub fn with_freq_resolution(hz_per_bin: f32, sample_rate: u32) -> Self {
+        if !(hz_per_bin.is_finite() && hz_per_bin > 0.0) {
+            let mut builder = Self::new(2);
+            builder.invalid_freq_resolution = true;
+            return builder;
         }
+
+        let exponent = (sample_rate as f32 / hz_per_bin).log2().round();
+        let num_bins = 1 << exponent.clamp(1.0, MAX_NUM_BINS_LOG2) as u32;
+        Self::new(num_bins)
     }
 
-    /// Load a .wav file to memory and use that file as the input.
+    /// Load an audio file to memory and use that file as the input.  The
+    /// format is chosen by the file extension, `.ogg` files are decoded as
+    /// Ogg Vorbis (requires the `vorbis` feature), `.mp3` files as MP3
+    /// (requires the `mp3` feature), all other files are treated as .wav
+    /// files (requires the `hound` feature).
     ///
     /// # Arguments
     ///
     ///  * `fname` - The path to the file.
     ///
-    #[cfg(feature = "hound")]
+    #[cfg(any(feature = "hound", feature = "vorbis", feature = "mp3"))]
     pub fn load_data_from_file(self, fname: &Path) -> Result<Self, SonogramError> {
+        let ext = fname
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase());
+
+        match ext.as_deref() {
+            #[cfg(feature = "vorbis")]
+            Some("ogg") | Some("oga") => self.load_vorbis(fname),
+            #[cfg(feature = "mp3")]
+            Some("mp3") => self.load_mp3(fname),
+            #[cfg(feature = "hound")]
+            _ => self.load_wav(fname),
+            #[cfg(not(feature = "hound"))]
+            _ => Err(SonogramError::InvalidCodec),
+        }
+    }
+
+    /// Load a .wav file.  Integer samples of 8, 16, 24 or 32 bits and 32 bit
+    /// float samples are supported.
+    #[cfg(feature = "hound")]
+    fn load_wav(self, fname: &Path) -> Result<Self, SonogramError> {
         let mut reader = hound::WavReader::open(fname)?;
+        let spec = reader.spec();
+
+        let channels = spec.channels;
+        let sample_rate = spec.sample_rate;
+        let max_len = self.max_len(channels);
+        let samples: Vec<f32> = match (spec.sample_format, spec.bits_per_sample) {
+            (hound::SampleFormat::Float, 32) => reader
+                .samples::<f32>()
+                .take(max_len)
+                .collect::<Result<_, _>>()?,
+            (hound::SampleFormat::Int, bits @ (8 | 16 | 24 | 32)) => {
+                // Scale to -1.0 to 1.0, the same as i16::MAX for 16 bit data
+                let scale = ((1_i64 << (bits - 1)) - 1) as f32;
+                reader
+                    .samples::<i32>()
+                    .take(max_len)
+                    .map(|x| x.map(|x| x as f32 / scale))
+                    .collect::<Result<_, _>>()?
+            }
+            _ => return Err(SonogramError::InvalidCodec),
+        };
+        self.load_interleaved(samples.into_iter(), channels, sample_rate)
+    }
+
+    /// Load an Ogg Vorbis file.
+    #[cfg(feature = "vorbis")]
+    fn load_vorbis(self, fname: &Path) -> Result<Self, SonogramError> {
+        let mut reader = lewton::inside_ogg::OggStreamReader::new(File::open(fname)?)?;
 
-        // Can only handle 16 bit data
-        // TODO: Add more data here
-        if 16 != reader.spec().bits_per_sample {
-            return Err(SonogramError::InvalidCodec);
+        let channels = reader.ident_hdr.audio_channels as u16;
+        let sample_rate = reader.ident_hdr.audio_sample_rate;
+
+        // Each packet holds interleaved samples for all channels
+        let max_len = self.max_len(channels);
+        let mut samples: Vec<i16> = vec![];
+        while samples.len() < max_len {
+            match reader.read_dec_packet_itl()? {
+                Some(packet) => samples.extend(packet),
+                None => break,
+            }
         }
+        samples.truncate(max_len);
+        self.load_interleaved(samples.into_iter().map(i16_to_f32), channels, sample_rate)
+    }
 
-        if self.channel > reader.spec().channels {
-            return Err(SonogramError::InvalidChannel);
+    /// Load an MP3 file.
+    ///
+    /// The decoder output is used as-is, the encoder delay and padding are
+    /// not removed.  So the spectrogram starts with the few milliseconds of
+    /// silence the encoder added (typically 1105 samples for LAME) and may
+    /// end with some padding.
+    ///
+    /// The first frame sets the number of channels and the sample rate.  A
+    /// stream that changes either part way through is not supported, it
+    /// returns [SonogramError::InvalidCodec] rather than leave gaps in the
+    /// audio.
+    #[cfg(feature = "mp3")]
+    fn load_mp3(self, fname: &Path) -> Result<Self, SonogramError> {
+        let mut decoder = minimp3::Decoder::new(File::open(fname)?);
+
+        // The first frame sets the format of the stream
+        let mut samples: Vec<i16> = vec![];
+        let mut format: Option<(u16, u32)> = None;
+        loop {
+            match decoder.next_frame() {
+                Ok(frame) => {
+                    let frame_format = (frame.channels as u16, frame.sample_rate as u32);
+                    let (channels, _) = *format.get_or_insert(frame_format);
+                    if format != Some(frame_format) {
+                        return Err(SonogramError::InvalidCodec);
+                    }
+                    samples.extend(frame.data);
+                    let max_len = self.max_len(channels);
+                    if samples.len() >= max_len {
+                        samples.truncate(max_len);
+                        break;
+                    }
+                }
+                Err(minimp3::Error::SkippedData) => continue, // e.g. ID3 tags
+                Err(minimp3::Error::Eof) | Err(minimp3::Error::InsufficientData) => break,
+                Err(err) => return Err(err.into()),
+            }
         }
 
-        let data: Vec<i16> = {
-            let first_sample = self.channel as usize - 1;
-            let step_size = reader.spec().channels as usize;
-            let mut s = reader.samples();
+        let (channels, sample_rate) = format.ok_or(SonogramError::IncompleteData)?;
+        self.load_interleaved(samples.into_iter().map(i16_to_f32), channels, sample_rate)
+    }
+
+    /// The number of interleaved samples to read from a file with `channels`
+    /// channels, see [SpecOptionsBuilder::max_samples].
+    #[cfg(any(feature = "hound", feature = "vorbis", feature = "mp3"))]
+    fn max_len(&self, channels: u16) -> usize {
+        self.max_samples
+            .map_or(usize::MAX, |n| n.saturating_mul(channels as usize))
+    }
+
+    /// Load interleaved samples, either combined into a single channel or,
+    /// with [SpecOptionsBuilder::all_channels], as separate channels.
+    fn load_interleaved<I: Iterator<Item = f32>>(
+        self,
+        samples: I,
+        channels: u16,
+        sample_rate: u32,
+    ) -> Result<Self, SonogramError> {
+        if !self.all_channels {
+            let data = self.combine_channels(samples, channels)?;
+            let mut builder = self.load_data_from_memory_f32(data, sample_rate);
+            builder.combined_channels = channels > 1;
+            return Ok(builder);
+        }
 
-            // TODO: replace this with .advanced_by in the future
-            for _ in 0..first_sample {
-                s.next();
+        let channels = channels as usize;
+        if channels == 0 {
+            return Err(SonogramError::InvalidChannel);
+        }
+        let samples: Vec<f32> = samples.collect();
+        let mut channel_data = vec![Vec::with_capacity(samples.len() / channels); channels];
+        for frame in samples.chunks_exact(channels) {
+            for (data, x) in channel_data.iter_mut().zip(frame) {
+                data.push(*x);
             }
+        }
+
+        let mut builder = self.load_data_from_memory_f32(vec![], sample_rate);
+        builder.channel_data = Some(channel_data);
+        Ok(builder)
+    }
 
-            s.step_by(step_size).map(|x| x.unwrap()).collect()
+    /// Combine the interleaved samples into a single channel, using the
+    /// channel weights, the mixdown or the selected channel.  The samples
+    /// must already be scaled to the range -1.0 to 1.0.
+    fn combine_channels<I: Iterator<Item = f32>>(
+        &self,
+        samples: I,
+        channels: u16,
+    ) -> Result<Vec<f32>, SonogramError> {
+        let channels = channels as usize;
+        if channels == 0 {
+            return Err(SonogramError::InvalidChannel);
+        }
+        let weights = match &self.channel_weights {
+            Some(weights) if weights.len() == channels => weights.clone(),
+            Some(_) => return Err(SonogramError::InvalidChannel),
+            None if self.mixdown => vec![1.0 / channels as f32; channels],
+            None => {
+                let channel = self.channel as usize;
+                if channel == 0 || channel > channels {
+                    return Err(SonogramError::InvalidChannel);
+                }
+                let mut weights = vec![0.0; channels];
+                weights[channel - 1] = 1.0;
+                weights
+            }
         };
-        let sample_rate = reader.spec().sample_rate;
 
-        Ok(self.load_data_from_memory(data, sample_rate))
+        let samples: Vec<f32> = samples.collect();
+        Ok(samples
+            .chunks_exact(channels)
+            .map(|frame| frame.iter().zip(&weights).map(|(x, w)| x * w).sum())
+            .collect())
     }
 
-    /// Load data directly from memory - i16 version.
+    /// Load data directly from memory - i16 version.  The samples are
+    /// treated as PCM and divided by `i16::MAX`, so they range from -1.0 to
+    /// 1.0.  See [SpecOptionsBuilder::load_data_from_memory_raw_i16] to load
+    /// them unscaled.
     ///
     /// # Arguments
     ///
@@ -123,21 +409,89 @@ impl SpecOptionsBuilder {
     ///  * `sample_rate` - The sample rate, in Hz, of the data.
     ///
     pub fn load_data_from_memory(mut self, data: Vec<i16>, sample_rate: u32) -> Self {
-        self.data = data.iter().map(|&x| x as f32 / (i16::MAX as f32)).collect();
+        self.data = data.into_iter().map(i16_to_f32).collect();
+        self.complex_data = None;
+        self.channel_data = None;
+        self.combined_channels = false;
+        self.sample_rate = sample_rate;
+        self
+    }
+
+    /// Load data directly from memory - unscaled i16 version.  Each sample
+    /// is cast straight to f32, without the division by `i16::MAX` done by
+    /// [SpecOptionsBuilder::load_data_from_memory], e.g. for data that is
+    /// already in some fixed-point scale.  Use
+    /// [normalise](SpecOptionsBuilder::normalise) or
+    /// [scale](SpecOptionsBuilder::scale) to bring the samples into range if
+    /// needed.
+    ///
+    /// # Arguments
+    ///
+    ///  * `data` - The waveform data that will be converted to a spectrogram.
+    ///  * `sample_rate` - The sample rate, in Hz, of the data.
+    ///
+    pub fn load_data_from_memory_raw_i16(mut self, data: Vec<i16>, sample_rate: u32) -> Self {
+        self.data = data.into_iter().map(f32::from).collect();
+        self.complex_data = None;
+        self.channel_data = None;
+        self.combined_channels = false;
         self.sample_rate = sample_rate;
         self
     }
 
+    /// Load interleaved multichannel data directly from memory - i16 version.
+    /// The channel is extracted in the same way as when loading a file, so
+    /// [SpecOptionsBuilder::channel], [SpecOptionsBuilder::mixdown] or
+    /// [SpecOptionsBuilder::channel_weights] must be called before this
+    /// function.
+    ///
+    /// # Arguments
+    ///
+    ///  * `data` - The interleaved raw wavform data, `channels` samples for
+    ///    each point in time.
+    ///  * `channels` - The number of channels in `data`.
+    ///  * `sample_rate` - The sample rate, in Hz, of the data.
+    ///
+    pub fn load_data_from_memory_interleaved(
+        self,
+        data: Vec<i16>,
+        channels: u16,
+        sample_rate: u32,
+    ) -> Result<Self, SonogramError> {
+        self.load_interleaved(data.into_iter().map(i16_to_f32), channels, sample_rate)
+    }
+
     /// Load data directly from memory - f32 version.
     ///
     /// # Arguments
     ///
     ///  * `data` - The raw wavform data that will be converted to a spectrogram.
-    ///             Samples must be in the range -1.0 to 1.0.
+    ///    Samples must be in the range -1.0 to 1.0.
     ///  * `sample_rate` - The sample rate, in Hz, of the data.
     ///
     pub fn load_data_from_memory_f32(mut self, data: Vec<f32>, sample_rate: u32) -> Self {
         self.data = data;
+        self.complex_data = None;
+        self.channel_data = None;
+        self.combined_channels = false;
+        self.sample_rate = sample_rate;
+        self
+    }
+
+    /// Load complex (IQ) data directly from memory, e.g. complex baseband
+    /// samples from a software defined radio.  The spectrogram will be
+    /// two-sided by default, see [SpecOptionsBuilder::two_sided].
+    ///
+    /// # Arguments
+    ///
+    ///  * `data` - The complex samples that will be converted to a spectrogram.
+    ///  * `sample_rate` - The sample rate, in Hz, of the data.
+    ///
+    pub fn load_data_from_complex(mut self, data: Vec<Complex<f32>>, sample_rate: u32) -> Self {
+        self.data = vec![];
+        self.complex_data = Some(data);
+        self.channel_data = None;
+        self.combined_channels = false;
         self.sample_rate = sample_rate;
         self
     }
@@ -152,6 +506,57 @@ impl SpecOptionsBuilder {
     ///
     pub fn downsample(mut self, divisor: usize) -> Self {
         self.downsample_divisor = Some(divisor);
+        self.downsample_filtered = false;
+        self
+    }
+
+    ///
+    /// Down sample the data by the given divisor, after applying a low-pass
+    /// FIR filter to remove the frequencies above the new nyquist frequency.
+    /// This is slower than [downsample], but frequencies above the new
+    /// nyquist frequency are not aliased into the spectrogram.
+    ///
+    /// [downsample]: SpecOptionsBuilder::downsample
+    ///
+    /// # Arguments
+    ///
+    ///  * `divisor` - How much to reduce the data by.
+    ///
+    pub fn downsample_filtered(mut self, divisor: usize) -> Self {
+        self.downsample_divisor = Some(divisor);
+        self.downsample_filtered = true;
+        self
+    }
+
+    ///
+    /// Apply a bandpass filter to the samples before the FFT, to keep only
+    /// the frequencies between `low_hz` and `high_hz`.  The filter is a
+    /// second order Butterworth high-pass at `low_hz` followed by a second
+    /// order Butterworth low-pass at `high_hz`, so the band edges are not
+    /// sharp.  It is applied after downsampling, both frequencies must be
+    /// between 0 Hz and the nyquist frequency of the downsampled data.
+    ///
+    /// # Arguments
+    ///
+    ///  * `low_hz` - The lower cutoff frequency, in Hz.
+    ///  * `high_hz` - The upper cutoff frequency, in Hz.
+    ///
+    pub fn bandpass(mut self, low_hz: f32, high_hz: f32) -> Self {
+        self.bandpass = Some((low_hz, high_hz));
+        self
+    }
+
+    ///
+    /// Only read the first `n` samples (per channel) from a file, e.g. for a
+    /// quick preview of a long recording.  The rest of the file is not read
+    /// or decoded.  This must be called before `load_data_from_file`.
+    ///
+    /// # Arguments
+    ///
+    ///  * `n` - The maximum number of samples to load.
+    ///
+    pub fn max_samples(mut self, n: usize) -> Self {
+        self.max_samples = Some(n);
         self
     }
 
@@ -161,25 +566,175 @@ impl SpecOptionsBuilder {
     ///
     pub fn channel(mut self, channel: u16) -> Self {
         self.channel = channel;
+        self.mixdown = false;
+        self.channel_weights = None;
+        self.all_channels = false;
+        self
+    }
+
+    ///
+    /// Average all the channels when importing multichannel data, rather than
+    /// using a single [channel](SpecOptionsBuilder::channel).  This must be
+    /// called before the data is loaded.
+    ///
+    pub fn mixdown(mut self) -> Self {
+        self.mixdown = true;
+        self.channel_weights = None;
+        self.all_channels = false;
+        self
+    }
+
+    ///
+    /// Combine the channels as a weighted sum when importing multichannel
+    /// data, e.g. `[0.5, 0.5]` averages a stereo pair and `[1.0, -1.0]` gives
+    /// the side signal.  There must be one weight for each channel of the
+    /// data, otherwise loading fails with [SonogramError::InvalidChannel].
+    /// This must be called before the data is loaded.
+    ///
+    /// # Arguments
+    ///
+    ///  * `weights` - The weight of each channel.
+    ///
+    pub fn channel_weights(mut self, weights: Vec<f32>) -> Self {
+        self.channel_weights = Some(weights);
+        self.mixdown = false;
+        self.all_channels = false;
+        self
+    }
+
+    ///
+    /// Keep every channel when importing multichannel data, rather than
+    /// combining them into one.  Build with
+    /// [SpecOptionsBuilder::build_multichannel] to get a spectrogram for
+    /// each channel from the one load.  The processing is applied to each
+    /// channel separately, except [normalise](SpecOptionsBuilder::normalise)
+    /// which uses the peak across all the channels, so their relative levels
+    /// are kept.  This must be called before the data is loaded, otherwise
+    /// `build_multichannel` fails with [SonogramError::InvalidChannel] for
+    /// multichannel data.
+    ///
+    pub fn all_channels(mut self) -> Self {
+        self.all_channels = true;
+        self.mixdown = false;
+        self.channel_weights = None;
         self
     }
 
     ///
     /// Normalise all the sample values to range from -1.0 to 1.0.
     ///
+    /// Normalisation is applied after downsampling and before the [scale]
+    /// factor, so combining `normalise()` with `scale(f)` gives samples in
+    /// the range -f to f.
+    ///
+    /// [scale]: SpecOptionsBuilder::scale
+    ///
     pub fn normalise(mut self) -> Self {
         self.do_normalise = true;
         self
     }
 
+    ///
+    /// Zero every sample whose absolute value is below `threshold`, a crude
+    /// way to remove a constant low-level hiss from the background of the
+    /// spectrogram.  For complex data the magnitude of each sample is used.
+    ///
+    /// The gate is applied after [normalise](SpecOptionsBuilder::normalise)
+    /// and before the [scale](SpecOptionsBuilder::scale) factor.  With
+    /// normalisation the threshold is relative to the peak, e.g. 0.01 gates
+    /// everything 40 dB below the peak, otherwise it is in the -1.0 to 1.0
+    /// range of the loaded samples.
+    ///
+    pub fn noise_gate(mut self, threshold: f32) -> Self {
+        self.noise_gate = Some(threshold);
+        self
+    }
+
+    ///
+    /// The fraction, from 0.0 to 1.0, of the loaded samples that are clipped,
+    /// i.e. in a run of two or more samples that hold the positive or negative
+    /// peak of the data.  Call this after loading to check the data for
+    /// clipping, which adds broadband energy to the spectrogram.  The peak is
+    /// taken from the data itself, so this works for unscaled data, e.g. from
+    /// [load_data_from_memory_raw_i16], and for data clipped below full scale.
+    /// A clean recording has few or no such samples, much more than about
+    /// 0.1% means it is clipped.  With
+    /// [all_channels](SpecOptionsBuilder::all_channels) every channel is
+    /// checked against its own peak.
+    ///
+    /// [load_data_from_memory_raw_i16]: SpecOptionsBuilder::load_data_from_memory_raw_i16
+    ///
+    /// # Returns
+    ///
+    /// The clipped fraction, or `None` if no real data has been loaded, e.g.
+    /// for complex data.
+    ///
+    pub fn detect_clipping(&self) -> Option<f32> {
+        let channels = match &self.channel_data {
+            Some(channel_data) => channel_data.as_slice(),
+            None => std::slice::from_ref(&self.data),
+        };
+        let len: usize = channels.iter().map(Vec::len).sum();
+        if self.complex_data.is_some() || len == 0 {
+            return None;
+        }
+
+        let clipped: usize = channels
+            .iter()
+            .flat_map(|data| clipped_runs(data))
+            .map(|run| run.len())
+            .sum();
+        Some(clipped as f32 / len as f32)
+    }
+
+    ///
+    /// Repair clipped samples, as found by
+    /// [detect_clipping](SpecOptionsBuilder::detect_clipping), before any
+    /// other processing.  Each run of clipped samples is replaced by a cubic
+    /// curve that follows the slope of the signal on either side, so the
+    /// flattened peaks are restored and may go beyond the clipping level.
+    /// Runs at the very start or end of the data, or right next to another
+    /// run, are left alone.  This only applies to real data.
+    ///
+    pub fn declip(mut self) -> Self {
+        self.declip = true;
+        self
+    }
+
     ///
     /// Scale the sample data by the given amount.
     ///
+    /// The scale factor is applied last, after downsampling,
+    /// [normalise](SpecOptionsBuilder::normalise) and the
+    /// [noise_gate](SpecOptionsBuilder::noise_gate).
+    ///
     pub fn scale(mut self, scale_factor: f32) -> Self {
         self.scale_factor = Some(scale_factor);
         self
     }
 
+    ///
+    /// Apply a custom transform to every sample, for example companding,
+    /// clipping or a gain curve.  The transform is applied last, after
+    /// downsampling, [bandpass], [normalise] and [scale], and before the
+    /// window function.  For complex data the transform is applied to the real
+    /// and imaginary parts separately.  Calling this again replaces the
+    /// previous transform.  The transform must be [Send] and [Sync] so the
+    /// builder can still be moved to another thread.
+    ///
+    /// [bandpass]: SpecOptionsBuilder::bandpass
+    /// [normalise]: SpecOptionsBuilder::normalise
+    /// [scale]: SpecOptionsBuilder::scale
+    ///
+    /// # Arguments
+    ///
+    ///  * `f` - The transform, called with each sample value.
+    ///
+    pub fn map_samples(mut self, f: impl Fn(f32) -> f32 + Send + Sync + 'static) -> Self {
+        self.sample_map = Some(Box::new(f));
+        self
+    }
+
     /// A window function describes the type of window to use during the
     /// DFT (discrete fourier transform).  See
     /// (here)[https://en.wikipedia.org/wiki/Window_function] for more details.
@@ -190,6 +745,22 @@ impl SpecOptionsBuilder {
     ///
     pub fn set_window_fn(mut self, window_fn: WindowFn) -> Self {
         self.window_fn = window_fn;
+        self.window_coeffs = None;
+        self
+    }
+
+    ///
+    /// Use a custom window shape, given as one coefficient per sample, rather
+    /// than a window function.  There must be `num_bins` coefficients, after
+    /// any [SpecOptionsBuilder::window_ms] conversion, otherwise `build`
+    /// returns [SonogramError::InvalidWindowLength].
+    ///
+    /// # Arguments
+    ///
+    ///  * `coeffs` - The coefficient to multiply each sample of a window by.
+    ///
+    pub fn set_window_coeffs(mut self, coeffs: Vec<f32>) -> Self {
+        self.window_coeffs = Some(coeffs);
         self
     }
 
@@ -199,84 +770,1428 @@ impl SpecOptionsBuilder {
     /// increase the smoothness of the sample, but take more time.  The default
     /// step size, if not set, is the same as the number of FFT bins.  This
     /// there is no overlap between windows and it most cases will suit your
-    /// needs.
+    /// needs.  A step of zero makes `build` return
+    /// [SonogramError::InvalidStepSize].
     ///
     pub fn set_step_size(mut self, step_size: usize) -> Self {
         self.step_size = step_size;
+        self.explicit_step = true;
+        self.step_seconds = None;
         self
     }
 
     ///
-    /// The final method to be called.  This will create an instance of
-    /// [Spectrograph].
+    /// The same as [SpecOptionsBuilder::set_step_size], but the step is given
+    /// in seconds, e.g. 0.01 for a column every 10 ms.  It is converted to the
+    /// nearest number of samples when the spectrogram is built, using the
+    /// sample rate after downsampling.  The step must be at least 1 sample.
     ///
-    pub fn build(mut self) -> Result<SpecCompute, SonogramError> {
-        if self.data.is_empty() {
-            // SpecOptionsBuilder requires data to be loaded
-            return Err(SonogramError::IncompleteData);
-        }
+    /// # Arguments
+    ///
+    ///  * `secs` - The time between each window, in seconds.
+    ///
+    pub fn set_step_seconds(mut self, secs: f32) -> Self {
+        self.step_seconds = Some(secs);
+        self
+    }
 
-        if self.channel == 0 {
-            // The channel must be an integer 1 or greater
-            return Err(SonogramError::InvalidChannel);
-        }
+    ///
+    /// Set the window length in milliseconds, e.g. 25.0 for the 25 ms windows
+    /// common in speech analysis.  This replaces the `num_bins` given to
+    /// [SpecOptionsBuilder::new] with the power of two nearest to
+    /// `ms * sample_rate / 1000` samples, so the window may be up to about
+    /// 40% longer or 30% shorter than asked for.  It is resolved when the
+    /// spectrogram is built, using the sample rate after downsampling.  The
+    /// actual length is given by [SpecCompute::window_seconds].  A window of
+    /// less than one sample, or more than 2^20 samples, makes `build` return
+    /// [SonogramError::InvalidNumBins].
+    ///
+    /// Unless the step size was set with [SpecOptionsBuilder::set_step_size],
+    /// it follows the window length, so the windows don't overlap.
+    ///
+    /// # Arguments
+    ///
+    ///  * `ms` - The length of each window, in milliseconds.
+    ///
+    pub fn window_ms(mut self, ms: f32) -> Self {
+        self.window_ms = Some(ms);
+        self
+    }
 
-        //
-        // Do downsample
-        //
+    ///
+    /// When the data length is not an exact multiple of the step size the
+    /// trailing samples that don't fill a complete window are dropped, this
+    /// is the default.  Set `include` to true to zero-pad the tail so that
+    /// the last partial window is included in the spectrogram.
+    ///
+    pub fn include_partial_window(mut self, include: bool) -> Self {
+        self.include_partial_window = include;
+        self
+    }
 
-        if let Some(divisor) = self.downsample_divisor {
-            if divisor == 0 {
-                return Err(SonogramError::InvalidDivisor);
-            }
+    ///
+    /// Pad `num_bins / 2` zeros at both ends of the data, so the first window
+    /// is centered on the first sample, as with librosa's `center=True`.
+    /// [Spectrogram::column_to_time] accounts for the padding, and the
+    /// spectrogram gets about `num_bins / step_size` more columns.
+    ///
+    /// [Spectrogram::column_to_time]: crate::Spectrogram::column_to_time
+    ///
+    pub fn center_windows(mut self) -> Self {
+        self.center_windows = true;
+        self
+    }
 
-            if divisor > 1 {
-                for (j, i) in (0..self.data.len() - divisor).step_by(divisor).enumerate() {
-                    let sum: f32 = self.data[i..i + divisor].iter().fold(0.0, |mut sum, &val| {
-                        sum += val;
-                        sum
-                    });
-                    let avg = sum / (divisor as f32);
-
-                    self.data[j] = avg;
-                }
-                self.data.resize(self.data.len() / divisor, 0.0);
-                self.sample_rate /= divisor as u32;
-            }
-        }
+    ///
+    /// Set how the data is padded past its ends, for the last partial window
+    /// and when the windows are centered.  Zero padding, the default, adds a
+    /// click at the end of the data, [PaddingMode::Reflect] avoids this.
+    ///
+    pub fn padding_mode(mut self, mode: PaddingMode) -> Self {
+        self.padding_mode = mode;
+        self
+    }
 
-        //
-        // Normalise
-        //
+    ///
+    /// Convert the spectrogram to dB when it is computed, rather than when it
+    /// is rendered.  The raw rows, the CSV and the PNG output then all hold
+    /// the same dB values.  By default the computed spectrogram holds linear
+    /// magnitudes.
+    ///
+    pub fn db_output(mut self, db_output: bool) -> Self {
+        self.db_output = db_output;
+        self
+    }
 
-        if self.do_normalise {
-            let max = self
-                .data
-                .iter()
-                .reduce(|max, x| if x > max { x } else { max })
-                .unwrap();
+    ///
+    /// Select how [SpecOptionsBuilder::db_output] converts to dB.  By
+    /// default the values are relative to the peak, like the rendered image.
+    /// [DbConvention::Dbfs] gives the usual `20 * log10(amplitude)`, where a
+    /// full scale sine reads 0 dB, to match other audio tools.  See
+    /// [DbConvention] for the exact formulas.
+    ///
+    pub fn db_convention(mut self, convention: DbConvention) -> Self {
+        self.db_convention = convention;
+        self
+    }
 
-            let norm = 1.0 / max;
-            for x in self.data.iter_mut() {
-                *x *= norm;
-            }
-        }
+    ///
+    /// Normalise the FFT output magnitudes, so the magnitude of a tone does
+    /// not depend on the number of FFT bins.  By default the output is not
+    /// normalised, see [FftNormalisation].
+    ///
+    pub fn fft_normalisation(mut self, normalisation: FftNormalisation) -> Self {
+        self.normalisation = normalisation;
+        self
+    }
 
-        //
-        // Apply the scale factor
-        //
+    ///
+    /// Compute the spectrogram with `num_threads` threads, or one per core
+    /// when `num_threads` is zero.  The default is one thread.  This needs the
+    /// `rayon` feature, see [SpecCompute::set_num_threads] for how the work
+    /// is shared.
+    ///
+    pub fn num_threads(mut self, num_threads: usize) -> Self {
+        self.num_threads = num_threads;
+        self
+    }
 
-        if let Some(scale_factor) = self.scale_factor {
-            for x in self.data.iter_mut() {
-                *x *= scale_factor;
-            }
-        }
+    ///
+    /// Keep the full two-sided spectrum, with `num_bins` rows and DC in the
+    /// centre, rather than only the positive frequencies.  See
+    /// [SpecCompute::set_two_sided] for the row order.  By default complex
+    /// data is two-sided and real data is not.
+    ///
+    pub fn two_sided(mut self, two_sided: bool) -> Self {
+        self.two_sided = Some(two_sided);
+        self
+    }
 
-        Ok(SpecCompute::new(
-            self.num_bins,
-            self.step_size,
-            self.data,
-            self.window_fn,
-        ))
+    ///
+    /// The final method to be called.  This will create an instance of
+    /// [Spectrograph].
+    ///
+    pub fn build(self) -> Result<SpecCompute, SonogramError> {
+        let mut planner = FftPlanner::<f32>::new();
+        self.build_with_planner(&mut planner)
+    }
+
+    ///
+    /// The same as [SpecOptionsBuilder::build], but the FFT is planned using
+    /// the given [FftPlanner].  Reuse the planner when building many
+    /// spectrograms with the same number of bins, the FFT plan is then only
+    /// computed once.
+    ///
+    pub fn build_with_planner(
+        mut self,
+        planner: &mut FftPlanner<f32>,
+    ) -> Result<SpecCompute, SonogramError> {
+        self.process_data()?;
+        self.spec_compute(planner)
+    }
+
+    ///
+    /// Build a [MultiSpecCompute], with one [SpecCompute] for each channel
+    /// loaded with [SpecOptionsBuilder::all_channels].  The channels share
+    /// one FFT plan.  If the data was loaded as a single channel there is
+    /// just the one.
+    ///
+    /// A silent channel, e.g. the muted side of a stereo recording, gives a
+    /// spectrogram of zeros.  Only when every channel is silent does this
+    /// fail with [SonogramError::SilentInput].
+    ///
+    pub fn build_multichannel(mut self) -> Result<MultiSpecCompute, SonogramError> {
+        let mut planner = FftPlanner::<f32>::new();
+        let channel_data = match self.channel_data.take() {
+            Some(channel_data) => channel_data,
+            None if self.all_channels && self.combined_channels => {
+                // `all_channels` was called after the channels were combined
+                return Err(SonogramError::InvalidChannel);
+            }
+            None => {
+                return Ok(MultiSpecCompute::new(vec![
+                    self.build_with_planner(&mut planner)?
+                ]))
+            }
+        };
+
+        // Processing the data changes the sample rate and sizes, so each
+        // channel starts from the settings as loaded
+        let (sample_rate, num_bins, step_size) = (self.sample_rate, self.num_bins, self.step_size);
+        let mut filtered = Vec::with_capacity(channel_data.len());
+        let mut peak: f32 = 0.0;
+        for data in channel_data {
+            self.data = data;
+            self.sample_rate = sample_rate;
+            self.num_bins = num_bins;
+            self.step_size = step_size;
+            self.filter_data()?;
+            peak = peak.max(self.peak());
+            filtered.push(std::mem::take(&mut self.data));
+        }
+        if peak <= SILENCE_THRESHOLD {
+            return Err(SonogramError::SilentInput);
+        }
+
+        // The channels are normalised together, to keep their relative levels
+        let channels = filtered
+            .into_iter()
+            .map(|data| {
+                self.data = data;
+                self.scale_data(peak);
+                self.spec_compute(&mut planner)
+            })
+            .collect::<Result<_, SonogramError>>()?;
+
+        Ok(MultiSpecCompute::new(channels))
+    }
+
+    ///
+    /// Create the [SpecCompute] for the processed data, taking the data out
+    /// of the builder.
+    ///
+    fn spec_compute(
+        &mut self,
+        planner: &mut FftPlanner<f32>,
+    ) -> Result<SpecCompute, SonogramError> {
+        let is_complex = self.complex_data.is_some();
+        let mut spec_compute = SpecCompute::with_planner(
+            self.num_bins,
+            self.step_size,
+            std::mem::take(&mut self.data),
+            self.window_fn,
+            planner,
+        );
+        if let Some(complex_data) = self.complex_data.take() {
+            spec_compute.set_complex_data(complex_data);
+        }
+        if let Some(coeffs) = &self.window_coeffs {
+            spec_compute.set_window_coeffs(coeffs.clone())?;
+        }
+        spec_compute.set_include_partial_window(self.include_partial_window);
+        spec_compute.set_center_windows(self.center_windows);
+        spec_compute.set_padding_mode(self.padding_mode);
+        spec_compute.set_sample_rate(self.sample_rate);
+        spec_compute.set_db_output(self.db_output);
+        spec_compute.set_db_convention(self.db_convention);
+        spec_compute.set_fft_normalisation(self.normalisation);
+        spec_compute.set_two_sided(self.two_sided.unwrap_or(is_complex));
+        spec_compute.set_num_threads(self.num_threads);
+
+        Ok(spec_compute)
+    }
+
+    ///
+    /// Validate the settings and apply them to the loaded data, in the order:
+    /// declip, downsample, bandpass, normalise, noise gate, scale and the
+    /// custom sample transform.
+    /// Silent data is rejected with [SonogramError::SilentInput].
+    ///
+    fn process_data(&mut self) -> Result<(), SonogramError> {
+        self.filter_data()?;
+
+        // Check there is a signal, silence would normalise to NaN
+        let peak = self.peak();
+        if peak <= SILENCE_THRESHOLD {
+            return Err(SonogramError::SilentInput);
+        }
+
+        self.scale_data(peak);
+        Ok(())
+    }
+
+    /// True if no samples are loaded, real or complex.
+    fn is_empty(&self) -> bool {
+        match &self.complex_data {
+            Some(complex_data) => complex_data.is_empty(),
+            None => self.data.is_empty(),
+        }
+    }
+
+    ///
+    /// Validate the settings and apply the first part of the processing to
+    /// the loaded data: declip, downsample and bandpass.
+    ///
+    fn filter_data(&mut self) -> Result<(), SonogramError> {
+        if self.is_empty() {
+            // SpecOptionsBuilder requires data to be loaded
+            return Err(SonogramError::IncompleteData);
+        }
+
+        if self.invalid_freq_resolution {
+            return Err(SonogramError::InvalidFrequency);
+        }
+
+        if self.channel == 0 {
+            // The channel must be an integer 1 or greater
+            return Err(SonogramError::InvalidChannel);
+        }
+
+        let two_sided = self.two_sided.unwrap_or(self.complex_data.is_some());
+        if self.num_bins == 0 || (!two_sided && !self.num_bins.is_multiple_of(2)) {
+            // An odd number of bins has no clear Nyquist bin
+            return Err(SonogramError::InvalidNumBins);
+        }
+
+        //
+        // Repair the clipped samples, while they are still at full scale
+        //
+
+        if self.declip && self.complex_data.is_none() {
+            declip(&mut self.data);
+        }
+
+        //
+        // Do downsample
+        //
+
+        if let Some(divisor) = self.downsample_divisor {
+            if divisor == 0 {
+                return Err(SonogramError::InvalidDivisor);
+            }
+
+            if divisor > 1 {
+                match (&mut self.complex_data, self.downsample_filtered) {
+                    (Some(complex_data), true) => decimate(complex_data, divisor),
+                    (Some(complex_data), false) => downsample(complex_data, divisor),
+                    (None, true) => decimate(&mut self.data, divisor),
+                    (None, false) => downsample(&mut self.data, divisor),
+                }
+                self.sample_rate /= divisor as u32;
+            }
+
+            if self.is_empty() {
+                // There were fewer samples than the divisor
+                return Err(SonogramError::IncompleteData);
+            }
+        }
+
+        //
+        // Convert the window length to samples, at the downsampled rate
+        //
+
+        if let Some(ms) = self.window_ms {
+            let samples = ms * self.sample_rate as f32 / 1000.0;
+            let exponent = samples.log2().round();
+            if !(samples >= 1.0 && exponent <= MAX_NUM_BINS_LOG2) {
+                // Less than a sample, or an unreasonably large FFT
+                return Err(SonogramError::InvalidNumBins);
+            }
+            let num_bins = 1 << exponent.max(1.0) as u32;
+            if !self.explicit_step {
+                // The default step follows the window length
+                self.step_size = num_bins;
+            }
+            self.num_bins = num_bins;
+        }
+
+        //
+        // Convert the step size to samples, at the downsampled rate
+        //
+
+        if let Some(secs) = self.step_seconds {
+            let step_size = (secs * self.sample_rate as f32).round();
+            if step_size.is_nan() || step_size < 1.0 {
+                return Err(SonogramError::InvalidStepSize);
+            }
+            self.step_size = step_size as usize;
+        }
+        if self.step_size == 0 {
+            // The windows would never advance
+            return Err(SonogramError::InvalidStepSize);
+        }
+
+        //
+        // Bandpass filter
+        //
+
+        if let Some((low_hz, high_hz)) = self.bandpass {
+            let nyquist = self.sample_rate as f32 / 2.0;
+            if !(low_hz > 0.0 && low_hz < high_hz && high_hz < nyquist) {
+                return Err(SonogramError::InvalidFrequency);
+            }
+
+            let filters = [
+                Biquad::highpass(low_hz, self.sample_rate),
+                Biquad::lowpass(high_hz, self.sample_rate),
+            ];
+            for filter in &filters {
+                match &mut self.complex_data {
+                    Some(complex_data) => filter.apply(complex_data),
+                    None => filter.apply(&mut self.data),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// The largest magnitude of the loaded samples.
+    ///
+    fn peak(&self) -> f32 {
+        match &self.complex_data {
+            Some(complex_data) => complex_data.iter().map(|x| x.norm()).fold(0.0, f32::max),
+            None => self.data.iter().map(|x| x.abs()).fold(0.0, f32::max),
+        }
+    }
+
+    ///
+    /// Apply the rest of the processing to the filtered data: normalise by
+    /// `peak`, noise gate, scale and the custom sample transform.
+    ///
+    fn scale_data(&mut self, peak: f32) {
+        //
+        // Normalise
+        //
+
+        if self.do_normalise {
+            match &mut self.complex_data {
+                Some(complex_data) => normalise(complex_data, peak),
+                None => normalise(&mut self.data, peak),
+            }
+        }
+
+        //
+        // Apply the noise gate
+        //
+
+        if let Some(threshold) = self.noise_gate {
+            match &mut self.complex_data {
+                Some(complex_data) => complex_data
+                    .iter_mut()
+                    .filter(|x| x.norm() < threshold)
+                    .for_each(|x| *x = Complex::new(0.0, 0.0)),
+                None => self
+                    .data
+                    .iter_mut()
+                    .filter(|x| x.abs() < threshold)
+                    .for_each(|x| *x = 0.0),
+            }
+        }
+
+        //
+        // Apply the scale factor
+        //
+
+        if let Some(scale_factor) = self.scale_factor {
+            match &mut self.complex_data {
+                Some(complex_data) => complex_data.iter_mut().for_each(|x| *x *= scale_factor),
+                None => self.data.iter_mut().for_each(|x| *x *= scale_factor),
+            }
+        }
+
+        //
+        // Apply the custom transform
+        //
+
+        if let Some(f) = &self.sample_map {
+            match &mut self.complex_data {
+                Some(complex_data) => complex_data
+                    .iter_mut()
+                    .for_each(|x| *x = Complex::new(f(x.re), f(x.im))),
+                None => self.data.iter_mut().for_each(|x| *x = f(*x)),
+            }
+        }
+    }
+}
+
+///
+/// Downsample the data by averaging every `divisor` samples, any remaining
+/// samples at the end are dropped.
+///
+fn downsample<T>(data: &mut Vec<T>, divisor: usize)
+where
+    T: Copy + Sum<T> + Div<f32, Output = T>,
+{
+    let len = data.len() / divisor;
+    for j in 0..len {
+        let sum: T = data[j * divisor..(j + 1) * divisor].iter().copied().sum();
+        data[j] = sum / (divisor as f32);
+    }
+    data.truncate(len);
+}
+
+///
+/// Downsample the data by keeping every `divisor` samples, after applying a
+/// windowed-sinc low-pass filter with a cutoff at the new nyquist frequency.
+/// The result has the same length as [downsample] gives.
+///
+fn decimate<T>(data: &mut Vec<T>, divisor: usize)
+where
+    T: Copy + Sum<T> + Mul<f32, Output = T>,
+{
+    let taps = lowpass_filter(divisor);
+    let half = taps.len() / 2;
+
+    // Only the samples that are kept need to be filtered
+    let len = data.len() / divisor;
+    let filtered: Vec<T> = (0..len)
+        .map(|j| {
+            let centre = j * divisor;
+            let start = centre.saturating_sub(half);
+            let end = usize::min(centre + half + 1, data.len());
+            data[start..end]
+                .iter()
+                .zip(&taps[start + half - centre..])
+                .map(|(&x, &h)| x * h)
+                .sum()
+        })
+        .collect();
+    *data = filtered;
+}
+
+///
+/// The taps of a low-pass FIR filter with a cutoff at `1 / (2 * divisor)` of
+/// the sample rate.  A windowed-sinc filter, with a Blackman-Harris window and
+/// unity gain at DC.
+///
+fn lowpass_filter(divisor: usize) -> Vec<f32> {
+    let num_taps = 32 * divisor + 1;
+    let half = (num_taps / 2) as f32;
+    let cutoff = 0.5 / divisor as f32;
+
+    let mut taps: Vec<f32> = (0..num_taps)
+        .map(|n| {
+            let x = n as f32 - half;
+            let sinc = if x == 0.0 {
+                1.0
+            } else {
+                (2.0 * f32::consts::PI * cutoff * x).sin() / (2.0 * f32::consts::PI * cutoff * x)
+            };
+            sinc * window_fn::blackman_harris(n, num_taps)
+        })
+        .collect();
+
+    let sum: f32 = taps.iter().sum();
+    taps.iter_mut().for_each(|h| *h /= sum);
+    taps
+}
+
+///
+/// A second order IIR filter, with coefficients normalised so `a0` is 1.0.
+///
+struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+}
+
+impl Biquad {
+    /// The Q factor of a second order Butterworth filter.
+    const BUTTERWORTH_Q: f32 = f32::consts::FRAC_1_SQRT_2;
+
+    ///
+    /// A Butterworth low-pass filter, from the Audio EQ Cookbook.
+    ///
+    fn lowpass(cutoff_hz: f32, sample_rate: u32) -> Self {
+        let (cos_w0, alpha) = Self::params(cutoff_hz, sample_rate);
+        let b1 = 1.0 - cos_w0;
+        Self::normalised(b1 / 2.0, b1, b1 / 2.0, cos_w0, alpha)
+    }
+
+    ///
+    /// A Butterworth high-pass filter, from the Audio EQ Cookbook.
+    ///
+    fn highpass(cutoff_hz: f32, sample_rate: u32) -> Self {
+        let (cos_w0, alpha) = Self::params(cutoff_hz, sample_rate);
+        let b1 = -(1.0 + cos_w0);
+        Self::normalised(-b1 / 2.0, b1, -b1 / 2.0, cos_w0, alpha)
+    }
+
+    fn params(cutoff_hz: f32, sample_rate: u32) -> (f32, f32) {
+        let w0 = 2.0 * f32::consts::PI * cutoff_hz / sample_rate as f32;
+        (w0.cos(), w0.sin() / (2.0 * Self::BUTTERWORTH_Q))
+    }
+
+    fn normalised(b0: f32, b1: f32, b2: f32, cos_w0: f32, alpha: f32) -> Self {
+        let a0 = 1.0 + alpha;
+        Biquad {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: -2.0 * cos_w0 / a0,
+            a2: (1.0 - alpha) / a0,
+        }
+    }
+
+    ///
+    /// Filter the data in place.
+    ///
+    fn apply<T>(&self, data: &mut [T])
+    where
+        T: Copy + Default + Add<Output = T> + Sub<Output = T> + Mul<f32, Output = T>,
+    {
+        let (mut x1, mut x2, mut y1, mut y2) =
+            (T::default(), T::default(), T::default(), T::default());
+        for x in data.iter_mut() {
+            let x0 = *x;
+            let y0 = x0 * self.b0 + x1 * self.b1 + x2 * self.b2 - y1 * self.a1 - y2 * self.a2;
+            x2 = x1;
+            x1 = x0;
+            y2 = y1;
+            y1 = y0;
+            *x = y0;
+        }
+    }
+}
+
+///
+/// Find the runs of clipped samples, i.e. two or more consecutive samples
+/// that hold the positive or the negative peak of the data.  A run ends where
+/// the sign changes, so a jump from one peak to the other is two runs.
+///
+fn clipped_runs(data: &[f32]) -> Vec<Range<usize>> {
+    let max = data.iter().copied().fold(0.0, f32::max);
+    let min = data.iter().copied().fold(0.0, f32::min);
+
+    // Which peak the sample holds, 1 for the positive and -1 for the negative
+    let peak = |x: f32| {
+        if max > 0.0 && x >= max * (1.0 - CLIP_TOLERANCE) {
+            1
+        } else if min < 0.0 && x <= min * (1.0 - CLIP_TOLERANCE) {
+            -1
+        } else {
+            0
+        }
+    };
+
+    let mut runs = vec![];
+    let mut i = 0;
+    while i < data.len() {
+        let (start, sign) = (i, peak(data[i]));
+        i += 1;
+        while sign != 0 && i < data.len() && peak(data[i]) == sign {
+            i += 1;
+        }
+        if sign != 0 && i - start >= 2 {
+            runs.push(start..i);
+        }
+    }
+    runs
+}
+
+///
+/// Replace each run of clipped samples with a cubic Hermite curve, from the
+/// sample before the run to the sample after it, with the slopes of the
+/// signal at those samples.  Runs without two unclipped samples on both
+/// sides are left as they are.
+///
+fn declip(data: &mut [f32]) {
+    let runs = clipped_runs(data);
+    for (k, run) in runs.iter().enumerate() {
+        // The run of clipped samples is `start..i`
+        let (start, i) = (run.start, run.end);
+        let prev_end = if k > 0 { runs[k - 1].end } else { 0 };
+        let next_start = runs.get(k + 1).map_or(data.len(), |next| next.start);
+        if start < prev_end + 2 || i + 2 > next_start {
+            continue;
+        }
+
+        // The end points and their slopes, scaled to the length of the curve
+        let len = (i - start + 1) as f32;
+        let (p0, p1) = (data[start - 1], data[i]);
+        let m0 = (data[start - 1] - data[start - 2]) * len;
+        let m1 = (data[i + 1] - data[i]) * len;
+        for (j, x) in data[start..i].iter_mut().enumerate() {
+            let t = (j + 1) as f32 / len;
+            let (t2, t3) = (t * t, t * t * t);
+            *x = (2.0 * t3 - 3.0 * t2 + 1.0) * p0
+                + (t3 - 2.0 * t2 + t) * m0
+                + (3.0 * t2 - 2.0 * t3) * p1
+                + (t3 - t2) * m1;
+        }
+    }
+}
+
+///
+/// Normalise the data by its largest magnitude, `peak`.
+///
+fn normalise<T: MulAssign<f32>>(data: &mut [T], peak: f32) {
+    let norm = 1.0 / peak;
+    for x in data.iter_mut() {
+        *x *= norm;
+    }
+}
+
+///
+/// Scale an i16 sample to the range -1.0 to 1.0.
+///
+fn i16_to_f32(x: i16) -> f32 {
+    x as f32 / i16::MAX as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalise_then_scale() {
+        let mut builder = SpecOptionsBuilder::new(16)
+            .load_data_from_memory_f32(vec![0.25, -0.125, 0.5], 8000)
+            .scale(2.0)
+            .normalise();
+        builder.process_data().unwrap();
+
+        // Normalised to 1.0 first, then scaled
+        assert_eq!(builder.data, vec![1.0, -0.5, 2.0]);
+    }
+
+    #[test]
+    fn test_load_data_from_memory_interleaved() {
+        let stereo: Vec<i16> = vec![100, -100, 200, -200, 300, -100];
+
+        let builder = SpecOptionsBuilder::new(16)
+            .channel(2)
+            .load_data_from_memory_interleaved(stereo.clone(), 2, 8000)
+            .unwrap();
+        let expected: Vec<f32> = [-100.0, -200.0, -100.0]
+            .iter()
+            .map(|x| x / i16::MAX as f32)
+            .collect();
+        assert_eq!(builder.data, expected);
+
+        let builder = SpecOptionsBuilder::new(16)
+            .mixdown()
+            .load_data_from_memory_interleaved(stereo.clone(), 2, 8000)
+            .unwrap();
+        let expected: Vec<f32> = [0.0, 0.0, 100.0]
+            .iter()
+            .map(|x| x / i16::MAX as f32)
+            .collect();
+        assert_eq!(builder.data, expected);
+
+        assert!(matches!(
+            SpecOptionsBuilder::new(16)
+                .channel(3)
+                .load_data_from_memory_interleaved(stereo, 2, 8000),
+            Err(SonogramError::InvalidChannel)
+        ));
+    }
+
+    #[test]
+    fn test_channel_weights() {
+        let stereo: Vec<i16> = vec![100, -100, 200, -200, 300, -100];
+
+        // The side signal
+        let builder = SpecOptionsBuilder::new(16)
+            .channel_weights(vec![1.0, -1.0])
+            .load_data_from_memory_interleaved(stereo.clone(), 2, 8000)
+            .unwrap();
+        let expected: Vec<f32> = [200.0, 400.0, 400.0]
+            .iter()
+            .map(|x| x / i16::MAX as f32)
+            .collect();
+        assert_eq!(builder.data, expected);
+
+        assert!(matches!(
+            SpecOptionsBuilder::new(16)
+                .channel_weights(vec![1.0, 1.0, 1.0])
+                .load_data_from_memory_interleaved(stereo, 2, 8000),
+            Err(SonogramError::InvalidChannel)
+        ));
+
+        // No weights for no channels
+        assert!(matches!(
+            SpecOptionsBuilder::new(16)
+                .channel_weights(vec![])
+                .load_data_from_memory_interleaved(vec![], 0, 8000),
+            Err(SonogramError::InvalidChannel)
+        ));
+    }
+
+    #[test]
+    fn test_with_freq_resolution() {
+        assert_eq!(
+            SpecOptionsBuilder::with_freq_resolution(10.0, 44100).num_bins,
+            4096
+        );
+        assert_eq!(
+            SpecOptionsBuilder::with_freq_resolution(7.0, 44100).num_bins,
+            8192
+        );
+        assert_eq!(
+            SpecOptionsBuilder::with_freq_resolution(1.0, 1024).num_bins,
+            1024
+        );
+        assert_eq!(
+            SpecOptionsBuilder::with_freq_resolution(1e6, 8000).num_bins,
+            2
+        );
+
+        // Very fine resolutions are limited to a sane FFT size
+        assert_eq!(
+            SpecOptionsBuilder::with_freq_resolution(1e-9, 44100).num_bins,
+            1 << 20
+        );
+
+        for hz_per_bin in [0.0, -10.0, f32::NAN, f32::INFINITY] {
+            let result = SpecOptionsBuilder::with_freq_resolution(hz_per_bin, 44100)
+                .load_data_from_memory_f32(vec![0.5; 100], 44100)
+                .build();
+            assert!(matches!(result, Err(SonogramError::InvalidFrequency)));
+        }
+    }
+
+    #[test]
+    fn test_silent_input() {
+        let result = SpecOptionsBuilder::new(64)
+            .load_data_from_memory_f32(vec![0.0; 1000], 8000)
+            .normalise()
+            .build();
+        assert!(matches!(result, Err(SonogramError::SilentInput)));
+
+        let result = SpecOptionsBuilder::new(64)
+            .load_data_from_memory(vec![0; 1000], 8000)
+            .build();
+        assert!(matches!(result, Err(SonogramError::SilentInput)));
+
+        // Downsampling fewer samples than the divisor leaves no data at all
+        let result = SpecOptionsBuilder::new(64)
+            .load_data_from_memory_f32(vec![0.5; 3], 8000)
+            .downsample(4)
+            .build();
+        assert!(matches!(result, Err(SonogramError::IncompleteData)));
+        let result = SpecOptionsBuilder::new(64)
+            .load_data_from_memory_f32(vec![0.5; 3], 8000)
+            .downsample_filtered(4)
+            .build();
+        assert!(matches!(result, Err(SonogramError::IncompleteData)));
+
+        // A single sample is enough
+        let mut data = vec![0.0; 1000];
+        data[500] = 0.01;
+        assert!(SpecOptionsBuilder::new(64)
+            .load_data_from_memory_f32(data, 8000)
+            .normalise()
+            .build()
+            .is_ok());
+    }
+
+    #[test]
+    fn test_num_bins() {
+        // The highest bin below Nyquist, 7 Hz at a 16 Hz sample rate
+        let data: Vec<f32> = (0..256)
+            .map(|i| (2.0 * std::f32::consts::PI * 7.0 * i as f32 / 16.0).sin())
+            .collect();
+        let spec = SpecOptionsBuilder::new(16)
+            .load_data_from_memory_f32(data.clone(), 16)
+            .build()
+            .unwrap()
+            .compute();
+        assert_eq!(spec.height(), 8);
+        let top_row = spec.row_iter().next().unwrap();
+        for row in spec.row_iter().skip(1) {
+            assert!(row[0] < top_row[0]);
+        }
+
+        // Odd sizes are rejected, unless two-sided
+        let result = SpecOptionsBuilder::new(15)
+            .load_data_from_memory_f32(data.clone(), 16)
+            .build();
+        assert!(matches!(result, Err(SonogramError::InvalidNumBins)));
+        assert!(SpecOptionsBuilder::new(15)
+            .load_data_from_memory_f32(data, 16)
+            .two_sided(true)
+            .build()
+            .is_ok());
+    }
+
+    #[test]
+    #[cfg(feature = "hound")]
+    fn test_float_wav() {
+        // A 0.5 amplitude, 1 kHz sine sampled at 8 kHz
+        let builder = SpecOptionsBuilder::new(64)
+            .load_data_from_file(Path::new("samples/sine-1khz-f32.wav"))
+            .unwrap();
+        assert_eq!(builder.sample_rate, 8000);
+        assert_eq!(builder.data.len(), 4096);
+        assert_eq!(builder.data[0], 0.0);
+        assert!((builder.data[2] - 0.5).abs() < 1e-6);
+        assert!((builder.data[6] + 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    #[cfg(feature = "hound")]
+    fn test_wav_bit_depths() {
+        let fname = std::env::temp_dir().join(format!("sonogram_bits_{}.wav", std::process::id()));
+        for bits in [8, 16, 24, 32] {
+            let spec = hound::WavSpec {
+                channels: 1,
+                sample_rate: 8000,
+                bits_per_sample: bits,
+                sample_format: hound::SampleFormat::Int,
+            };
+            let max = (1_i64 << (bits - 1)) - 1;
+            let mut writer = hound::WavWriter::create(&fname, spec).unwrap();
+            for x in [max / 2, max, -max] {
+                writer.write_sample(x as i32).unwrap();
+            }
+            writer.finalize().unwrap();
+
+            let builder = SpecOptionsBuilder::new(64)
+                .load_data_from_file(&fname)
+                .unwrap();
+            assert_eq!(builder.data.len(), 3);
+            assert!((builder.data[0] - 0.5).abs() < 0.01);
+            assert_eq!(builder.data[1], 1.0);
+            assert_eq!(builder.data[2], -1.0);
+        }
+        std::fs::remove_file(&fname).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "hound")]
+    fn test_all_channels() {
+        // 500 Hz on the left channel and 1 kHz, twice as loud, on the right
+        let fname = std::env::temp_dir().join(format!("sonogram_all_{}.wav", std::process::id()));
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 8000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(&fname, spec).unwrap();
+        for i in 0..4096 {
+            for (freq, amplitude) in [(500.0, 8000.0), (1000.0, 16000.0)] {
+                let x = (2.0 * std::f32::consts::PI * freq * i as f32 / 8000.0).sin();
+                writer.write_sample((x * amplitude) as i16).unwrap();
+            }
+        }
+        writer.finalize().unwrap();
+
+        let mut multi = SpecOptionsBuilder::new(64)
+            .all_channels()
+            .load_data_from_file(&fname)
+            .unwrap()
+            .downsample(2)
+            .build_multichannel()
+            .unwrap();
+        assert_eq!(multi.num_channels(), 2);
+        let specs = multi.compute();
+        assert_eq!(specs.len(), 2);
+
+        // Each channel is processed the same as when it is loaded alone
+        let mut right = SpecOptionsBuilder::new(64)
+            .channel(2)
+            .load_data_from_file(&fname)
+            .unwrap()
+            .downsample(2)
+            .build()
+            .unwrap();
+        assert_eq!(specs[1].spec, right.compute().spec);
+
+        // Normalising scales every channel by the same amount
+        let normalised = SpecOptionsBuilder::new(64)
+            .all_channels()
+            .load_data_from_file(&fname)
+            .unwrap()
+            .downsample(2)
+            .normalise()
+            .build_multichannel()
+            .unwrap()
+            .compute();
+        let peak = |spec: &crate::Spectrogram| spec.spec.iter().copied().fold(0.0, f32::max);
+        let gain: Vec<f32> = (0..2)
+            .map(|c| peak(&normalised[c]) / peak(&specs[c]))
+            .collect();
+        assert!((gain[0] - gain[1]).abs() < 1e-3 * gain[1]);
+
+        // Calling all_channels after the channels are combined is an error
+        let result = SpecOptionsBuilder::new(64)
+            .load_data_from_file(&fname)
+            .unwrap()
+            .all_channels()
+            .build_multichannel();
+        assert!(matches!(result, Err(SonogramError::InvalidChannel)));
+        std::fs::remove_file(&fname).unwrap();
+
+        for (spec, expected_bin) in specs.iter().zip([8, 16]) {
+            let avg = spec.average_spectrum();
+            let peak = (0..avg.len())
+                .max_by(|&a, &b| avg[a].total_cmp(&avg[b]))
+                .unwrap();
+            assert_eq!(peak, expected_bin);
+        }
+
+        // A single channel gives a single spectrogram
+        let multi = SpecOptionsBuilder::new(64)
+            .load_data_from_memory_f32(vec![0.5; 256], 8000)
+            .build_multichannel()
+            .unwrap();
+        assert_eq!(multi.num_channels(), 1);
+
+        // A silent channel gives a spectrogram of zeros, unless every channel is silent
+        let specs = SpecOptionsBuilder::new(64)
+            .all_channels()
+            .load_data_from_memory_interleaved(
+                [1000, 0, 1000, 0, -1000, 0, -1000, 0].repeat(32),
+                2,
+                8000,
+            )
+            .unwrap()
+            .normalise()
+            .build_multichannel()
+            .unwrap()
+            .compute();
+        assert!(specs[0].spec.iter().any(|&x| x > 0.0));
+        assert!(specs[1].spec.iter().all(|&x| x == 0.0));
+        let result = SpecOptionsBuilder::new(64)
+            .all_channels()
+            .load_data_from_memory_interleaved(vec![0; 256], 2, 8000)
+            .unwrap()
+            .build_multichannel();
+        assert!(matches!(result, Err(SonogramError::SilentInput)));
+    }
+
+    #[test]
+    #[cfg(feature = "hound")]
+    fn test_max_samples() {
+        let fname = std::env::temp_dir().join(format!("sonogram_max_{}.wav", std::process::id()));
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 8000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(&fname, spec).unwrap();
+        for i in 0..100_000 {
+            writer.write_sample((i % 1000) as i16).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let builder = SpecOptionsBuilder::new(64)
+            .max_samples(1000)
+            .channel(2)
+            .load_data_from_file(&fname)
+            .unwrap();
+        assert_eq!(builder.data.len(), 1000);
+        assert_eq!(builder.data[10], 21.0 / i16::MAX as f32);
+
+        let builder = SpecOptionsBuilder::new(64)
+            .load_data_from_file(&fname)
+            .unwrap();
+        assert_eq!(builder.data.len(), 50_000);
+
+        // Too large to count the interleaved samples, so the whole file is read
+        let builder = SpecOptionsBuilder::new(64)
+            .max_samples(usize::MAX)
+            .load_data_from_file(&fname)
+            .unwrap();
+        assert_eq!(builder.data.len(), 50_000);
+        std::fs::remove_file(&fname).unwrap();
+    }
+
+    #[test]
+    fn test_with_height() {
+        let data = vec![0.5; 17 * 256];
+        let spec = SpecOptionsBuilder::with_height(128)
+            .load_data_from_memory_f32(data.clone(), 8000)
+            .build()
+            .unwrap()
+            .compute();
+        assert_eq!(spec.height(), 128);
+        assert_eq!(spec.width(), 17);
+
+        let spec = SpecOptionsBuilder::with_height(128)
+            .load_data_from_memory_f32(data, 8000)
+            .two_sided(true)
+            .build()
+            .unwrap()
+            .compute();
+        assert_eq!(spec.height(), 256);
+    }
+
+    #[test]
+    fn test_set_step_seconds() {
+        let mut builder = SpecOptionsBuilder::new(16)
+            .load_data_from_memory_f32(vec![0.5; 100], 8000)
+            .downsample(2)
+            .set_step_seconds(0.01);
+        builder.process_data().unwrap();
+        assert_eq!(builder.step_size, 40);
+
+        let mut builder = SpecOptionsBuilder::new(16)
+            .load_data_from_memory_f32(vec![0.0; 100], 8000)
+            .set_step_seconds(0.00001);
+        assert!(matches!(
+            builder.process_data(),
+            Err(SonogramError::InvalidStepSize)
+        ));
+    }
+
+    #[test]
+    fn test_zero_step_size() {
+        let result = SpecOptionsBuilder::new(16)
+            .load_data_from_memory_f32(vec![0.5; 100], 8000)
+            .set_step_size(0)
+            .build();
+        assert!(matches!(result, Err(SonogramError::InvalidStepSize)));
+    }
+
+    #[test]
+    fn test_window_ms() {
+        // 25 ms at 16 kHz is 400 samples, the nearest power of two is 512
+        let spec_compute = SpecOptionsBuilder::new(2048)
+            .load_data_from_memory_f32(vec![0.5; 4000], 16000)
+            .window_ms(25.0)
+            .build()
+            .unwrap();
+        assert_eq!(spec_compute.window_seconds(), 0.032);
+
+        // At the downsampled rate, 25 ms at 8 kHz is 200 samples, giving 256
+        let mut builder = SpecOptionsBuilder::new(2048)
+            .load_data_from_memory_f32(vec![0.5; 4000], 16000)
+            .downsample(2)
+            .window_ms(25.0);
+        builder.process_data().unwrap();
+        assert_eq!(builder.num_bins, 256);
+        assert_eq!(builder.step_size, 256);
+
+        // An explicit step size is kept, even if it equals `num_bins`
+        for step_size in [100, 2048] {
+            let mut builder = SpecOptionsBuilder::new(2048)
+                .load_data_from_memory_f32(vec![0.5; 4000], 16000)
+                .set_step_size(step_size)
+                .window_ms(25.0);
+            builder.process_data().unwrap();
+            assert_eq!(builder.num_bins, 512);
+            assert_eq!(builder.step_size, step_size);
+        }
+
+        // Too short, or far too long
+        for ms in [0.01, 1e9, f32::NAN] {
+            let mut builder = SpecOptionsBuilder::new(2048)
+                .load_data_from_memory_f32(vec![0.5; 4000], 16000)
+                .window_ms(ms);
+            assert!(matches!(
+                builder.process_data(),
+                Err(SonogramError::InvalidNumBins)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_normalise_negative_peak() {
+        let mut builder = SpecOptionsBuilder::new(16)
+            .load_data_from_memory_f32(vec![0.1, -0.8, 0.4, -0.2], 8000)
+            .normalise();
+        builder.process_data().unwrap();
+        assert_eq!(builder.data, vec![0.125, -1.0, 0.5, -0.25]);
+
+        // All negative, the waveform is not inverted
+        let mut builder = SpecOptionsBuilder::new(16)
+            .load_data_from_memory_f32(vec![-0.5, -0.25], 8000)
+            .normalise();
+        builder.process_data().unwrap();
+        assert_eq!(builder.data, vec![-1.0, -0.5]);
+    }
+
+    #[test]
+    fn test_load_data_from_memory_raw_i16() {
+        let data = vec![0, 1, -100, i16::MAX];
+        let builder = SpecOptionsBuilder::new(16).load_data_from_memory(data.clone(), 8000);
+        assert_eq!(
+            builder.data,
+            vec![0.0, 1.0 / 32767.0, -100.0 / 32767.0, 1.0]
+        );
+
+        let builder = SpecOptionsBuilder::new(16).load_data_from_memory_raw_i16(data, 8000);
+        assert_eq!(builder.data, vec![0.0, 1.0, -100.0, 32767.0]);
+        assert_eq!(builder.sample_rate, 8000);
+    }
+
+    #[test]
+    fn test_noise_gate() {
+        let mut builder = SpecOptionsBuilder::new(16)
+            .load_data_from_memory_f32(vec![0.01, -0.5, 0.02, -0.03, 0.3], 8000)
+            .noise_gate(0.05);
+        builder.process_data().unwrap();
+        assert_eq!(builder.data, vec![0.0, -0.5, 0.0, 0.0, 0.3]);
+
+        // The threshold is applied to the normalised samples, before scaling
+        let mut builder = SpecOptionsBuilder::new(16)
+            .load_data_from_memory_f32(vec![0.01, -0.05, 0.2, -0.15], 8000)
+            .normalise()
+            .noise_gate(0.5)
+            .scale(2.0);
+        builder.process_data().unwrap();
+        assert_eq!(builder.data, vec![0.0, 0.0, 2.0, -1.5]);
+    }
+
+    #[test]
+    fn test_db_convention() {
+        // A half scale 500 Hz tone, centered on bin 64 of the 1024 bin FFT
+        let tone: Vec<f32> = (0..8192)
+            .map(|i| 0.5 * (2.0 * std::f32::consts::PI * 500.0 * i as f32 / 8000.0).sin())
+            .collect();
+        let dbfs_max = |window_fn: crate::WindowFn, normalisation| {
+            let mut spec_compute = SpecOptionsBuilder::new(1024)
+                .load_data_from_memory_f32(tone.clone(), 8000)
+                .set_window_fn(window_fn)
+                .fft_normalisation(normalisation)
+                .db_output(true)
+                .db_convention(DbConvention::Dbfs)
+                .build()
+                .unwrap();
+            spec_compute.compute().get_min_max().1
+        };
+
+        // -6.02 dB for any window and normalisation
+        let expected = 20.0 * 0.5_f32.log10();
+        assert!(
+            (dbfs_max(window_fn::hann_function, FftNormalisation::None) - expected).abs() < 0.01
+        );
+        assert!(
+            (dbfs_max(window_fn::rectangular, FftNormalisation::NumBins) - expected).abs() < 0.01
+        );
+        assert!(
+            (dbfs_max(window_fn::blackman_harris, FftNormalisation::SqrtNumBins) - expected).abs()
+                < 0.01
+        );
+
+        // The default is relative to the peak
+        let mut spec_compute = SpecOptionsBuilder::new(1024)
+            .load_data_from_memory_f32(tone, 8000)
+            .db_output(true)
+            .build()
+            .unwrap();
+        assert!(spec_compute.compute().get_min_max().1.abs() < 0.001);
+    }
+
+    #[test]
+    fn test_window_coeffs() {
+        let data: Vec<f32> = (0..1024).map(|i| (i as f32 * 0.3).sin()).collect();
+        let spec = |builder: SpecOptionsBuilder| {
+            builder
+                .load_data_from_memory_f32(data.clone(), 8000)
+                .build()
+                .map(|mut spec_compute| spec_compute.compute())
+        };
+
+        // The coefficients of a window function give the same spectrogram
+        let hann: Vec<f32> = (0..64).map(|i| window_fn::hann_function(i, 64)).collect();
+        let expected = spec(SpecOptionsBuilder::new(64).set_window_fn(window_fn::hann_function));
+        let custom = spec(SpecOptionsBuilder::new(64).set_window_coeffs(hann.clone()));
+        assert_eq!(custom.unwrap().spec, expected.unwrap().spec);
+
+        // A later window function replaces them
+        let builder = SpecOptionsBuilder::new(64)
+            .set_window_coeffs(vec![0.0; 64])
+            .set_window_fn(window_fn::rectangular);
+        assert!(spec(builder).unwrap().spec.iter().any(|&v| v > 0.0));
+
+        assert!(matches!(
+            spec(SpecOptionsBuilder::new(32).set_window_coeffs(hann)),
+            Err(SonogramError::InvalidWindowLength)
+        ));
+    }
+
+    #[test]
+    fn test_clipping() {
+        let sine = |amplitude: f32| -> Vec<f32> {
+            (0..8000)
+                .map(|i| amplitude * (2.0 * std::f32::consts::PI * 50.0 * i as f32 / 8000.0).sin())
+                .collect()
+        };
+        let clipped: Vec<f32> = sine(1.2).iter().map(|x| x.clamp(-1.0, 1.0)).collect();
+
+        assert_eq!(SpecOptionsBuilder::new(64).detect_clipping(), None);
+        let builder = SpecOptionsBuilder::new(64).load_data_from_memory_f32(sine(0.5), 8000);
+        assert_eq!(builder.detect_clipping(), Some(0.0));
+
+        // About 37% of a sine 1.2 times full scale is clipped
+        let mut builder = SpecOptionsBuilder::new(64)
+            .load_data_from_memory_f32(clipped.clone(), 8000)
+            .declip();
+        let fraction = builder.detect_clipping().unwrap();
+        assert!((fraction - 0.37).abs() < 0.01);
+
+        // Declipping restores the peaks, much closer to the original sine
+        builder.process_data().unwrap();
+        let error = |data: &[f32]| {
+            data.iter()
+                .zip(sine(1.2))
+                .map(|(a, b)| (a - b).abs())
+                .fold(0.0, f32::max)
+        };
+        assert!(error(&clipped) > 0.19);
+        assert!(error(&builder.data) < 0.05);
+
+        // The clipping level is the peak of the data, not full scale
+        let raw: Vec<i16> = sine(10000.0).iter().map(|&x| x as i16).collect();
+        let builder = SpecOptionsBuilder::new(64).load_data_from_memory_raw_i16(raw, 8000);
+        assert_eq!(builder.detect_clipping(), Some(0.0));
+        let low: Vec<f32> = sine(0.6).iter().map(|x| x.clamp(-0.5, 0.5)).collect();
+        let builder = SpecOptionsBuilder::new(64).load_data_from_memory_f32(low, 8000);
+        assert!(builder.detect_clipping().unwrap() > 0.3);
+
+        // A jump from one peak to the other is two runs, neither is repaired
+        let mut data = vec![0.0, 0.2, 0.5, 1.0, 1.0, -1.0, -1.0, -0.5, -0.2, 0.0];
+        assert_eq!(clipped_runs(&data), vec![3..5, 5..7]);
+        declip(&mut data);
+        assert_eq!(data[3..7], [1.0, 1.0, -1.0, -1.0]);
+    }
+
+    #[test]
+    fn test_map_samples() {
+        let mut builder = SpecOptionsBuilder::new(16)
+            .load_data_from_memory_f32(vec![0.25, -0.125, 0.5], 8000)
+            .normalise()
+            .map_samples(|x| x.clamp(-0.25, 0.75));
+        builder.process_data().unwrap();
+
+        // Clipped after normalising
+        assert_eq!(builder.data, vec![0.5, -0.25, 0.75]);
+    }
+
+    #[test]
+    fn test_builder_is_send() {
+        fn assert_send<T: Send>() {}
+        assert_send::<SpecOptionsBuilder>();
+    }
+
+    #[test]
+    fn test_downsample_filtered() {
+        // A 1500 Hz tone, above the 1000 Hz nyquist frequency after downsampling
+        let data: Vec<f32> = (0..8000)
+            .map(|i| (2.0 * f32::consts::PI * 1500.0 * i as f32 / 8000.0).sin())
+            .collect();
+        let rms = |data: &[f32]| {
+            // Skip the ends, where the filter runs off the data
+            let data = &data[100..data.len() - 100];
+            (data.iter().map(|x| x * x).sum::<f32>() / data.len() as f32).sqrt()
+        };
+
+        let mut averaged = SpecOptionsBuilder::new(16)
+            .load_data_from_memory_f32(data.clone(), 8000)
+            .downsample(4);
+        averaged.process_data().unwrap();
+
+        let mut filtered = SpecOptionsBuilder::new(16)
+            .load_data_from_memory_f32(data, 8000)
+            .downsample_filtered(4);
+        filtered.process_data().unwrap();
+
+        // Averaging lets the tone alias down to 500 Hz, the filter removes it
+        assert_eq!(filtered.data.len(), 2000);
+        assert_eq!(filtered.sample_rate, 2000);
+        assert!(rms(&averaged.data) > 0.1);
+        assert!(rms(&filtered.data) < 0.001);
+    }
+
+    #[test]
+    fn test_downsample_filtered_passband() {
+        // A 200 Hz tone is kept when downsampling from 8000 to 2000 Hz
+        let data: Vec<f32> = (0..8000)
+            .map(|i| (2.0 * f32::consts::PI * 200.0 * i as f32 / 8000.0).sin())
+            .collect();
+        let mut builder = SpecOptionsBuilder::new(16)
+            .load_data_from_memory_f32(data, 8000)
+            .downsample_filtered(4);
+        builder.process_data().unwrap();
+
+        for (j, x) in builder.data.iter().enumerate().skip(100).take(1800) {
+            let expected = (2.0 * f32::consts::PI * 200.0 * j as f32 / 2000.0).sin();
+            assert!((x - expected).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn test_bandpass() {
+        let tone_rms = |freq: f32| {
+            let data: Vec<f32> = (0..16000)
+                .map(|i| (2.0 * f32::consts::PI * freq * i as f32 / 16000.0).sin())
+                .collect();
+            let mut builder = SpecOptionsBuilder::new(16)
+                .load_data_from_memory_f32(data, 16000)
+                .bandpass(300.0, 3400.0);
+            builder.process_data().unwrap();
+
+            // Skip the start, while the filter settles
+            let data = &builder.data[4000..];
+            (2.0 * data.iter().map(|x| x * x).sum::<f32>() / data.len() as f32).sqrt()
+        };
+
+        assert!(tone_rms(1000.0) > 0.95);
+        assert!(tone_rms(50.0) < 0.05);
+        assert!(tone_rms(7500.0) < 0.05);
+    }
+
+    #[test]
+    fn test_bandpass_invalid() {
+        for (low_hz, high_hz) in [(0.0, 1000.0), (2000.0, 1000.0), (300.0, 4000.0)] {
+            let mut builder = SpecOptionsBuilder::new(16)
+                .load_data_from_memory_f32(vec![0.0; 100], 8000)
+                .bandpass(low_hz, high_hz);
+            assert!(matches!(
+                builder.process_data(),
+                Err(SonogramError::InvalidFrequency)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_complex_data() {
+        let data: Vec<Complex<f32>> = (0..9).map(|i| Complex::new(i as f32, -1.0)).collect();
+        let mut builder = SpecOptionsBuilder::new(16)
+            .load_data_from_complex(data, 8000)
+            .downsample(3)
+            .normalise();
+        builder.process_data().unwrap();
+
+        let norm = Complex::new(7.0f32, -1.0).norm();
+        let expected: Vec<Complex<f32>> = [1.0, 4.0, 7.0]
+            .iter()
+            .map(|&re| Complex::new(re, -1.0) / norm)
+            .collect();
+        let complex_data = builder.complex_data.unwrap();
+        assert_eq!(complex_data.len(), 3);
+        for (a, b) in complex_data.iter().zip(&expected) {
+            assert!((a - b).norm() < 0.0001);
+        }
+        assert_eq!(builder.sample_rate, 8000 / 3);
     }
 }
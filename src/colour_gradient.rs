@@ -15,6 +15,8 @@
  * along with this program; if not, see <http://www.gnu.org/licenses/>.
  */
 
+use crate::SonogramError;
+
 #[derive(Clone, Copy)]
 pub enum ColourTheme {
     Default,
@@ -22,6 +24,17 @@ pub enum ColourTheme {
     Rainbow,
     BlackWhite, // Black background to white foreground.
     WhiteBlack, // White background to black foreground.
+    Diverging,  // Blue for negative, white in the centre and red for positive.
+}
+
+///
+/// How the colour between two gradient stops is interpolated.
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InterpMode {
+    Srgb,   // Blend the 8-bit sRGB values directly, this is the default.
+    Linear, // Blend in linear light, avoids muddy midtones.
+    Hsl,    // Blend the hue around the colour wheel, gives smooth rainbows.
 }
 
 /// Colours required for a PNG file, includes the alpha channel.
@@ -37,6 +50,77 @@ impl RGBAColour {
     pub fn new(r: u8, g: u8, b: u8, a: u8) -> Self {
         Self { r, g, b, a }
     }
+
+    ///
+    /// Create a colour from a hex string in the form `#RGB`, `#RRGGBB` or
+    /// `#RRGGBBAA`.  The alpha defaults to 255 when it is not given.
+    ///
+    pub fn from_hex(s: &str) -> Result<Self, SonogramError> {
+        let hex = s.strip_prefix('#').ok_or(SonogramError::InvalidColour)?;
+        if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(SonogramError::InvalidColour);
+        }
+
+        let parse = |digits: &str| u8::from_str_radix(digits, 16).unwrap();
+        match hex.len() {
+            3 => {
+                // Each digit is repeated, e.g. "f" becomes "ff"
+                let expand = |i: usize| parse(&hex[i..i + 1]) * 17;
+                Ok(Self::new(expand(0), expand(1), expand(2), 255))
+            }
+            6 | 8 => {
+                let a = if hex.len() == 8 {
+                    parse(&hex[6..8])
+                } else {
+                    255
+                };
+                Ok(Self::new(
+                    parse(&hex[0..2]),
+                    parse(&hex[2..4]),
+                    parse(&hex[4..6]),
+                    a,
+                ))
+            }
+            _ => Err(SonogramError::InvalidColour),
+        }
+    }
+
+    ///
+    /// Get the same colour with a different alpha.
+    ///
+    pub fn with_alpha(&self, a: u8) -> Self {
+        Self { a, ..self.clone() }
+    }
+
+    ///
+    /// Composite this colour over the background colour `bg`, using the
+    /// standard "source over" alpha compositing.
+    ///
+    pub fn blend_over(&self, bg: &RGBAColour) -> RGBAColour {
+        let src_a = f32::from(self.a) / 255.0;
+        let bg_a = f32::from(bg.a) / 255.0 * (1.0 - src_a);
+        let out_a = src_a + bg_a;
+        if out_a == 0.0 {
+            return RGBAColour::new(0, 0, 0, 0);
+        }
+
+        let blend = |src: u8, bg: u8| {
+            ((f32::from(src) * src_a + f32::from(bg) * bg_a) / out_a).round() as u8
+        };
+        RGBAColour::new(
+            blend(self.r, bg.r),
+            blend(self.g, bg.g),
+            blend(self.b, bg.b),
+            (out_a * 255.0).round() as u8,
+        )
+    }
+
+    ///
+    /// Get the colour as a `#RRGGBBAA` hex string.
+    ///
+    pub fn to_hex(&self) -> String {
+        format!("#{:02x}{:02x}{:02x}{:02x}", self.r, self.g, self.b, self.a)
+    }
 }
 
 /// ColourGradient allows you to create custom colour gradients for each
@@ -44,16 +128,26 @@ impl RGBAColour {
 #[derive(Clone, Debug)]
 pub struct ColourGradient {
     colours: Vec<RGBAColour>,
+    positions: Option<Vec<f32>>, // The position of each colour, evenly spaced if None
     min: f32,
     max: f32,
+    interp_mode: InterpMode,
+    nan_colour: RGBAColour,
+    center: Option<f32>,
+    high_precision: bool,
 }
 
 impl ColourGradient {
     pub fn new() -> Self {
         Self {
             colours: vec![],
+            positions: None,
             min: 0.0,
             max: 1.0,
+            interp_mode: InterpMode::Srgb,
+            nan_colour: RGBAColour::new(0, 0, 0, 0),
+            center: None,
+            high_precision: false,
         }
     }
 
@@ -64,6 +158,7 @@ impl ColourGradient {
             ColourTheme::Rainbow => Self::rainbow_theme(),
             ColourTheme::BlackWhite => Self::black_white_theme(),
             ColourTheme::WhiteBlack => Self::white_black_theme(),
+            ColourTheme::Diverging => Self::diverging_theme(),
         }
     }
 
@@ -115,49 +210,253 @@ impl ColourGradient {
         result
     }
 
+    ///
+    /// A gradient for data that is either side of zero, such as a
+    /// [difference](crate::Spectrogram::difference) of two spectrograms.  The
+    /// center of the gradient is white, and it is centered on 0.0, see
+    /// [ColourGradient::set_center].
+    ///
+    pub fn diverging_theme() -> Self {
+        let mut result = ColourGradient::new();
+        result.add_colour(RGBAColour::new(5, 48, 97, 255)); // Dark blue
+        result.add_colour(RGBAColour::new(67, 147, 195, 255)); // Blue
+        result.add_colour(RGBAColour::new(255, 255, 255, 255)); // White
+        result.add_colour(RGBAColour::new(214, 96, 77, 255)); // Red
+        result.add_colour(RGBAColour::new(103, 0, 31, 255)); // Dark red
+        result.set_center(0.0);
+        result
+    }
+
     pub fn get_colour(&self, value: f32) -> RGBAColour {
+        self.get_colour_in_range(value, self.min, self.max)
+    }
+
+    ///
+    /// The same as [ColourGradient::get_colour], but for values from `min`
+    /// to `max` rather than the range set with [ColourGradient::set_min] and
+    /// [ColourGradient::set_max].  As the gradient isn't changed, one
+    /// gradient can be shared to render images with different ranges, e.g.
+    /// from several threads.
+    ///
+    pub fn get_colour_in_range(&self, value: f32, min: f32, max: f32) -> RGBAColour {
+        let [r, g, b, a] = self
+            .get_colour_f32(value, min, max)
+            .map(|c| c.round() as u8);
+        RGBAColour { r, g, b, a }
+    }
+
+    ///
+    /// The same as [ColourGradient::get_colour], but with 16 bits for each
+    /// channel.  The colour is interpolated at a higher precision, rather
+    /// than scaling the 8-bit colour.
+    ///
+    /// # Returns
+    ///
+    /// The red, green, blue and alpha channels.
+    ///
+    pub fn get_colour16(&self, value: f32) -> [u16; 4] {
+        self.get_colour16_in_range(value, self.min, self.max)
+    }
+
+    ///
+    /// The same as [ColourGradient::get_colour16], but for values from `min`
+    /// to `max`, see [ColourGradient::get_colour_in_range].
+    ///
+    pub fn get_colour16_in_range(&self, value: f32, min: f32, max: f32) -> [u16; 4] {
+        // 65535 / 255 = 257
+        self.get_colour_f32(value, min, max)
+            .map(|c| (c * 257.0).round().clamp(0.0, 65535.0) as u16)
+    }
+
+    ///
+    /// Get the colour of the value, for values from `min` to `max`.  Each
+    /// channel is in the range 0.0 to 255.0, but not rounded.
+    ///
+    fn get_colour_f32(&self, value: f32, min: f32, max: f32) -> [f32; 4] {
         let len = self.colours.len();
         assert!(len > 1);
-        assert!(self.max >= self.min);
+        assert!(max >= min);
 
-        if value >= self.max {
-            return self.colours.last().unwrap().clone();
+        let to_f32 = |c: &RGBAColour| [c.r, c.g, c.b, c.a].map(f32::from);
+
+        if !value.is_finite() {
+            return to_f32(&self.nan_colour);
+        }
+        if max - min <= f32::EPSILON * f32::max(max.abs(), 1.0) {
+            // There is no range to spread the colours over, e.g. silence
+            return to_f32(self.colours.first().unwrap());
         }
-        if value <= self.min {
-            return self.colours.first().unwrap().clone();
+        if value >= max {
+            return to_f32(self.colours.last().unwrap());
+        }
+        if value <= min {
+            return to_f32(self.colours.first().unwrap());
         }
 
-        // Get the scaled values and indexes to lookup the colour
-        let m = ((len - 1) as f32) / (self.max - self.min); // TODO: Precalc this value
-        let scaled_value = (value - self.min) * m;
-        let idx_value = scaled_value.floor() as usize;
-        let ratio = scaled_value - idx_value as f32;
-        let (i, j) = (idx_value, idx_value + 1);
+        // Get the indexes and ratio to lookup the colour
+        let (i, ratio) = self.segment(self.position(value, min, max));
+        let j = i + 1;
 
         // Prevent over indexing after index computation
         if j >= self.colours.len() {
-            return self.colours.last().unwrap().clone();
+            return to_f32(self.colours.last().unwrap());
         }
 
         // Get the colour band
-        let first = self.colours[i].clone();
-        let second = self.colours[j].clone();
+        let first = &self.colours[i];
+        let second = &self.colours[j];
+        let alpha = self.interpolate(first.a, second.a, ratio);
+
+        match self.interp_mode {
+            InterpMode::Srgb => [
+                self.interpolate(first.r, second.r, ratio),
+                self.interpolate(first.g, second.g, ratio),
+                self.interpolate(first.b, second.b, ratio),
+                alpha,
+            ],
+            InterpMode::Linear => {
+                let lerp = |start: u8, finish: u8| {
+                    let (start, finish) = (srgb_to_linear(start), srgb_to_linear(finish));
+                    linear_to_srgb((finish - start) * ratio + start)
+                };
+                [
+                    lerp(first.r, second.r),
+                    lerp(first.g, second.g),
+                    lerp(first.b, second.b),
+                    alpha,
+                ]
+            }
+            InterpMode::Hsl => {
+                let (h1, s1, l1) = rgb_to_hsl(first);
+                let (h2, s2, l2) = rgb_to_hsl(second);
+
+                // Take the shorter direction around the colour wheel
+                let mut dh = h2 - h1;
+                if dh > 180.0 {
+                    dh -= 360.0;
+                } else if dh < -180.0 {
+                    dh += 360.0;
+                }
+                let h = (h1 + dh * ratio).rem_euclid(360.0);
+                let s = (s2 - s1) * ratio + s1;
+                let l = (l2 - l1) * ratio + l1;
+
+                let (r, g, b) = hsl_to_rgb(h, s, l);
+                [r, g, b, alpha]
+            }
+        }
+    }
+
+    ///
+    /// Find the pair of colours either side of `pos`, returns the index of
+    /// the first colour and how far `pos` is towards the second.
+    ///
+    fn segment(&self, pos: f32) -> (usize, f32) {
+        match &self.positions {
+            Some(positions) => {
+                // The last colour at or before `pos`, so duplicates step
+                let i = positions
+                    .partition_point(|&p| p <= pos)
+                    .clamp(1, positions.len() - 1)
+                    - 1;
+                let (p1, p2) = (positions[i], positions[i + 1]);
+                let ratio = if p2 > p1 {
+                    ((pos - p1) / (p2 - p1)).clamp(0.0, 1.0)
+                } else {
+                    1.0
+                };
+                (i, ratio)
+            }
+            None => {
+                let scaled_value = pos * (self.colours.len() - 1) as f32;
+                let idx_value = scaled_value.floor() as usize;
+                (idx_value, scaled_value - idx_value as f32)
+            }
+        }
+    }
 
-        RGBAColour {
-            r: self.interpolate(first.r, second.r, ratio),
-            g: self.interpolate(first.g, second.g, ratio),
-            b: self.interpolate(first.b, second.b, ratio),
-            a: self.interpolate(first.a, second.a, ratio),
+    ///
+    /// The position of `value` along the gradient, from 0.0 at `min` to 1.0
+    /// at `max`.  With a center value, the center is at 0.5 and each side is
+    /// scaled separately.
+    ///
+    fn position(&self, value: f32, min: f32, max: f32) -> f32 {
+        match self.center {
+            Some(center) if center > min && center < max => {
+                if value < center {
+                    0.5 * (value - min) / (center - min)
+                } else {
+                    0.5 + 0.5 * (value - center) / (max - center)
+                }
+            }
+            _ => (value - min) / (max - min),
         }
     }
 
+    ///
+    /// Sample the gradient at `size` evenly spaced values from `min` to
+    /// `max`, inclusive.  The lookup table can be indexed with
+    /// `(value - min) / (max - min) * (size - 1)`, e.g. as a texture on the
+    /// GPU, which is much faster than [ColourGradient::get_colour] for every
+    /// pixel.
+    ///
+    /// # Arguments
+    ///
+    ///  * `size` - The number of entries in the lookup table.
+    ///
+    pub fn to_lut(&self, size: usize) -> Vec<RGBAColour> {
+        self.to_lut_in_range(size, self.min, self.max)
+    }
+
+    ///
+    /// The same as [ColourGradient::to_lut], but for values from `min` to
+    /// `max`, see [ColourGradient::get_colour_in_range].
+    ///
+    pub fn to_lut_in_range(&self, size: usize, min: f32, max: f32) -> Vec<RGBAColour> {
+        let step = (max - min) / (size.max(2) - 1) as f32;
+        (0..size)
+            .map(|i| self.get_colour_in_range(min + step * i as f32, min, max))
+            .collect()
+    }
+
+    ///
+    /// A legend for the range set with [ColourGradient::set_min] and
+    /// [ColourGradient::set_max], see [ColourGradient::to_legend_in_range].
+    ///
+    /// Rendering a spectrogram no longer sets the range of the gradient, so
+    /// after rendering this is not the range of the image.
+    ///
+    #[deprecated(
+        since = "0.8.0",
+        note = "use `to_legend_in_range` with the range of the rendered buffer, \
+                e.g. from `get_finite_min_max`"
+    )]
     pub fn to_legend(&self, width: usize, height: usize) -> Vec<RGBAColour> {
+        self.to_legend_in_range(width, height, self.min, self.max)
+    }
+
+    ///
+    /// Create a legend, `width` by `height` pixels, with the colour of `max`
+    /// in the top row and `min` in the bottom row, see
+    /// [ColourGradient::get_colour_in_range].
+    ///
+    pub fn to_legend_in_range(
+        &self,
+        width: usize,
+        height: usize,
+        min: f32,
+        max: f32,
+    ) -> Vec<RGBAColour> {
         let mut result = vec![RGBAColour::new(0, 0, 0, 0); width * height];
-        let step = -(self.max - self.min) / (height as f32 - 1.0);
-        let mut val = self.max;
+        let step = if height > 1 {
+            -(max - min) / (height as f32 - 1.0)
+        } else {
+            0.0
+        };
+        let mut val = max;
         let mut i = 0;
         for _ in 0..height {
-            let col = self.get_colour(val);
+            let col = self.get_colour_in_range(val, min, max);
             val += step;
             for _ in 0..width {
                 result[i] = col.clone();
@@ -168,11 +467,112 @@ impl ColourGradient {
     }
 
     pub fn add_colour(&mut self, colour: RGBAColour) {
-        self.colours.push(colour);
+        match self.positions {
+            Some(_) => self.add_colour_at_position(1.0, colour),
+            None => self.colours.push(colour),
+        }
+    }
+
+    ///
+    /// Write the colour stops as Rust source, e.g. to paste a gradient that
+    /// was tweaked at runtime into your code.  Each stop is one line that
+    /// calls [ColourGradient::add_colour], or
+    /// [ColourGradient::add_colour_at_position] if the stops were positioned,
+    /// on a variable called `gradient`.  The center is included if it is set.
+    ///
+    pub fn to_rust_source(&self) -> String {
+        let mut source = String::new();
+        for (i, c) in self.colours.iter().enumerate() {
+            let colour = format!("RGBAColour::new({}, {}, {}, {})", c.r, c.g, c.b, c.a);
+            match &self.positions {
+                Some(positions) => source.push_str(&format!(
+                    "gradient.add_colour_at_position({:?}, {});\n",
+                    positions[i], colour
+                )),
+                None => source.push_str(&format!("gradient.add_colour({});\n", colour)),
+            }
+        }
+        if let Some(center) = self.center {
+            source.push_str(&format!("gradient.set_center({:?});\n", center));
+        }
+        source
+    }
+
+    ///
+    /// Add a colour at a position along the gradient, from 0.0 at the min to
+    /// 1.0 at the max, rather than spacing the colours evenly.  For example
+    /// stops at 0.0, 0.8 and 1.0 put most of the detail in the top 20%.  The
+    /// colours are kept in order of position.  If two colours share a
+    /// position the gradient steps from one to the other.  Colours that were
+    /// added with [ColourGradient::add_colour] keep their even spacing, any
+    /// added later go at 1.0.
+    ///
+    /// # Arguments
+    ///
+    ///  * `pos` - The position, clamped to 0.0 to 1.0.
+    ///  * `colour` - The colour at that position.
+    ///
+    pub fn add_colour_at_position(&mut self, pos: f32, colour: RGBAColour) {
+        let len = self.colours.len();
+        let positions = self.positions.get_or_insert_with(|| {
+            (0..len)
+                .map(|i| i as f32 / (len.max(2) - 1) as f32)
+                .collect()
+        });
+
+        let pos = pos.clamp(0.0, 1.0);
+        let i = positions.partition_point(|&p| p <= pos);
+        positions.insert(i, pos);
+        self.colours.insert(i, colour);
+    }
+
+    fn interpolate(&self, start: u8, finish: u8, ratio: f32) -> f32 {
+        (f32::from(finish) - f32::from(start)) * ratio + f32::from(start)
+    }
+
+    ///
+    /// Set how colours are blended between the gradient stops.  The default
+    /// is [InterpMode::Srgb].
+    ///
+    pub fn set_interpolation(&mut self, mode: InterpMode) {
+        self.interp_mode = mode;
+    }
+
+    ///
+    /// Set the colour used for NaN and infinite values, so numerical problems
+    /// are visible in the image.  By default this is transparent.
+    ///
+    pub fn set_nan_colour(&mut self, colour: RGBAColour) {
+        self.nan_colour = colour;
     }
 
-    fn interpolate(&self, start: u8, finish: u8, ratio: f32) -> u8 {
-        ((f32::from(finish) - f32::from(start)) * ratio + f32::from(start)).round() as u8
+    ///
+    /// Set the value at the center of the gradient, for example 0.0 so the
+    /// neutral colour of a diverging gradient is on zero, rather than the
+    /// middle of the min and max.  The values either side of the center are
+    /// scaled separately.  The center is ignored if it's not between the min
+    /// and max.
+    ///
+    pub fn set_center(&mut self, center: f32) {
+        self.center = Some(center);
+    }
+
+    ///
+    /// Interpolate the exact colour of every pixel when rendering an image,
+    /// rather than looking it up in a table, see [ColourGradient::to_lut].
+    /// The table is accurate to within one step of the 8-bit colours for
+    /// smooth gradients, so this is rarely needed.
+    ///
+    pub fn set_high_precision(&mut self, high_precision: bool) {
+        self.high_precision = high_precision;
+    }
+
+    ///
+    /// True if images are rendered with the exact colour of every pixel,
+    /// see [ColourGradient::set_high_precision].
+    ///
+    pub fn is_high_precision(&self) -> bool {
+        self.high_precision
     }
 
     pub fn set_max(&mut self, max: f32) {
@@ -184,6 +584,74 @@ impl ColourGradient {
     }
 }
 
+/// Convert an 8-bit sRGB channel to linear light in the range 0.0 to 1.0.
+fn srgb_to_linear(c: u8) -> f32 {
+    let c = f32::from(c) / 255.0;
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Convert a linear light value in the range 0.0 to 1.0 to an sRGB channel
+/// in the range 0.0 to 255.0.
+fn linear_to_srgb(c: f32) -> f32 {
+    let c = if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    };
+    (c * 255.0).clamp(0.0, 255.0)
+}
+
+/// Convert a colour to hue (degrees), saturation and lightness.
+fn rgb_to_hsl(colour: &RGBAColour) -> (f32, f32, f32) {
+    let r = f32::from(colour.r) / 255.0;
+    let g = f32::from(colour.g) / 255.0;
+    let b = f32::from(colour.b) / 255.0;
+
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let l = (max + min) / 2.0;
+    let delta = max - min;
+    if delta == 0.0 {
+        // Grey, the hue is undefined
+        return (0.0, 0.0, l);
+    }
+
+    let s = delta / (1.0 - (2.0 * l - 1.0).abs());
+    let h = if max == r {
+        60.0 * ((g - b) / delta).rem_euclid(6.0)
+    } else if max == g {
+        60.0 * ((b - r) / delta + 2.0)
+    } else {
+        60.0 * ((r - g) / delta + 4.0)
+    };
+
+    (h, s, l)
+}
+
+/// Convert hue (degrees), saturation and lightness to RGB, each in the range
+/// 0.0 to 255.0.
+fn hsl_to_rgb(h: f32, s: f32, l: f32) -> (f32, f32, f32) {
+    let c = (1.0 - (2.0 * l - 1.0).abs()) * s;
+    let x = c * (1.0 - ((h / 60.0).rem_euclid(2.0) - 1.0).abs());
+    let m = l - c / 2.0;
+
+    let (r, g, b) = match (h / 60.0) as u32 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+
+    let scale = |v: f32| ((v + m) * 255.0).clamp(0.0, 255.0);
+    (scale(r), scale(g), scale(b))
+}
+
 impl Default for ColourGradient {
     fn default() -> Self {
         Self::new()
@@ -233,6 +701,83 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_interpolation() {
+        let mut gradient = ColourGradient::new();
+        gradient.add_colour(RGBAColour::new(255, 0, 0, 255));
+        gradient.add_colour(RGBAColour::new(0, 255, 0, 255));
+
+        assert_eq!(gradient.get_colour(0.5), RGBAColour::new(128, 128, 0, 255));
+
+        // Linear light gives a brighter midpoint
+        gradient.set_interpolation(InterpMode::Linear);
+        assert_eq!(gradient.get_colour(0.5), RGBAColour::new(188, 188, 0, 255));
+        assert_eq!(gradient.get_colour(0.0), RGBAColour::new(255, 0, 0, 255));
+        assert_eq!(gradient.get_colour(1.0), RGBAColour::new(0, 255, 0, 255));
+    }
+
+    #[test]
+    fn test_hsl_interpolation() {
+        let mut gradient = ColourGradient::new();
+        gradient.add_colour(RGBAColour::new(255, 0, 0, 255)); // Red
+        gradient.add_colour(RGBAColour::new(0, 0, 255, 255)); // Blue
+        gradient.set_interpolation(InterpMode::Hsl);
+
+        // Red is 0 degrees and blue is 240 degrees, the shorter way is through magenta
+        assert_eq!(gradient.get_colour(0.5), RGBAColour::new(255, 0, 255, 255));
+        assert_eq!(gradient.get_colour(0.25), RGBAColour::new(255, 0, 128, 255));
+        assert_eq!(gradient.get_colour(0.0), RGBAColour::new(255, 0, 0, 255));
+        assert_eq!(gradient.get_colour(1.0), RGBAColour::new(0, 0, 255, 255));
+
+        // Round trip through HSL
+        let colour = RGBAColour::new(12, 200, 99, 255);
+        let (h, s, l) = rgb_to_hsl(&colour);
+        let (r, g, b) = hsl_to_rgb(h, s, l);
+        assert_eq!((r.round(), g.round(), b.round()), (12.0, 200.0, 99.0));
+    }
+
+    #[test]
+    fn test_nan_colour() {
+        let mut gradient = ColourGradient::black_white_theme();
+        assert_eq!(gradient.get_colour(f32::NAN), RGBAColour::new(0, 0, 0, 0));
+
+        let magenta = RGBAColour::new(255, 0, 255, 255);
+        gradient.set_nan_colour(magenta.clone());
+        assert_eq!(gradient.get_colour(f32::NAN), magenta);
+        assert_eq!(gradient.get_colour(f32::INFINITY), magenta);
+        assert_eq!(gradient.get_colour(f32::NEG_INFINITY), magenta);
+        assert_eq!(
+            gradient.get_colour(1.0),
+            RGBAColour::new(255, 255, 255, 255)
+        );
+    }
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(
+            RGBAColour::from_hex("#3b0764").unwrap(),
+            RGBAColour::new(0x3b, 0x07, 0x64, 255)
+        );
+        assert_eq!(
+            RGBAColour::from_hex("#f0a").unwrap(),
+            RGBAColour::new(255, 0, 170, 255)
+        );
+        assert_eq!(
+            RGBAColour::from_hex("#11223380").unwrap(),
+            RGBAColour::new(0x11, 0x22, 0x33, 0x80)
+        );
+
+        // Round trip
+        let colour = RGBAColour::new(1, 20, 200, 128);
+        assert_eq!(RGBAColour::from_hex(&colour.to_hex()).unwrap(), colour);
+        assert_eq!(colour.to_hex(), "#0114c880");
+
+        // Malformed
+        for s in ["3b0764", "#3b076", "#3b07645", "#gg0000", "#", "#+1+2+3"] {
+            assert!(RGBAColour::from_hex(s).is_err());
+        }
+    }
+
     #[test]
     fn test_min_max() {
         let mut gradient = ColourGradient::new();
@@ -251,4 +796,153 @@ mod tests {
             RGBAColour::new(128, 128, 128, 255)
         );
     }
+
+    #[test]
+    fn test_diverging_center() {
+        let mut gradient = ColourGradient::create(ColourTheme::Diverging);
+        let white = RGBAColour::new(255, 255, 255, 255);
+
+        // Zero is white, even though it's not the middle of min and max
+        gradient.set_min(-2.0);
+        gradient.set_max(10.0);
+        assert_eq!(gradient.get_colour(0.0), white);
+        assert_eq!(gradient.get_colour(-2.0), RGBAColour::new(5, 48, 97, 255));
+        assert_eq!(gradient.get_colour(10.0), RGBAColour::new(103, 0, 31, 255));
+        assert_eq!(
+            gradient.get_colour(-1.0),
+            RGBAColour::new(67, 147, 195, 255)
+        );
+        assert_eq!(gradient.get_colour(5.0), RGBAColour::new(214, 96, 77, 255));
+
+        // A center outside of min and max is ignored
+        gradient.set_min(1.0);
+        gradient.set_max(3.0);
+        assert_eq!(gradient.get_colour(2.0), white);
+    }
+
+    #[test]
+    fn test_get_colour16() {
+        let mut gradient = ColourGradient::new();
+        gradient.add_colour(RGBAColour::new(0, 0, 0, 255));
+        gradient.add_colour(RGBAColour::new(255, 255, 255, 255));
+
+        assert_eq!(gradient.get_colour16(0.0), [0, 0, 0, 65535]);
+        assert_eq!(gradient.get_colour16(1.0), [65535; 4]);
+
+        // More precise than the 8-bit colour
+        assert_eq!(gradient.get_colour16(0.5), [32768, 32768, 32768, 65535]);
+        assert_eq!(gradient.get_colour16(0.001), [66, 66, 66, 65535]);
+        assert_eq!(gradient.get_colour(0.001), RGBAColour::new(0, 0, 0, 255));
+    }
+
+    #[test]
+    fn test_to_lut() {
+        let mut gradient = ColourGradient::create(ColourTheme::Default);
+        gradient.set_min(-80.0);
+        gradient.set_max(0.0);
+
+        let lut = gradient.to_lut(256);
+        assert_eq!(lut.len(), 256);
+        assert_eq!(lut[0], gradient.get_colour(-80.0));
+        assert_eq!(lut[255], gradient.get_colour(0.0));
+        assert_eq!(lut[0], gradient.colours[0]);
+        assert_eq!(lut[255], *gradient.colours.last().unwrap());
+
+        assert!(gradient.to_lut(0).is_empty());
+        assert_eq!(gradient.to_lut(1), vec![gradient.get_colour(-80.0)]);
+    }
+
+    #[test]
+    fn test_add_colour_at_position() {
+        let mut gradient = ColourGradient::new();
+        let (black, grey, white) = (
+            RGBAColour::new(0, 0, 0, 255),
+            RGBAColour::new(100, 100, 100, 255),
+            RGBAColour::new(255, 255, 255, 255),
+        );
+
+        // Out of order, the stops are sorted
+        gradient.add_colour_at_position(1.0, white.clone());
+        gradient.add_colour_at_position(0.0, black.clone());
+        gradient.add_colour_at_position(0.2, grey.clone());
+
+        assert_eq!(gradient.get_colour(0.0), black);
+        assert_eq!(gradient.get_colour(0.1), RGBAColour::new(50, 50, 50, 255));
+        assert_eq!(gradient.get_colour(0.2), grey);
+        assert_eq!(
+            gradient.get_colour(0.6),
+            RGBAColour::new(178, 178, 178, 255)
+        );
+        assert_eq!(gradient.get_colour(1.0), white);
+
+        // A duplicate position is a hard step
+        let red = RGBAColour::new(255, 0, 0, 255);
+        gradient.add_colour_at_position(0.2, red.clone());
+        assert_eq!(gradient.get_colour(0.2), red);
+        assert_eq!(
+            gradient.get_colour(0.1999),
+            RGBAColour::new(100, 100, 100, 255)
+        );
+
+        // Evenly spaced colours keep their positions
+        let mut gradient = ColourGradient::black_white_theme();
+        gradient.add_colour_at_position(0.75, grey.clone());
+        assert_eq!(gradient.get_colour(0.75), grey);
+        assert_eq!(gradient.get_colour(0.375), RGBAColour::new(50, 50, 50, 255));
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_min_equals_max() {
+        let mut gradient = ColourGradient::create(ColourTheme::Default);
+        gradient.set_min(-80.0);
+        gradient.set_max(-80.0);
+
+        let first = gradient.colours[0].clone();
+        for val in [-100.0, -80.0, -79.99999, 0.0] {
+            assert_eq!(gradient.get_colour(val), first);
+        }
+        assert!(gradient.to_legend(2, 5).iter().all(|c| *c == first));
+
+        // A single row legend is the max colour
+        gradient.set_min(0.0);
+        gradient.set_max(1.0);
+        assert_eq!(gradient.to_legend(1, 1), vec![gradient.get_colour(1.0)]);
+    }
+
+    #[test]
+    fn test_to_rust_source() {
+        assert_eq!(
+            ColourGradient::default_theme().to_rust_source(),
+            "gradient.add_colour(RGBAColour::new(0, 0, 0, 255));\n\
+             gradient.add_colour(RGBAColour::new(55, 0, 110, 255));\n\
+             gradient.add_colour(RGBAColour::new(0, 0, 180, 255));\n\
+             gradient.add_colour(RGBAColour::new(0, 255, 255, 255));\n\
+             gradient.add_colour(RGBAColour::new(0, 255, 0, 255));\n"
+        );
+
+        let mut gradient = ColourGradient::new();
+        gradient.add_colour_at_position(0.2, RGBAColour::new(1, 2, 3, 4));
+        gradient.set_center(0.0);
+        assert_eq!(
+            gradient.to_rust_source(),
+            "gradient.add_colour_at_position(0.2, RGBAColour::new(1, 2, 3, 4));\n\
+             gradient.set_center(0.0);\n"
+        );
+    }
+
+    #[test]
+    fn test_blend_over() {
+        let red = RGBAColour::new(255, 0, 0, 255).with_alpha(128);
+        assert_eq!(red, RGBAColour::new(255, 0, 0, 128));
+
+        // Half transparent red over black is dark red
+        let black = RGBAColour::new(0, 0, 0, 255);
+        assert_eq!(red.blend_over(&black), RGBAColour::new(128, 0, 0, 255));
+
+        // Over a transparent background the colour is unchanged
+        let clear = RGBAColour::new(0, 0, 0, 0);
+        assert_eq!(red.blend_over(&clear), red);
+        assert_eq!(clear.blend_over(&clear), clear);
+    }
 }
@@ -4,14 +4,30 @@ use std::io;
 #[derive(Debug)]
 pub enum SonogramError {
     Io(io::Error),
+    Resize(resize::Error),
     #[cfg(feature = "hound")]
     Hound(hound::Error),
+    #[cfg(feature = "vorbis")]
+    Vorbis(lewton::VorbisError),
+    #[cfg(feature = "mp3")]
+    Mp3(minimp3::Error),
 
     // Our own errors
     InvalidCodec,
     InvalidChannel,
     InvalidDivisor,
     IncompleteData,
+    InvalidColour,
+    InvalidBufferSize,
+    InvalidFrameLength,
+    InvalidFrequency,
+    Cancelled,
+    MismatchedSpectrograms,
+    InvalidStepSize,
+    InvalidImageSize,
+    SilentInput,
+    InvalidNumBins,
+    InvalidWindowLength,
 }
 
 impl From<io::Error> for SonogramError {
@@ -20,9 +36,35 @@ impl From<io::Error> for SonogramError {
     }
 }
 
+impl From<resize::Error> for SonogramError {
+    fn from(err: resize::Error) -> SonogramError {
+        SonogramError::Resize(err)
+    }
+}
+
+impl From<csv::Error> for SonogramError {
+    fn from(err: csv::Error) -> SonogramError {
+        SonogramError::Io(err.into())
+    }
+}
+
 #[cfg(feature = "hound")]
 impl From<hound::Error> for SonogramError {
     fn from(err: hound::Error) -> SonogramError {
         SonogramError::Hound(err)
     }
 }
+
+#[cfg(feature = "vorbis")]
+impl From<lewton::VorbisError> for SonogramError {
+    fn from(err: lewton::VorbisError) -> SonogramError {
+        SonogramError::Vorbis(err)
+    }
+}
+
+#[cfg(feature = "mp3")]
+impl From<minimp3::Error> for SonogramError {
+    fn from(err: minimp3::Error) -> SonogramError {
+        SonogramError::Mp3(err)
+    }
+}
@@ -63,6 +63,10 @@ pub trait FreqScalerTrait {
 
     /// The y->(f1,f2) scaler function
     fn scale(&self, y: usize) -> (f32, f32);
+
+    /// The continuous y->f scaler function, `scale(y)` is the same as
+    /// `(position(y), position(y + 1))`.
+    fn position(&self, y: f32) -> f32;
 }
 
 /// Scale the frequncy linearly.
@@ -100,6 +104,10 @@ impl FreqScalerTrait for LinearFreq {
         let f2 = self.ratio * ((y + 1) as f32);
         (f1, f2)
     }
+
+    fn position(&self, y: f32) -> f32 {
+        self.ratio * y
+    }
 }
 
 ///
@@ -141,4 +149,8 @@ impl FreqScalerTrait for LogFreq {
         let f2 = self.log_coef * ((y + 1) as f32).ln();
         (f1, f2)
     }
+
+    fn position(&self, y: f32) -> f32 {
+        self.log_coef * y.ln()
+    }
 }
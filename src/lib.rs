@@ -19,24 +19,37 @@ extern crate csv;
 #[cfg(feature = "png")]
 extern crate png;
 
+mod analysis;
+#[cfg(feature = "png")]
+mod batch;
 mod builder;
 mod colour_gradient;
 mod errors;
 mod freq_scales;
+mod mel;
+mod overlay;
 mod spec_core;
 mod window_fn;
 
+#[cfg(feature = "png")]
+pub use batch::{batch_process, BatchResult};
 pub use builder::SpecOptionsBuilder;
-pub use colour_gradient::{ColourGradient, ColourTheme, RGBAColour};
+pub use colour_gradient::{ColourGradient, ColourTheme, InterpMode, RGBAColour};
 pub use errors::SonogramError;
 pub use freq_scales::{FreqScaler, FrequencyScale};
-pub use spec_core::SpecCompute;
+pub use mel::{hz_to_mel, mel_filterbank, mel_to_hz};
+pub use spec_core::{
+    CrossSpectrum, DbConvention, FftNormalisation, MultiSpecCompute, PaddingMode, SpecCompute,
+};
 pub use window_fn::*;
 
+pub use rustfft::{num_complex::Complex, Fft, FftPlanner};
+
 #[cfg(feature = "png")]
 use std::fs::File;
 #[cfg(feature = "png")]
 use std::io::BufWriter;
+use std::io::Write;
 use std::path::Path;
 
 use resize::Pixel::GrayF32;
@@ -46,13 +59,325 @@ use rgb::FromSlice;
 #[cfg(feature = "png")]
 use png::HasParameters; // To use encoder.set()
 
+/// The largest output image, in pixels, anything bigger is most likely a bug
+/// in the caller and would exhaust the memory.
+const MAX_IMAGE_PIXELS: usize = 1 << 28;
+
+/// The smallest amplitude converted to dBFS, i.e. the -200 dB floor.
+const MIN_DBFS_AMPLITUDE: f32 = 1e-10;
+
+/// The number of entries in the colour lookup table used to render images.
+const LUT_SIZE: usize = 1024;
+
+/// The number of pixels each task colours when rendering in parallel.
+#[cfg(feature = "rayon")]
+const PIXELS_PER_TASK: usize = 4096;
+
+///
+/// The value domain of exported data, see [Spectrogram::to_csv].  The default
+/// is [AmplitudeScale::Db], the same values that are rendered to images.
+///
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AmplitudeScale {
+    /// The magnitude of each bin
+    Linear,
+    /// 20 * log10 of the magnitude, relative to the peak
+    #[default]
+    Db,
+    /// The magnitude squared
+    Power,
+}
+
+///
+/// How the spectrogram is resized to the output size, see
+/// [Spectrogram::to_buffer_with_filter].  The default is
+/// [ResizeFilter::Lanczos3].
+///
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ResizeFilter {
+    /// Lanczos3 interpolation, a smooth image
+    #[default]
+    Lanczos3,
+    /// Each output pixel takes the bin under its centre.  When the output is
+    /// larger than the spectrogram each bin is shown as a solid block, so the
+    /// image doesn't suggest a finer resolution than the data has.
+    NearestNeighbour,
+}
+
+///
+/// The point in each window that a column's time refers to, see
+/// [Spectrogram::column_to_time].  The default is [TimeReference::Center].
+///
+/// Versions up to 0.7 used the start of the window, and every time reported
+/// or taken by a [Spectrogram] is now `window_len / 2` samples later.  Call
+/// [Spectrogram::set_time_reference] with [TimeReference::Start] to keep the
+/// old times.
+///
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TimeReference {
+    /// The first sample of the window
+    Start,
+    /// The middle of the window
+    #[default]
+    Center,
+    /// Just after the last sample of the window
+    End,
+}
+
+///
+/// The unit of the time axis, e.g. the header of [Spectrogram::to_csv_with_time].
+/// The default is [TimeUnit::Seconds].
+///
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TimeUnit {
+    /// Seconds from the first sample of the data
+    #[default]
+    Seconds,
+    /// The sample index, at the sample rate of the spectrogram, i.e. after
+    /// any downsampling
+    Samples,
+    /// The column (window) index of the spectrogram
+    Frames,
+}
+
 pub struct Spectrogram {
     spec: Vec<f32>,
     width: usize,
     height: usize,
+    sample_rate: u32,
+    step_size: usize,              // The number of samples between each column
+    is_db: bool,                   // The values in `spec` have already been converted to dB
+    two_sided: bool,               // Negative frequencies are included, DC is in the centre
+    window_len: usize,             // The number of samples in each window
+    window_padding: usize,         // The number of samples padded before the data
+    time_reference: TimeReference, // The point in each window that a column's time refers to
 }
 
 impl Spectrogram {
+    ///
+    /// Iterate over the rows of the spectrogram, each row is `width` values
+    /// long.  The first row is the highest frequency.  The values are linear
+    /// magnitudes, unless the spectrogram was computed with the dB output
+    /// option, see [Spectrogram::is_db].  Use [Spectrogram::row_iter_db] for
+    /// the values shown in the image.
+    ///
+    pub fn row_iter(&self) -> impl Iterator<Item = &[f32]> + '_ {
+        self.spec.chunks(self.width.max(1))
+    }
+
+    ///
+    /// The same as [Spectrogram::row_iter], but the values are in dB relative
+    /// to the peak and floored at -80 dB, the same values that are rendered by
+    /// [Spectrogram::to_png] before the image is resized.
+    ///
+    pub fn row_iter_db(&self) -> impl Iterator<Item = Vec<f32>> {
+        let buf = self.to_amplitude_buffer(FrequencyScale::Linear, AmplitudeScale::Db);
+        self.buf_rows(buf)
+    }
+
+    ///
+    /// The same as [Spectrogram::row_iter], but the values are the power,
+    /// i.e. the magnitude squared.
+    ///
+    pub fn row_iter_power(&self) -> impl Iterator<Item = Vec<f32>> {
+        let buf = self.to_amplitude_buffer(FrequencyScale::Linear, AmplitudeScale::Power);
+        self.buf_rows(buf)
+    }
+
+    /// Split a buffer the size of the spectrogram into its rows.
+    fn buf_rows(&self, buf: Vec<f32>) -> impl Iterator<Item = Vec<f32>> {
+        let width = self.width.max(1);
+        (0..self.height).map(move |h| buf[h * width..(h + 1) * width].to_vec())
+    }
+
+    ///
+    /// Get the spectrogram as a flat slice, row by row.  The first `width`
+    /// values are the first row, which is the highest frequency.
+    ///
+    pub fn raw_data(&self) -> &[f32] {
+        &self.spec
+    }
+
+    ///
+    /// Copy the spectrogram into a vector of rows, indexed `[row][column]`.
+    /// Row 0 is the top of the image, which is the highest frequency, and the
+    /// last row is the lowest frequency.
+    ///
+    pub fn to_rows(&self) -> Vec<Vec<f32>> {
+        self.row_iter().map(|row| row.to_vec()).collect()
+    }
+
+    ///
+    /// Iterate over every cell of the spectrogram with its physical
+    /// coordinates, in the same order as [Spectrogram::raw_data].  The time
+    /// is the same as [Spectrogram::column_to_time] and the frequency is the
+    /// frequency of the FFT bin.
+    ///
+    /// # Arguments
+    ///
+    ///  * `sample_rate` - The sample rate, in Hz, of the data.
+    ///
+    /// # Returns
+    ///
+    /// `(time_seconds, frequency_hz, magnitude)` for each cell.
+    ///
+    pub fn cells(&self, sample_rate: u32) -> impl Iterator<Item = (f32, f32, f32)> + '_ {
+        let ratio = sample_rate as f32 / self.sample_rate as f32;
+        let width = self.width.max(1);
+        self.spec.iter().enumerate().map(move |(i, &val)| {
+            let (row, column) = (i / width, i % width);
+            let time = self.column_to_time(column);
+            let freq = self.bin_to_freq((self.height - 1 - row) as f32) * ratio;
+            (time, freq, val)
+        })
+    }
+
+    ///
+    /// Get the value at any time and frequency, bilinearly interpolated
+    /// between the four surrounding cells, e.g. to inspect the point under
+    /// the mouse.  The value is as stored, in dB if the spectrogram was
+    /// converted to dB, otherwise the linear magnitude.  The cells are at the
+    /// times given by [Spectrogram::column_to_time] and the frequencies of
+    /// their FFT bins, the same as [Spectrogram::cells].
+    ///
+    /// # Arguments
+    ///
+    ///  * `time_sec` - The time, in seconds.
+    ///  * `freq_hz` - The frequency, in Hz.
+    ///  * `sample_rate` - The sample rate, in Hz, of the data.
+    ///
+    /// # Returns
+    ///
+    /// The value, or `None` if the point is outside the spectrogram.
+    ///
+    pub fn value_at(&self, time_sec: f32, freq_hz: f32, sample_rate: u32) -> Option<f32> {
+        if self.width == 0 || self.height == 0 {
+            return None;
+        }
+
+        let ratio = sample_rate as f32 / self.sample_rate as f32;
+        let x = self.time_to_column(time_sec);
+        let y = self.height as f32 - 1.0 - self.freq_to_bin(freq_hz / ratio);
+        let (x_max, y_max) = ((self.width - 1) as f32, (self.height - 1) as f32);
+        if !(0.0..=x_max).contains(&x) || !(0.0..=y_max).contains(&y) {
+            return None;
+        }
+
+        // The cell above and to the left, and how far to the next cells
+        let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+        let (x1, y1) = ((x0 + 1).min(self.width - 1), (y0 + 1).min(self.height - 1));
+        let (dx, dy) = (x - x0 as f32, y - y0 as f32);
+        let cell = |x: usize, y: usize| self.spec[y * self.width + x];
+
+        let top = cell(x0, y0) * (1.0 - dx) + cell(x1, y0) * dx;
+        let bottom = cell(x0, y1) * (1.0 - dx) + cell(x1, y1) * dx;
+        Some(top * (1.0 - dy) + bottom * dy)
+    }
+
+    ///
+    /// Get the time, in seconds, of the window used for the given column,
+    /// relative to the first sample of the data.  By default this is the
+    /// middle of the window, see [Spectrogram::set_time_reference].  Any
+    /// padding added by [SpecOptionsBuilder::center_windows] is accounted
+    /// for, so with the default the time of column 0 is 0.0 for centered
+    /// windows and `num_bins / 2` samples otherwise.
+    ///
+    pub fn column_to_time(&self, column: usize) -> f32 {
+        self.column_time(column as f32, TimeUnit::Seconds)
+    }
+
+    ///
+    /// Get the time of the given column, which may be fractional, in the
+    /// given unit.  See [Spectrogram::column_to_time].
+    ///
+    fn column_time(&self, column: f32, unit: TimeUnit) -> f32 {
+        let start = column * self.step_size as f32 - self.window_padding as f32;
+        let sample = start + self.time_reference_offset();
+        match unit {
+            TimeUnit::Seconds => sample / self.sample_rate as f32,
+            TimeUnit::Samples => sample,
+            TimeUnit::Frames => column,
+        }
+    }
+
+    ///
+    /// Get the column, which may be fractional, at the given time in seconds.
+    /// This is the inverse of [Spectrogram::column_to_time].
+    ///
+    pub(crate) fn time_to_column(&self, time: f32) -> f32 {
+        let start = time * self.sample_rate as f32 - self.time_reference_offset();
+        (start + self.window_padding as f32) / self.step_size as f32
+    }
+
+    /// The offset, in samples, from the start of a window to its time.
+    fn time_reference_offset(&self) -> f32 {
+        match self.time_reference {
+            TimeReference::Start => 0.0,
+            TimeReference::Center => self.window_len as f32 / 2.0,
+            TimeReference::End => self.window_len as f32,
+        }
+    }
+
+    ///
+    /// Set the point in each window that the time of a column refers to, for
+    /// [Spectrogram::column_to_time], [Spectrogram::cells] and everything
+    /// that reports or takes a time.  The default is
+    /// [TimeReference::Center].
+    ///
+    pub fn set_time_reference(&mut self, time_reference: TimeReference) {
+        self.time_reference = time_reference;
+    }
+
+    ///
+    /// The number of columns (time windows) in the spectrogram, before it is
+    /// resized for output.
+    ///
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    ///
+    /// The number of rows (frequency bins) in the spectrogram, before it is
+    /// resized for output.  This is `num_bins / 2`, or `num_bins` for a
+    /// two-sided spectrogram, see [SpecOptionsBuilder::with_height].
+    ///
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    ///
+    /// True if the stored values are in dB, as rendered by [Spectrogram::to_buffer],
+    /// rather than linear magnitudes.
+    ///
+    pub fn is_db(&self) -> bool {
+        self.is_db
+    }
+
+    ///
+    /// Convert the stored linear magnitudes to dB, using the same conversion
+    /// as the rendered image.  The values are relative to the peak and
+    /// floored at 80 dB below it.  Does nothing if the values are already dB.
+    ///
+    pub fn to_db_inplace(&mut self) {
+        if !self.is_db {
+            to_db(&mut self.spec);
+            self.is_db = true;
+        }
+    }
+
+    ///
+    /// Convert the stored linear magnitudes to dBFS, `20 * log10(scale * x)`,
+    /// floored at -200 dB.  See [DbConvention::Dbfs].
+    ///
+    pub(crate) fn convert_to_dbfs(&mut self, scale: f32) {
+        if !self.is_db {
+            for val in self.spec.iter_mut() {
+                *val = 20.0 * f32::max(MIN_DBFS_AMPLITUDE, *val * scale).log10();
+            }
+            self.is_db = true;
+        }
+    }
+
     ///
     /// Save the calculated spectrogram as a PNG image.
     ///
@@ -66,24 +391,137 @@ impl Spectrogram {
     ///
     #[cfg(feature = "png")]
     pub fn to_png(
-        &mut self,
+        &self,
+        fname: &Path,
+        freq_scale: FrequencyScale,
+        gradient: &ColourGradient,
+        w_img: usize,
+        h_img: usize,
+    ) -> Result<(), SonogramError> {
+        self.to_png_with_filter(
+            fname,
+            freq_scale,
+            gradient,
+            w_img,
+            h_img,
+            ResizeFilter::Lanczos3,
+        )
+    }
+
+    ///
+    /// The same as [Spectrogram::to_png], with the given filter to resize the
+    /// spectrogram to the image size.
+    ///
+    /// # Arguments
+    ///
+    ///  * `fname` - The path to the PNG to save to the filesystem.
+    ///  * `freq_scale` - The type of frequency scale to use for the spectrogram.
+    ///  * `gradient` - The colour gradient to use for the spectrogram.
+    ///  * `w_img` - The output image width.
+    ///  * `h_img` - The output image height.
+    ///  * `filter` - How the spectrogram is resized to the image size.
+    ///
+    #[cfg(feature = "png")]
+    pub fn to_png_with_filter(
+        &self,
+        fname: &Path,
+        freq_scale: FrequencyScale,
+        gradient: &ColourGradient,
+        w_img: usize,
+        h_img: usize,
+        filter: ResizeFilter,
+    ) -> Result<(), SonogramError> {
+        let buf = self.to_buffer_with_filter(freq_scale, w_img, h_img, filter)?;
+
+        let mut img: Vec<u8> = vec![0u8; w_img * h_img * 4];
+        self.buf_to_img(&buf, &mut img, gradient);
+
+        let file = File::create(fname)?;
+        write_png(
+            BufWriter::new(file),
+            &img,
+            w_img,
+            h_img,
+            png::BitDepth::Eight,
+        )?;
+
+        Ok(())
+    }
+
+    ///
+    /// Save the calculated spectrogram as a PNG image, transposed so time runs
+    /// down the vertical axis and frequency runs left to right, see
+    /// [Spectrogram::to_buffer_transposed].
+    ///
+    /// # Arguments
+    ///
+    ///  * `fname` - The path to the PNG to save to the filesystem.
+    ///  * `freq_scale` - The type of frequency scale to use for the spectrogram.
+    ///  * `gradient` - The colour gradient to use for the spectrogram.
+    ///  * `w_img` - The output image width, the frequency axis.
+    ///  * `h_img` - The output image height, the time axis.
+    ///
+    #[cfg(feature = "png")]
+    pub fn to_png_transposed(
+        &self,
         fname: &Path,
         freq_scale: FrequencyScale,
-        gradient: &mut ColourGradient,
+        gradient: &ColourGradient,
         w_img: usize,
         h_img: usize,
-    ) -> Result<(), std::io::Error> {
-        let buf = self.to_buffer(freq_scale, w_img, h_img);
+    ) -> Result<(), SonogramError> {
+        let buf = self.to_buffer_transposed(freq_scale, w_img, h_img)?;
 
         let mut img: Vec<u8> = vec![0u8; w_img * h_img * 4];
         self.buf_to_img(&buf, &mut img, gradient);
 
         let file = File::create(fname)?;
-        let w = &mut BufWriter::new(file);
-        let mut encoder = png::Encoder::new(w, w_img as u32, h_img as u32);
-        encoder.set(png::ColorType::RGBA).set(png::BitDepth::Eight);
-        let mut writer = encoder.write_header()?;
-        writer.write_image_data(&img)?; // Save
+        write_png(
+            BufWriter::new(file),
+            &img,
+            w_img,
+            h_img,
+            png::BitDepth::Eight,
+        )?;
+
+        Ok(())
+    }
+
+    ///
+    /// Save the calculated spectrogram as a 16-bit PNG image.  The colours
+    /// are interpolated with 16 bits per channel, which preserves more of the
+    /// gradient than [Spectrogram::to_png].
+    ///
+    /// # Arguments
+    ///
+    ///  * `fname` - The path to the PNG to save to the filesystem.
+    ///  * `freq_scale` - The type of frequency scale to use for the spectrogram.
+    ///  * `gradient` - The colour gradient to use for the spectrogram.
+    ///  * `w_img` - The output image width.
+    ///  * `h_img` - The output image height.
+    ///
+    #[cfg(feature = "png")]
+    pub fn to_png16(
+        &self,
+        fname: &Path,
+        freq_scale: FrequencyScale,
+        gradient: &ColourGradient,
+        w_img: usize,
+        h_img: usize,
+    ) -> Result<(), SonogramError> {
+        let buf = self.to_buffer(freq_scale, w_img, h_img)?;
+
+        let mut img: Vec<u8> = vec![0u8; w_img * h_img * 8];
+        self.buf_to_img16(&buf, &mut img, gradient);
+
+        let file = File::create(fname)?;
+        write_png(
+            BufWriter::new(file),
+            &img,
+            w_img,
+            h_img,
+            png::BitDepth::Sixteen,
+        )?;
 
         Ok(())
     }
@@ -100,28 +538,46 @@ impl Spectrogram {
     ///
     #[cfg(feature = "png")]
     pub fn to_png_in_memory(
-        &mut self,
+        &self,
         freq_scale: FrequencyScale,
-        gradient: &mut ColourGradient,
+        gradient: &ColourGradient,
         w_img: usize,
         h_img: usize,
-    ) -> Result<Vec<u8>, std::io::Error> {
-        let buf = self.to_buffer(freq_scale, w_img, h_img);
+    ) -> Result<Vec<u8>, SonogramError> {
+        let buf = self.to_buffer(freq_scale, w_img, h_img)?;
 
         let mut img: Vec<u8> = vec![0u8; w_img * h_img * 4];
         self.buf_to_img(&buf, &mut img, gradient);
 
         let mut pngbuf: Vec<u8> = Vec::new();
-        let mut encoder = png::Encoder::new(&mut pngbuf, w_img as u32, h_img as u32);
-        encoder.set(png::ColorType::RGBA).set(png::BitDepth::Eight);
-        let mut writer = encoder.write_header()?;
-        writer.write_image_data(&img)?;
-
-        // The png writer needs to be explicitly dropped
-        drop(writer);
+        write_png(&mut pngbuf, &img, w_img, h_img, png::BitDepth::Eight)?;
         Ok(pngbuf)
     }
 
+    ///
+    /// Create the spectrogram in memory as a PNG, using one of the built-in
+    /// colour themes.  This is the same as [Spectrogram::to_png_in_memory]
+    /// without having to create a [ColourGradient] first.
+    ///
+    /// # Arguments
+    ///
+    ///  * `freq_scale` - The type of frequency scale to use for the spectrogram.
+    ///  * `theme` - The colour theme to use for the spectrogram.
+    ///  * `w_img` - The output image width.
+    ///  * `h_img` - The output image height.
+    ///
+    #[cfg(feature = "png")]
+    pub fn to_png_bytes_themed(
+        &self,
+        freq_scale: FrequencyScale,
+        theme: ColourTheme,
+        w_img: usize,
+        h_img: usize,
+    ) -> Result<Vec<u8>, SonogramError> {
+        let gradient = ColourGradient::create(theme);
+        self.to_png_in_memory(freq_scale, &gradient, w_img, h_img)
+    }
+
     ///
     /// Create the spectrogram in memory as raw RGBA format.
     ///
@@ -133,30 +589,91 @@ impl Spectrogram {
     ///  * `h_img` - The output image height.
     ///
     pub fn to_rgba_in_memory(
-        &mut self,
+        &self,
         freq_scale: FrequencyScale,
-        gradient: &mut ColourGradient,
+        gradient: &ColourGradient,
         w_img: usize,
         h_img: usize,
-    ) -> Vec<u8> {
-        let buf = self.to_buffer(freq_scale, w_img, h_img);
+    ) -> Result<Vec<u8>, SonogramError> {
+        let buf = self.to_buffer(freq_scale, w_img, h_img)?;
 
         let mut img: Vec<u8> = vec![0u8; w_img * h_img * 4];
         self.buf_to_img(&buf, &mut img, gradient);
 
-        img
+        Ok(img)
+    }
+
+    ///
+    /// Create the spectrogram as an [image::RgbaImage], which can then be
+    /// processed further or saved in any format the `image` crate supports.
+    ///
+    /// # Arguments
+    ///
+    ///  * `freq_scale` - The type of frequency scale to use for the spectrogram.
+    ///  * `gradient` - The colour gradient to use for the spectrogram.
+    ///  * `w_img` - The output image width.
+    ///  * `h_img` - The output image height.
+    ///
+    #[cfg(feature = "image")]
+    pub fn to_dynamic_image(
+        &self,
+        freq_scale: FrequencyScale,
+        gradient: &ColourGradient,
+        w_img: usize,
+        h_img: usize,
+    ) -> Result<image::RgbaImage, SonogramError> {
+        let img = self.to_rgba_in_memory(freq_scale, gradient, w_img, h_img)?;
+        Ok(image::RgbaImage::from_raw(w_img as u32, h_img as u32, img)
+            .expect("the RGBA buffer matches the image size"))
     }
 
     /// Convenience function to convert the the buffer to an image
-    fn buf_to_img(&self, buf: &[f32], img: &mut [u8], gradient: &mut ColourGradient) {
-        let (min, max) = get_min_max(buf);
-        gradient.set_min(min);
-        gradient.set_max(max);
+    fn buf_to_img(&self, buf: &[f32], img: &mut [u8], gradient: &ColourGradient) {
+        let (min, max) = match get_finite_min_max(buf) {
+            Some(min_max) => min_max,
+            None => {
+                // There is nothing to show, leave the image transparent
+                img.iter_mut().for_each(|b| *b = 0);
+                return;
+            }
+        };
+        // Look up the colours in a table, rather than interpolating each pixel
+        let lut = (!gradient.is_high_precision() && max > min)
+            .then(|| gradient.to_lut_in_range(LUT_SIZE, min, max));
+        let scale = (LUT_SIZE - 1) as f32 / (max - min);
+        let colour = |val: f32| match &lut {
+            Some(lut) if val.is_finite() => {
+                let i = ((val - min) * scale).round() as usize;
+                lut[i.min(LUT_SIZE - 1)].clone()
+            }
+            _ => gradient.get_colour_in_range(val, min, max),
+        };
 
-        // For each pixel, compute the RGBAColour, then assign each byte to output img
+        #[cfg(feature = "rayon")]
+        {
+            use rayon::prelude::*;
+            buf.par_chunks(PIXELS_PER_TASK)
+                .zip(img.par_chunks_mut(PIXELS_PER_TASK * 4))
+                .for_each(|(buf, img)| colour_pixels(buf, img, colour));
+        }
+        #[cfg(not(feature = "rayon"))]
+        colour_pixels(buf, img, colour);
+    }
+
+    /// Convert the buffer to a 16-bit RGBA image, each channel is big-endian
+    #[cfg(feature = "png")]
+    fn buf_to_img16(&self, buf: &[f32], img: &mut [u8], gradient: &ColourGradient) {
+        let (min, max) = match get_finite_min_max(buf) {
+            Some(min_max) => min_max,
+            None => {
+                // There is nothing to show, leave the image transparent
+                img.iter_mut().for_each(|b| *b = 0);
+                return;
+            }
+        };
         buf.iter()
-            .map(|val| gradient.get_colour(*val))
-            .flat_map(|c| [c.r, c.g, c.b, c.a].into_iter())
+            .map(|val| gradient.get_colour16_in_range(*val, min, max))
+            .flat_map(|c| c.into_iter().flat_map(u16::to_be_bytes))
             .zip(img.iter_mut())
             .for_each(|(val_rgba, img_rgba)| *img_rgba = val_rgba);
     }
@@ -170,74 +687,381 @@ impl Spectrogram {
     ///  * `freq_scale` - The type of frequency scale to use for the spectrogram.
     ///  * `cols` - The number of columns.
     ///  * `rows` - The number of rows.
+    ///  * `amplitude_scale` - The value domain to write.  Use
+    ///    [AmplitudeScale::Db] for the same values as [Spectrogram::to_buffer].
     ///
     pub fn to_csv(
-        &mut self,
+        &self,
         fname: &Path,
         freq_scale: FrequencyScale,
         cols: usize,
         rows: usize,
-    ) -> Result<(), std::io::Error> {
-        let result = self.to_buffer(freq_scale, cols, rows);
-
-        let mut writer = csv::Writer::from_path(fname)?;
-
-        // Create the CSV header
-        let mut csv_record: Vec<String> = (0..cols).into_iter().map(|x| x.to_string()).collect();
-        writer.write_record(&csv_record)?;
-
-        let mut i = 0;
-        for _ in 0..rows {
-            for c_rec in csv_record.iter_mut().take(cols) {
-                let val = result[i];
-                i += 1;
-                *c_rec = val.to_string();
-            }
-            writer.write_record(&csv_record)?;
-        }
-
-        writer.flush()?; // Save
-
-        Ok(())
+        amplitude_scale: AmplitudeScale,
+    ) -> Result<(), SonogramError> {
+        self.to_csv_with_filter(
+            fname,
+            freq_scale,
+            cols,
+            rows,
+            amplitude_scale,
+            ResizeFilter::Lanczos3,
+        )
     }
 
     ///
-    /// Map the spectrogram to the output buffer.  Essentially scales the
-    /// frequency to map to the vertical axis (y-axis) of the output and
-    /// scale the x-axis to match the output.  It will also convert the
-    /// spectrogram to dB.
+    /// The same as [Spectrogram::to_csv], with the given filter to resize the
+    /// spectrogram to `cols` x `rows`.
     ///
     /// # Arguments
     ///
+    ///  * `fname` - The path to the CSV to save to the filesystem.
     ///  * `freq_scale` - The type of frequency scale to use for the spectrogram.
-    ///  * `img_width` - The output image width.
-    ///  * `img_height` - The output image height.
+    ///  * `cols` - The number of columns.
+    ///  * `rows` - The number of rows.
+    ///  * `amplitude_scale` - The value domain to write.
+    ///  * `filter` - How the spectrogram is resized to `cols` x `rows`.
     ///
-    pub fn to_buffer(
+    pub fn to_csv_with_filter(
         &self,
+        fname: &Path,
         freq_scale: FrequencyScale,
-        img_width: usize,
-        img_height: usize,
-    ) -> Vec<f32> {
-        let mut buf = Vec::with_capacity(self.height * self.width);
+        cols: usize,
+        rows: usize,
+        amplitude_scale: AmplitudeScale,
+        filter: ResizeFilter,
+    ) -> Result<(), SonogramError> {
+        let result = self.to_csv_buffer(freq_scale, cols, rows, amplitude_scale, filter)?;
+        let header = (0..cols).map(|x| x.to_string()).collect();
+        write_csv(fname, &result, header)
+    }
 
-        // Apply the log scale if required
-        match freq_scale {
-            FrequencyScale::Log => {
-                let scaler = FreqScaler::create(freq_scale, self.height, self.height);
-                let mut vert_slice = vec![0.0; self.height];
-                for h in 0..self.height {
-                    let (f1, f2) = scaler.scale(h);
-                    let (h1, mut h2) = (f1.floor() as usize, f2.ceil() as usize);
-                    if h2 >= self.height {
-                        h2 = self.height - 1;
-                    }
-                    for w in 0..self.width {
+    ///
+    /// Save the calculated spectrogram as a CSV file, the same as
+    /// [Spectrogram::to_csv], but the header holds the time of each column
+    /// rather than its index.  The time is that of the middle of the output
+    /// column, so it is fractional when the spectrogram is resized, see
+    /// [Spectrogram::column_to_time].
+    ///
+    /// # Arguments
+    ///
+    ///  * `fname` - The path to the CSV to save to the filesystem.
+    ///  * `freq_scale` - The type of frequency scale to use for the spectrogram.
+    ///  * `cols` - The number of columns.
+    ///  * `rows` - The number of rows.
+    ///  * `amplitude_scale` - The value domain to write.
+    ///  * `time_unit` - The unit of the times in the header.
+    ///
+    pub fn to_csv_with_time(
+        &self,
+        fname: &Path,
+        freq_scale: FrequencyScale,
+        cols: usize,
+        rows: usize,
+        amplitude_scale: AmplitudeScale,
+        time_unit: TimeUnit,
+    ) -> Result<(), SonogramError> {
+        let filter = ResizeFilter::Lanczos3;
+        let result = self.to_csv_buffer(freq_scale, cols, rows, amplitude_scale, filter)?;
+
+        // The spectrogram column under the centre of each output column
+        let ratio = self.width as f32 / cols.max(1) as f32;
+        let header = (0..cols)
+            .map(|x| (x as f32 + 0.5) * ratio - 0.5)
+            .map(|column| self.column_time(column, time_unit).to_string())
+            .collect();
+        write_csv(fname, &result, header)
+    }
+
+    ///
+    /// The values written to a CSV file, the spectrogram resized to
+    /// `cols` x `rows`.
+    ///
+    fn to_csv_buffer(
+        &self,
+        freq_scale: FrequencyScale,
+        cols: usize,
+        rows: usize,
+        amplitude_scale: AmplitudeScale,
+        filter: ResizeFilter,
+    ) -> Result<Vec<f32>, SonogramError> {
+        check_image_size(cols, rows)?;
+        let buf = self.to_amplitude_buffer(freq_scale, amplitude_scale);
+        let mut result = resize(&buf, self.width, self.height, cols, rows, filter)?;
+        if amplitude_scale != AmplitudeScale::Db {
+            // The resize filter can ring slightly below zero
+            result.iter_mut().for_each(|val| *val = val.max(0.0));
+        }
+        Ok(result)
+    }
+
+    ///
+    /// Save the spectrogram, at its native size, as a NumPy `.npy` file.  The
+    /// array is `float32` with the shape `(height, width)`, the first row is
+    /// the highest frequency.  It can be loaded with `numpy.load(fname)`.
+    ///
+    /// # Arguments
+    ///
+    ///  * `fname` - The path to the `.npy` file to save to the filesystem.
+    ///
+    pub fn to_npy(&self, fname: &Path) -> Result<(), SonogramError> {
+        let mut header = format!(
+            "{{'descr': '<f4', 'fortran_order': False, 'shape': ({}, {}), }}",
+            self.height, self.width
+        );
+
+        // The magic string, version and header length take 10 bytes, the
+        // data must start on a multiple of 64 bytes
+        let padding = 64 - (10 + header.len() + 1) % 64;
+        header.push_str(&" ".repeat(padding % 64));
+        header.push('\n');
+
+        let mut writer = std::io::BufWriter::new(std::fs::File::create(fname)?);
+        writer.write_all(b"\x93NUMPY\x01\x00")?;
+        writer.write_all(&(header.len() as u16).to_le_bytes())?;
+        writer.write_all(header.as_bytes())?;
+        for val in &self.spec {
+            writer.write_all(&val.to_le_bytes())?;
+        }
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    ///
+    /// Map the spectrogram to the output buffer.  Essentially scales the
+    /// frequency to map to the vertical axis (y-axis) of the output and
+    /// scale the x-axis to match the output.  It will also convert the
+    /// spectrogram to dB, unless this was already done during the compute.
+    ///
+    /// Returns [SonogramError::InvalidImageSize] if either dimension is zero
+    /// or the image is unreasonably large.  Returns [SonogramError::Resize] if
+    /// the spectrogram is empty, or the resizer cannot allocate the filter for
+    /// an extreme change in aspect ratio.
+    ///
+    /// # Arguments
+    ///
+    ///  * `freq_scale` - The type of frequency scale to use for the spectrogram.
+    ///  * `img_width` - The output image width.
+    ///  * `img_height` - The output image height.
+    ///
+    pub fn to_buffer(
+        &self,
+        freq_scale: FrequencyScale,
+        img_width: usize,
+        img_height: usize,
+    ) -> Result<Vec<f32>, SonogramError> {
+        self.to_buffer_with_ref(freq_scale, img_width, img_height, None)
+    }
+
+    ///
+    /// The same as [Spectrogram::to_buffer], but the dB values are relative to
+    /// the given reference amplitude rather than the peak of this spectrogram.
+    /// Using the same reference, e.g. the magnitude of a full scale tone,
+    /// makes the output comparable across recordings.
+    ///
+    /// The reference is not used if the spectrogram was already converted to
+    /// dB when it was computed.
+    ///
+    /// # Arguments
+    ///
+    ///  * `freq_scale` - The type of frequency scale to use for the spectrogram.
+    ///  * `img_width` - The output image width.
+    ///  * `img_height` - The output image height.
+    ///  * `ref_amplitude` - The magnitude that maps to 0 dB, `None` uses the peak.
+    ///
+    pub fn to_buffer_with_ref(
+        &self,
+        freq_scale: FrequencyScale,
+        img_width: usize,
+        img_height: usize,
+        ref_amplitude: Option<f32>,
+    ) -> Result<Vec<f32>, SonogramError> {
+        check_image_size(img_width, img_height)?;
+
+        let buf = self.to_db_buffer(freq_scale, ref_amplitude);
+        Ok(resize(
+            &buf,
+            self.width,
+            self.height,
+            img_width,
+            img_height,
+            ResizeFilter::Lanczos3,
+        )?)
+    }
+
+    ///
+    /// The same as [Spectrogram::to_buffer], with the given filter to resize
+    /// the spectrogram to the output size.
+    ///
+    /// # Arguments
+    ///
+    ///  * `freq_scale` - The type of frequency scale to use for the spectrogram.
+    ///  * `img_width` - The output image width.
+    ///  * `img_height` - The output image height.
+    ///  * `filter` - How the spectrogram is resized to the output size.
+    ///
+    pub fn to_buffer_with_filter(
+        &self,
+        freq_scale: FrequencyScale,
+        img_width: usize,
+        img_height: usize,
+        filter: ResizeFilter,
+    ) -> Result<Vec<f32>, SonogramError> {
+        check_image_size(img_width, img_height)?;
+
+        let buf = self.to_db_buffer(freq_scale, None);
+        Ok(resize(
+            &buf,
+            self.width,
+            self.height,
+            img_width,
+            img_height,
+            filter,
+        )?)
+    }
+
+    ///
+    /// The same as [Spectrogram::to_buffer], but transposed so time runs down
+    /// the vertical axis and frequency runs along the horizontal axis.  The
+    /// first row is the first time window and the lowest frequency is on the
+    /// left.
+    ///
+    /// # Arguments
+    ///
+    ///  * `freq_scale` - The type of frequency scale to use for the spectrogram.
+    ///  * `img_width` - The output image width, the frequency axis.
+    ///  * `img_height` - The output image height, the time axis.
+    ///
+    pub fn to_buffer_transposed(
+        &self,
+        freq_scale: FrequencyScale,
+        img_width: usize,
+        img_height: usize,
+    ) -> Result<Vec<f32>, SonogramError> {
+        // Time is along the rows and the highest frequency is in the first row
+        let buf = self.to_buffer(freq_scale, img_height, img_width)?;
+
+        let mut result = vec![0.0; img_width * img_height];
+        for (t, row) in result.chunks_mut(img_width).enumerate() {
+            for (f, val) in row.iter_mut().enumerate() {
+                *val = buf[(img_width - 1 - f) * img_height + t];
+            }
+        }
+        Ok(result)
+    }
+
+    ///
+    /// The same as [Spectrogram::to_buffer], but the output is written into
+    /// `out`, which must be exactly `img_width * img_height` long.  This
+    /// allows the output buffer to be reused between calls.
+    ///
+    /// # Arguments
+    ///
+    ///  * `freq_scale` - The type of frequency scale to use for the spectrogram.
+    ///  * `img_width` - The output image width.
+    ///  * `img_height` - The output image height.
+    ///  * `out` - The output buffer.
+    ///
+    pub fn to_buffer_into(
+        &self,
+        freq_scale: FrequencyScale,
+        img_width: usize,
+        img_height: usize,
+        out: &mut [f32],
+    ) -> Result<(), SonogramError> {
+        check_image_size(img_width, img_height)?;
+        if out.len() != img_width * img_height {
+            return Err(SonogramError::InvalidBufferSize);
+        }
+
+        let buf = self.to_db_buffer(freq_scale, None);
+        resize_into(
+            &buf,
+            self.width,
+            self.height,
+            img_width,
+            img_height,
+            out,
+            ResizeFilter::Lanczos3,
+        )?;
+
+        Ok(())
+    }
+
+    ///
+    /// Apply the frequency scale and convert the spectrogram to dB, at the
+    /// native size of the spectrogram.
+    ///
+    fn to_db_buffer(&self, freq_scale: FrequencyScale, ref_amplitude: Option<f32>) -> Vec<f32> {
+        let mut buf = self.to_scaled_buffer(freq_scale);
+
+        // Convert the buffer to dB
+        if !self.is_db {
+            to_db_with_ref(&mut buf, ref_amplitude);
+        }
+
+        buf
+    }
+
+    ///
+    /// Apply the frequency scale and convert the spectrogram to the given
+    /// amplitude scale, at the native size of the spectrogram.  If the
+    /// spectrogram is already in dB the linear values are relative to the
+    /// peak.
+    ///
+    fn to_amplitude_buffer(
+        &self,
+        freq_scale: FrequencyScale,
+        amplitude_scale: AmplitudeScale,
+    ) -> Vec<f32> {
+        if amplitude_scale == AmplitudeScale::Db {
+            return self.to_db_buffer(freq_scale, None);
+        }
+
+        let mut buf = self.to_scaled_buffer(freq_scale);
+        if self.is_db {
+            buf.iter_mut()
+                .for_each(|val| *val = 10.0_f32.powf(*val / 20.0));
+        }
+        if amplitude_scale == AmplitudeScale::Power {
+            buf.iter_mut().for_each(|val| *val *= *val);
+        }
+
+        buf
+    }
+
+    ///
+    /// Apply the frequency scale, at the native size of the spectrogram.  If
+    /// the spectrogram is in dB the log scale resamples the linear magnitudes
+    /// and converts them back to dB, so it matches a linear spectrogram.
+    ///
+    fn to_scaled_buffer(&self, freq_scale: FrequencyScale) -> Vec<f32> {
+        let mut buf = Vec::with_capacity(self.height * self.width);
+
+        // Apply the log scale if required
+        match freq_scale {
+            FrequencyScale::Log => {
+                let scaler = FreqScaler::create(freq_scale, self.height, self.height);
+                let mut vert_slice = vec![0.0; self.height];
+                for h in 0..self.height {
+                    let (f1, f2) = scaler.scale(h);
+                    let (h1, mut h2) = (f1.floor() as usize, f2.ceil() as usize);
+                    if h2 >= self.height {
+                        h2 = self.height - 1;
+                    }
+                    for w in 0..self.width {
                         for (hh, val) in vert_slice.iter_mut().enumerate().take(h2).skip(h1) {
                             *val = self.spec[(hh * self.width) + w];
+                            if self.is_db {
+                                *val = 10.0_f32.powf(*val / 20.0);
+                            }
                         }
                         let value = integrate(f1, f2, &vert_slice);
-                        buf.push(value);
+                        if self.is_db {
+                            buf.push(20.0 * f32::max(MIN_DBFS_AMPLITUDE, value).log10());
+                        } else {
+                            buf.push(value);
+                        }
                     }
                 }
             }
@@ -246,10 +1070,87 @@ impl Spectrogram {
             }
         }
 
-        // Convert the buffer to dB
-        to_db(&mut buf);
+        buf
+    }
+
+    ///
+    /// Get the frequency, in Hz, at the center of each row of an output image
+    /// that is `img_height` pixels high, e.g. for drawing the axis labels of
+    /// a log scale image.  The first row is the top of the image.
+    ///
+    /// # Arguments
+    ///
+    ///  * `freq_scale` - The type of frequency scale used for the image.
+    ///  * `img_height` - The output image height.
+    ///  * `sample_rate` - The sample rate, in Hz, of the data.
+    ///
+    pub fn row_frequencies(
+        &self,
+        freq_scale: FrequencyScale,
+        img_height: usize,
+        sample_rate: u32,
+    ) -> Vec<f32> {
+        let ratio = sample_rate as f32 / self.sample_rate as f32;
+        (0..img_height)
+            .map(|y| self.image_row_to_freq(freq_scale, img_height, y as f32 + 0.5) * ratio)
+            .collect()
+    }
+
+    ///
+    /// Get the frequency, in Hz, at the vertical position `y` of an output
+    /// image that is `img_height` pixels high.  `y` may be fractional, 0.0 is
+    /// the top edge of the image and `img_height` is the bottom edge.
+    ///
+    pub(crate) fn image_row_to_freq(
+        &self,
+        freq_scale: FrequencyScale,
+        img_height: usize,
+        y: f32,
+    ) -> f32 {
+        // Position in the native spectrogram rows, before resizing
+        let y_native = y * self.height as f32 / img_height as f32;
+        let row = match freq_scale {
+            FrequencyScale::Linear => y_native,
+            FrequencyScale::Log => {
+                let scaler = FreqScaler::create(freq_scale, self.height, self.height);
+                scaler.position(y_native)
+            }
+        };
+        let row = row.clamp(0.0, self.height as f32);
 
-        resize(&buf, self.width, self.height, img_width, img_height)
+        // The first row is the highest frequency
+        self.bin_to_freq(self.height as f32 - row)
+    }
+
+    ///
+    /// Get the vertical position, in an output image that is `img_height`
+    /// pixels high, of the frequency `freq` in Hz.  This is the inverse of
+    /// [Spectrogram::image_row_to_freq].  Returns `None` if the frequency is
+    /// outside the image.
+    ///
+    pub(crate) fn freq_to_image_row(
+        &self,
+        freq_scale: FrequencyScale,
+        img_height: usize,
+        freq: f32,
+    ) -> Option<f32> {
+        let top = self.image_row_to_freq(freq_scale, img_height, 0.0);
+        let bottom = self.image_row_to_freq(freq_scale, img_height, img_height as f32);
+        if !(bottom..=top).contains(&freq) {
+            return None;
+        }
+
+        // The frequency falls from the top to the bottom of the image
+        let (mut y_top, mut y_bottom) = (0.0, img_height as f32);
+        for _ in 0..32 {
+            let y = (y_top + y_bottom) / 2.0;
+            if self.image_row_to_freq(freq_scale, img_height, y) > freq {
+                y_top = y;
+            } else {
+                y_bottom = y;
+            }
+        }
+        Some((y_top + y_bottom) / 2.0)
     }
 
     ///
@@ -258,6 +1159,205 @@ impl Spectrogram {
     pub fn get_min_max(&self) -> (f32, f32) {
         get_min_max(&self.spec)
     }
+
+    ///
+    /// Resample the frequency axis of the stored spectrogram to `target_bins`
+    /// rows, e.g. to a compact feature for further analysis.  Unlike
+    /// [Spectrogram::to_buffer] this changes the spectrogram itself.  Each new
+    /// row is the average of the rows it covers, including the fractions of
+    /// partly covered rows, so the energy (each value times its bandwidth) is
+    /// conserved.  Increasing the number of rows repeats the values.
+    ///
+    /// # Arguments
+    ///
+    ///  * `target_bins` - The new number of rows, must be at least 1,
+    ///    otherwise [SonogramError::InvalidNumBins] is returned.
+    ///
+    pub fn resample_frequency(&mut self, target_bins: usize) -> Result<(), SonogramError> {
+        if target_bins == 0 {
+            return Err(SonogramError::InvalidNumBins);
+        }
+        if target_bins == self.height || self.height == 0 {
+            return Ok(());
+        }
+
+        let ratio = self.height as f32 / target_bins as f32;
+        let mut spec = vec![0.0; target_bins * self.width];
+        let mut column = vec![0.0; self.height];
+        for w in 0..self.width {
+            for (h, val) in column.iter_mut().enumerate() {
+                *val = self.spec[h * self.width + w];
+            }
+            for h in 0..target_bins {
+                let (x1, x2) = (h as f32 * ratio, (h + 1) as f32 * ratio);
+                spec[h * self.width + w] = integrate(x1, x2, &column) / ratio;
+            }
+        }
+
+        self.spec = spec;
+        self.height = target_bins;
+        Ok(())
+    }
+
+    ///
+    /// Downsample the time axis of the stored spectrogram by averaging every
+    /// `factor` consecutive columns into one, e.g. to shrink a long
+    /// recording before analysis.  Unlike [Spectrogram::to_buffer] this
+    /// changes the spectrogram itself, and each column is an exact average
+    /// rather than a Lanczos3 interpolation.  The step size is multiplied by
+    /// `factor`.  If `width` is not a multiple of `factor` the last column
+    /// is the average of the columns that are left over.
+    ///
+    /// The values are averaged as stored, so downsample before converting to
+    /// dB to preserve the energy.
+    ///
+    /// # Arguments
+    ///
+    ///  * `factor` - The number of columns to merge, must be at least 1,
+    ///    otherwise [SonogramError::InvalidDivisor] is returned.
+    ///
+    pub fn downsample_time(&mut self, factor: usize) -> Result<(), SonogramError> {
+        if factor == 0 {
+            return Err(SonogramError::InvalidDivisor);
+        }
+        if factor == 1 || self.width == 0 {
+            return Ok(());
+        }
+
+        let width = self.width.div_ceil(factor);
+        let mut spec = Vec::with_capacity(width * self.height);
+        for row in self.spec.chunks_exact(self.width) {
+            spec.extend(
+                row.chunks(factor)
+                    .map(|group| group.iter().sum::<f32>() / group.len() as f32),
+            );
+        }
+
+        // Each merged column covers the windows of all the columns it averages
+        self.spec = spec;
+        self.width = width;
+        self.window_len += (factor - 1) * self.step_size;
+        self.step_size *= factor;
+        Ok(())
+    }
+
+    ///
+    /// Multiply each value of the spectrogram by the matching value of a
+    /// time-frequency mask, e.g. a binary or soft mask for source
+    /// separation.  The mask is laid out the same as [Spectrogram::raw_data].
+    ///
+    /// # Arguments
+    ///
+    ///  * `mask` - The mask, it must be `width * height` long.
+    ///
+    pub fn apply_mask(&mut self, mask: &[f32]) -> Result<(), SonogramError> {
+        if mask.len() != self.spec.len() {
+            return Err(SonogramError::InvalidBufferSize);
+        }
+
+        self.spec
+            .iter_mut()
+            .zip(mask)
+            .for_each(|(val, m)| *val *= m);
+        Ok(())
+    }
+
+    ///
+    /// Append the columns of `other` to the end of this spectrogram, e.g. to
+    /// join the blocks of a long recording, see
+    /// [SpecCompute::set_overlap_carry].
+    ///
+    /// # Returns
+    ///
+    /// [SonogramError::MismatchedSpectrograms] if the spectrograms don't have
+    /// the same height, sample rate, step size and value domain.
+    ///
+    pub fn append(&mut self, other: &Spectrogram) -> Result<(), SonogramError> {
+        if self.height != other.height
+            || self.sample_rate != other.sample_rate
+            || self.step_size != other.step_size
+            || self.is_db != other.is_db
+            || self.two_sided != other.two_sided
+        {
+            return Err(SonogramError::MismatchedSpectrograms);
+        }
+
+        let width = self.width + other.width;
+        let mut spec = Vec::with_capacity(width * self.height);
+        for h in 0..self.height {
+            spec.extend_from_slice(&self.spec[h * self.width..(h + 1) * self.width]);
+            spec.extend_from_slice(&other.spec[h * other.width..(h + 1) * other.width]);
+        }
+
+        self.spec = spec;
+        self.width = width;
+        Ok(())
+    }
+
+    ///
+    /// Subtract another spectrogram from this one, cell by cell.  Both must
+    /// have the same dimensions and both must be linear magnitudes or both
+    /// dB, see [Spectrogram::is_db].
+    ///
+    /// To render the difference, convert both spectrograms to dB first with
+    /// [Spectrogram::to_db_inplace], otherwise the negative differences are
+    /// lost when the image is converted to dB.  A diverging colour gradient
+    /// suits the result.
+    ///
+    /// # Arguments
+    ///
+    ///  * `other` - The spectrogram to subtract.
+    ///
+    /// # Returns
+    ///
+    /// A spectrogram of `self - other`, or [SonogramError::MismatchedSpectrograms].
+    ///
+    pub fn difference(&self, other: &Spectrogram) -> Result<Spectrogram, SonogramError> {
+        if self.width != other.width
+            || self.height != other.height
+            || self.is_db != other.is_db
+            || self.two_sided != other.two_sided
+        {
+            return Err(SonogramError::MismatchedSpectrograms);
+        }
+
+        Ok(Spectrogram {
+            spec: self
+                .spec
+                .iter()
+                .zip(&other.spec)
+                .map(|(a, b)| a - b)
+                .collect(),
+            width: self.width,
+            height: self.height,
+            sample_rate: self.sample_rate,
+            step_size: self.step_size,
+            is_db: self.is_db,
+            two_sided: self.two_sided,
+            window_len: self.window_len,
+            window_padding: self.window_padding,
+            time_reference: self.time_reference,
+        })
+    }
+}
+
+///
+/// Encode the RGBA image as a PNG, with 8 or 16 bits per channel.
+///
+#[cfg(feature = "png")]
+fn write_png<W: std::io::Write>(
+    w: W,
+    img: &[u8],
+    w_img: usize,
+    h_img: usize,
+    bit_depth: png::BitDepth,
+) -> Result<(), std::io::Error> {
+    let mut encoder = png::Encoder::new(w, w_img as u32, h_img as u32);
+    encoder.set(png::ColorType::RGBA).set(bit_depth);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(img)?; // Save
+
+    Ok(())
 }
 
 pub fn get_min_max(data: &[f32]) -> (f32, f32) {
@@ -270,17 +1370,57 @@ pub fn get_min_max(data: &[f32]) -> (f32, f32) {
     (min, max)
 }
 
-fn to_db(buf: &mut [f32]) {
-    let mut ref_db = f32::MIN;
-    buf.iter().for_each(|v| ref_db = f32::max(ref_db, *v));
+///
+/// Get the minimum and maximum values from `data`, ignoring any NaN or
+/// infinite values.
+///
+/// # Returns
+///
+/// `None` if `data` is empty or only contains NaN or infinite values.
+///
+pub fn get_finite_min_max(data: &[f32]) -> Option<(f32, f32)> {
+    data.iter()
+        .filter(|val| val.is_finite())
+        .fold(None, |min_max, &val| match min_max {
+            None => Some((val, val)),
+            Some((min, max)) => Some((f32::min(val, min), f32::max(val, max))),
+        })
+}
+
+///
+/// Convert linear magnitudes to dB, relative to the peak value.  The output
+/// is floored at 80 dB below the peak.
+///
+pub(crate) fn to_db(buf: &mut [f32]) {
+    to_db_with_ref(buf, None);
+}
+
+///
+/// Convert linear magnitudes to dB, relative to `ref_amplitude`, or the peak
+/// value if it is `None`.  The output is floored at 80 dB below the peak.
+/// NaN and infinite values are ignored when finding the peak, and are set to
+/// the floor, so one bad value doesn't spoil the whole image.
+///
+fn to_db_with_ref(buf: &mut [f32], ref_amplitude: Option<f32>) {
+    let ref_db = ref_amplitude.unwrap_or_else(|| {
+        let mut ref_db = f32::MIN;
+        buf.iter()
+            .filter(|v| v.is_finite())
+            .for_each(|v| ref_db = f32::max(ref_db, *v));
+        ref_db
+    });
 
     let amp_ref = ref_db * ref_db;
     let offset = 10.0 * (f32::max(1e-10, amp_ref)).log10();
     let mut log_spec_max = f32::MIN;
 
     for val in buf.iter_mut() {
-        *val = 10.0 * (f32::max(1e-10, *val * *val)).log10() - offset;
-        log_spec_max = f32::max(log_spec_max, *val);
+        if val.is_finite() {
+            *val = 10.0 * (f32::max(1e-10, *val * *val)).log10() - offset;
+            log_spec_max = f32::max(log_spec_max, *val);
+        } else {
+            *val = f32::NEG_INFINITY;
+        }
     }
 
     for val in buf.iter_mut() {
@@ -289,20 +1429,100 @@ fn to_db(buf: &mut [f32]) {
 }
 
 ///
-/// Resize the image buffer
+/// Colour each value of `buf` and write it to the RGBA image `img`.
+///
+fn colour_pixels(buf: &[f32], img: &mut [u8], colour: impl Fn(f32) -> RGBAColour) {
+    // For each pixel, compute the RGBAColour, then assign each byte to output img
+    buf.iter()
+        .map(|val| colour(*val))
+        .flat_map(|c| [c.r, c.g, c.b, c.a].into_iter())
+        .zip(img.iter_mut())
+        .for_each(|(val_rgba, img_rgba)| *img_rgba = val_rgba);
+}
+
+///
+/// Check the output image is at least one pixel and not absurdly large.
+///
+fn check_image_size(w_img: usize, h_img: usize) -> Result<(), SonogramError> {
+    match w_img.checked_mul(h_img) {
+        Some(pixels) if pixels > 0 && pixels <= MAX_IMAGE_PIXELS => Ok(()),
+        _ => Err(SonogramError::InvalidImageSize),
+    }
+}
+
+///
+/// Write the `result` buffer as a CSV file, one record for each row, after
+/// the header row.  There is one column for each header.
+///
+fn write_csv(
+    fname: &Path,
+    result: &[f32],
+    mut csv_record: Vec<String>,
+) -> Result<(), SonogramError> {
+    let mut writer = csv::Writer::from_path(fname)?;
+
+    // Create the CSV header
+    writer.write_record(&csv_record)?;
+
+    for row in result.chunks_exact(csv_record.len()) {
+        for (c_rec, val) in csv_record.iter_mut().zip(row) {
+            *c_rec = val.to_string();
+        }
+        writer.write_record(&csv_record)?;
+    }
+
+    writer.flush()?; // Save
+
+    Ok(())
+}
+
+///
+/// Resize the image buffer.  This fails if the input is empty, e.g. when there
+/// was too little data for a single window, or if the filter coefficients
+/// for an extreme change in aspect ratio cannot be allocated.
 ///
-fn resize(buf: &[f32], w_in: usize, h_in: usize, w_out: usize, h_out: usize) -> Vec<f32> {
+fn resize(
+    buf: &[f32],
+    w_in: usize,
+    h_in: usize,
+    w_out: usize,
+    h_out: usize,
+    filter: ResizeFilter,
+) -> Result<Vec<f32>, resize::Error> {
     // Resize the buffer to match the user requirements
-    if let Ok(mut resizer) = resize::new(w_in, h_in, w_out, h_out, GrayF32, Lanczos3) {
-        let mut resized_buf = vec![0.0; w_out * h_out];
-        let result = resizer.resize(buf.as_gray(), resized_buf.as_gray_mut());
-        if result.is_ok() {
-            return resized_buf;
+    let mut resized_buf = vec![0.0; w_out * h_out];
+    resize_into(buf, w_in, h_in, w_out, h_out, &mut resized_buf, filter)?;
+    Ok(resized_buf)
+}
+
+///
+/// Resize the image buffer into `out`, which must be `w_out * h_out` long.
+///
+fn resize_into(
+    buf: &[f32],
+    w_in: usize,
+    h_in: usize,
+    w_out: usize,
+    h_out: usize,
+    out: &mut [f32],
+    filter: ResizeFilter,
+) -> Result<(), resize::Error> {
+    if filter == ResizeFilter::NearestNeighbour {
+        if w_in == 0 || h_in == 0 || w_out == 0 || h_out == 0 {
+            return Err(resize::Error::InvalidParameters);
         }
+        // Sample the bin under the centre of each output pixel
+        for (y, row) in out.chunks_exact_mut(w_out).enumerate() {
+            let src = &buf[((2 * y + 1) * h_in / (2 * h_out)) * w_in..];
+            for (x, val) in row.iter_mut().enumerate() {
+                *val = src[(2 * x + 1) * w_in / (2 * w_out)];
+            }
+        }
+        return Ok(());
     }
 
-    // If this happens there resize return an Err
-    vec![]
+    let mut resizer = resize::new(w_in, h_in, w_out, h_out, GrayF32, Lanczos3)?;
+    resizer.resize(buf.as_gray(), out.as_gray_mut())
 }
 
 ///
@@ -348,6 +1568,27 @@ fn integrate(x1: f32, x2: f32, spec: &[f32]) -> f32 {
     }
 }
 
+///
+/// A spectrogram for the tests, with the given data and the default settings,
+/// a sample rate of 8000 Hz, a step of 16 samples and `2 * height` samples in
+/// each window.  Override the other fields with the struct update syntax.
+///
+#[cfg(test)]
+pub(crate) fn test_spectrogram(spec: Vec<f32>, width: usize, height: usize) -> Spectrogram {
+    Spectrogram {
+        spec,
+        width,
+        height,
+        sample_rate: 8000,
+        step_size: 16,
+        is_db: false,
+        two_sided: false,
+        window_len: 2 * height,
+        window_padding: 0,
+        time_reference: TimeReference::Center,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -384,4 +1625,706 @@ mod tests {
         let c = integrate(0.0, 4.0, &v);
         assert!((c - 8.123).abs() < 0.0001);
     }
+
+    #[test]
+    fn test_get_finite_min_max() {
+        assert_eq!(get_finite_min_max(&[]), None);
+        assert_eq!(get_finite_min_max(&[f32::NAN, f32::NAN]), None);
+        assert_eq!(get_finite_min_max(&[2.0]), Some((2.0, 2.0)));
+        assert_eq!(
+            get_finite_min_max(&[f32::NAN, 1.0, -3.0, f32::NAN, 5.0]),
+            Some((-3.0, 5.0))
+        );
+        assert_eq!(
+            get_finite_min_max(&[f32::INFINITY, 1.0, -3.0, f32::NEG_INFINITY]),
+            Some((-3.0, 1.0))
+        );
+        assert_eq!(get_finite_min_max(&[f32::INFINITY]), None);
+
+        // One infinite cell doesn't turn the whole image one colour
+        let spec = test_spectrogram(vec![0.0; 4], 2, 2);
+        let gradient = ColourGradient::black_white_theme();
+        let mut img = vec![0; 16];
+        spec.buf_to_img(&[f32::INFINITY, -40.0, -20.0, 0.0], &mut img, &gradient);
+        assert_ne!(img[4..8], img[8..12]);
+        assert_ne!(img[8..12], img[12..16]);
+    }
+
+    #[test]
+    fn test_db_output() {
+        let data: Vec<f32> = (0..2048).map(|i| (i as f32 * 0.7).sin()).collect();
+        let mut spec_compute = SpecCompute::new(64, 64, data, hann_function);
+        spec_compute.set_db_output(true);
+        let spec = spec_compute.compute();
+        assert!(spec.is_db());
+
+        // Rendering at the native size gives the same dB values as the rows
+        let buf = spec
+            .to_buffer(FrequencyScale::Linear, spec.width, spec.height)
+            .unwrap();
+        for (row, buf_row) in spec.row_iter().zip(buf.chunks(spec.width)) {
+            for (a, b) in row.iter().zip(buf_row) {
+                assert!((a - b).abs() < 0.01);
+            }
+        }
+
+        // The log scale is applied to the linear values, the same as when the
+        // spectrogram is converted to dB for rendering.  Noise keeps every bin
+        // well above the -80 dB floor.
+        let mut seed = 1_u32;
+        let noise: Vec<f32> = (0..2048)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                (seed >> 8) as f32 / (1 << 23) as f32 - 1.0
+            })
+            .collect();
+        let mut spec_compute = SpecCompute::new(64, 64, noise, hann_function);
+        let linear = spec_compute.compute();
+        spec_compute.set_db_output(true);
+        let spec = spec_compute.compute();
+
+        let peak = get_finite_min_max(&linear.spec).unwrap().1;
+        let (w, h) = (spec.width, spec.height);
+        let expected = linear
+            .to_buffer_with_ref(FrequencyScale::Log, w, h, Some(peak))
+            .unwrap();
+        let buf = spec.to_buffer(FrequencyScale::Log, w, h).unwrap();
+        let floor = get_finite_min_max(&expected).unwrap().1 - 80.0;
+        for (a, b) in expected.iter().zip(&buf).filter(|(a, _)| **a > floor) {
+            assert!((a - b).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn test_to_buffer_with_ref() {
+        let spec = |amplitude: f32| Spectrogram {
+            step_size: 8,
+            ..test_spectrogram(vec![amplitude; 16], 4, 4)
+        };
+        let loud = spec(1.0)
+            .to_buffer_with_ref(FrequencyScale::Linear, 4, 4, Some(1.0))
+            .unwrap();
+        let quiet = spec(0.5)
+            .to_buffer_with_ref(FrequencyScale::Linear, 4, 4, Some(1.0))
+            .unwrap();
+
+        // Half the amplitude is 6.02 dB quieter
+        let expected = 20.0 * 0.5_f32.log10();
+        assert!(loud[5].abs() < 0.001);
+        assert!((quiet[5] - expected).abs() < 0.001);
+
+        // Without a reference both are relative to their own peak
+        let quiet = spec(0.5).to_buffer(FrequencyScale::Linear, 4, 4).unwrap();
+        assert!(quiet[5].abs() < 0.001);
+    }
+
+    #[test]
+    fn test_to_buffer_into() {
+        let data: Vec<f32> = (0..4096).map(|i| (i as f32 * 0.2).sin()).collect();
+        let spec = SpecCompute::new(128, 128, data, hann_function).compute();
+
+        let mut out = vec![0.0; 40 * 30];
+        spec.to_buffer_into(FrequencyScale::Log, 40, 30, &mut out)
+            .unwrap();
+        assert_eq!(out, spec.to_buffer(FrequencyScale::Log, 40, 30).unwrap());
+
+        let mut out = vec![0.0; 40 * 29];
+        assert!(spec
+            .to_buffer_into(FrequencyScale::Log, 40, 30, &mut out)
+            .is_err());
+    }
+
+    #[test]
+    fn test_invalid_image_size() {
+        let data: Vec<f32> = (0..4096).map(|i| (i as f32 * 0.2).sin()).collect();
+        let spec = SpecCompute::new(128, 128, data, hann_function).compute();
+        let gradient = ColourGradient::black_white_theme();
+
+        for (w, h) in [(0, 30), (40, 0), (usize::MAX, 2)] {
+            assert!(matches!(
+                spec.to_buffer(FrequencyScale::Linear, w, h),
+                Err(SonogramError::InvalidImageSize)
+            ));
+            assert!(matches!(
+                spec.to_rgba_in_memory(FrequencyScale::Linear, &gradient, w, h),
+                Err(SonogramError::InvalidImageSize)
+            ));
+        }
+
+        let mut out = vec![];
+        assert!(matches!(
+            spec.to_buffer_into(FrequencyScale::Linear, 0, 0, &mut out),
+            Err(SonogramError::InvalidImageSize)
+        ));
+    }
+
+    #[test]
+    fn test_resize_failure() {
+        // Too little data for a single window, there is nothing to resize
+        let spec = SpecCompute::new(128, 128, vec![0.0; 100], hann_function).compute();
+        assert_eq!(spec.width, 0);
+        assert!(matches!(
+            spec.to_buffer(FrequencyScale::Linear, 40, 30),
+            Err(SonogramError::Resize(_))
+        ));
+
+        assert!(resize(&[], 0, 4, 10, 10, ResizeFilter::Lanczos3).is_err());
+        assert_eq!(
+            resize(&[1.0; 4], 2, 2, 3, 3, ResizeFilter::Lanczos3)
+                .unwrap()
+                .len(),
+            9
+        );
+    }
+
+    #[test]
+    fn test_to_db_inplace() {
+        let data: Vec<f32> = (0..2048).map(|i| (i as f32 * 0.3).sin()).collect();
+        let linear = SpecCompute::new(64, 32, data.clone(), hann_function).compute();
+        let mut db = SpecCompute::new(64, 32, data, hann_function).compute();
+
+        db.to_db_inplace();
+        assert!(db.is_db());
+
+        let mut buf = linear.spec.clone();
+        to_db(&mut buf);
+        assert_eq!(db.spec, buf);
+
+        // The rendered buffer is unchanged
+        assert_eq!(
+            db.to_buffer(FrequencyScale::Linear, 20, 20).unwrap(),
+            linear.to_buffer(FrequencyScale::Linear, 20, 20).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_difference() {
+        let data: Vec<f32> = (0..1024).map(|i| (i as f32 * 0.3).sin()).collect();
+        let mut spec_compute = SpecCompute::new(64, 32, data, hann_function);
+        let spec = spec_compute.compute();
+
+        let diff = spec.difference(&spec).unwrap();
+        assert_eq!((diff.width, diff.height), (spec.width, spec.height));
+        assert!(diff.spec.iter().all(|&v| v == 0.0));
+
+        // The units must match
+        let mut db_spec = spec_compute.compute();
+        db_spec.to_db_inplace();
+        assert!(matches!(
+            spec.difference(&db_spec),
+            Err(SonogramError::MismatchedSpectrograms)
+        ));
+
+        // The dimensions must match
+        spec_compute.set_data(vec![0.0; 512]);
+        assert!(matches!(
+            spec.difference(&spec_compute.compute()),
+            Err(SonogramError::MismatchedSpectrograms)
+        ));
+    }
+
+    #[test]
+    fn test_to_buffer_transposed() {
+        let data: Vec<f32> = (0..4096).map(|i| (i as f32 * 0.3).sin()).collect();
+        let spec = SpecCompute::new(64, 32, data, hann_function).compute();
+
+        let buf = spec.to_buffer(FrequencyScale::Linear, 40, 10).unwrap();
+        let transposed = spec
+            .to_buffer_transposed(FrequencyScale::Linear, 10, 40)
+            .unwrap();
+        assert_eq!(transposed.len(), 400);
+
+        // Each row of the transposed buffer is a column of the normal one,
+        // with the lowest frequency first
+        for t in 0..40 {
+            for f in 0..10 {
+                assert_eq!(transposed[t * 10 + f], buf[(9 - f) * 40 + t]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_row_frequencies() {
+        // 4000 Hz nyquist over 8 rows, 500 Hz per row
+        let spec = test_spectrogram(vec![1.0; 8], 1, 8);
+
+        let freqs = spec.row_frequencies(FrequencyScale::Linear, 4, 8000);
+        assert_eq!(freqs, vec![3500.0, 2500.0, 1500.0, 500.0]);
+
+        // The log scale gives more rows to the low frequencies
+        let freqs = spec.row_frequencies(FrequencyScale::Log, 8, 8000);
+        assert!(freqs.windows(2).all(|f| f[0] > f[1]));
+        assert!(freqs[0] - freqs[1] > freqs[6] - freqs[7]);
+    }
+
+    #[test]
+    #[cfg(feature = "png")]
+    fn test_buf_to_img16() {
+        let spec = test_spectrogram(vec![], 0, 0);
+        let gradient = ColourGradient::black_white_theme();
+
+        let mut img = vec![0u8; 2 * 8];
+        spec.buf_to_img16(&[0.0, 2.0], &mut img, &gradient);
+
+        // Black then white, each channel is big-endian
+        assert_eq!(img[..8], [0, 0, 0, 0, 0, 0, 0xff, 0xff]);
+        assert_eq!(img[8..], [0xff; 8]);
+    }
+
+    #[test]
+    fn test_to_rows() {
+        let spec = test_spectrogram(vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0], 3, 2);
+
+        let rows = spec.to_rows();
+        assert_eq!(rows, vec![vec![1.0, 2.0, 3.0], vec![4.0, 5.0, 6.0]]);
+        assert_eq!(rows.concat(), spec.raw_data());
+    }
+
+    #[test]
+    fn test_to_csv_amplitude_scale() {
+        let spec = test_spectrogram(vec![0.5, 1.0, 0.25, 0.5], 2, 2);
+        let fname = std::env::temp_dir().join(format!("sonogram_{}.csv", std::process::id()));
+        let first_cell = |amplitude_scale| {
+            spec.to_csv(&fname, FrequencyScale::Linear, 2, 2, amplitude_scale)
+                .unwrap();
+            let csv = std::fs::read_to_string(&fname).unwrap();
+            // Skip the header row
+            let line = csv.lines().nth(1).unwrap();
+            line.split(',').next().unwrap().parse::<f32>().unwrap()
+        };
+
+        assert!((first_cell(AmplitudeScale::Linear) - 0.5).abs() < 1e-4);
+        assert!((first_cell(AmplitudeScale::Power) - 0.25).abs() < 1e-4);
+        // Half of the peak is about -6 dB
+        assert!((first_cell(AmplitudeScale::Db) + 6.0206).abs() < 1e-3);
+        std::fs::remove_file(&fname).unwrap();
+    }
+
+    #[test]
+    fn test_to_csv_with_time() {
+        let spec = Spectrogram {
+            window_len: 32,
+            ..test_spectrogram(vec![1.0; 8], 4, 2)
+        };
+        let fname = std::env::temp_dir().join(format!("sonogram_time_{}.csv", std::process::id()));
+        let header = |cols, time_unit| {
+            spec.to_csv_with_time(
+                &fname,
+                FrequencyScale::Linear,
+                cols,
+                2,
+                AmplitudeScale::Db,
+                time_unit,
+            )
+            .unwrap();
+            let csv = std::fs::read_to_string(&fname).unwrap();
+            let line = csv.lines().next().unwrap().to_string();
+            line.split(',')
+                .map(|v| v.parse::<f32>().unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        // The middle of each window, 16 samples in
+        assert_eq!(
+            header(4, TimeUnit::Seconds),
+            [16.0 / 8000.0, 32.0 / 8000.0, 48.0 / 8000.0, 64.0 / 8000.0]
+        );
+        assert_eq!(header(4, TimeUnit::Samples), [16.0, 32.0, 48.0, 64.0]);
+        assert_eq!(header(4, TimeUnit::Frames), [0.0, 1.0, 2.0, 3.0]);
+
+        // Resized, each output column is half way between two
+        assert_eq!(header(2, TimeUnit::Frames), [0.5, 2.5]);
+        assert_eq!(header(2, TimeUnit::Samples), [24.0, 56.0]);
+        std::fs::remove_file(&fname).unwrap();
+    }
+
+    #[test]
+    fn test_buf_to_img_lut() {
+        let spec = Spectrogram {
+            is_db: true,
+            ..test_spectrogram(vec![], 0, 0)
+        };
+        let buf: Vec<f32> = (0..5000).map(|i| (i as f32 * 0.37).sin() * 40.0).collect();
+        let mut gradient = ColourGradient::create(ColourTheme::Default);
+
+        let mut fast = vec![0u8; buf.len() * 4];
+        spec.buf_to_img(&buf, &mut fast, &gradient);
+        gradient.set_high_precision(true);
+        let mut exact = vec![0u8; buf.len() * 4];
+        spec.buf_to_img(&buf, &mut exact, &gradient);
+
+        // The lookup table is at most a step or so off the exact colour
+        for (f, e) in fast.iter().zip(exact.iter()) {
+            assert!((*f as i32 - *e as i32).abs() <= 2);
+        }
+    }
+
+    #[test]
+    fn test_downsample_time() {
+        let mut spec = test_spectrogram((0..300).map(|i| i as f32).collect(), 100, 3);
+        spec.downsample_time(4).unwrap();
+        assert_eq!(spec.width, 25);
+        assert_eq!(spec.height, 3);
+        assert_eq!(spec.step_size, 64);
+        assert_eq!(spec.spec.len(), 75);
+        // Each value is the average of four columns, e.g. (0 + 1 + 2 + 3) / 4
+        assert_eq!(spec.spec[0], 1.5);
+        assert_eq!(spec.spec[24], 97.5);
+        assert_eq!(spec.spec[25], 101.5);
+
+        // The leftover columns are averaged on their own
+        spec.downsample_time(2).unwrap();
+        assert_eq!(spec.width, 13);
+        assert_eq!(spec.spec[0], 3.5);
+        assert_eq!(spec.spec[12], 97.5);
+
+        assert!(matches!(
+            spec.downsample_time(0),
+            Err(SonogramError::InvalidDivisor)
+        ));
+        assert_eq!(spec.width, 13);
+    }
+
+    #[test]
+    fn test_downsample_time_reference() {
+        let mut spec = test_spectrogram(vec![0.0; 300], 100, 3);
+        let samples = |spec: &Spectrogram, c| spec.column_time(c as f32, TimeUnit::Samples);
+        let centers: Vec<f32> = (0..4).map(|c| samples(&spec, c)).collect();
+        spec.downsample_time(4).unwrap();
+
+        // The merged column is centered on the middle of the columns it averages
+        assert_eq!(spec.window_len, 6 + 3 * 16);
+        assert_eq!(samples(&spec, 0), centers.iter().sum::<f32>() / 4.0);
+        spec.set_time_reference(TimeReference::End);
+        assert_eq!(samples(&spec, 0), centers[3] + 3.0);
+    }
+
+    #[test]
+    fn test_nearest_neighbour() {
+        let spec = Spectrogram {
+            is_db: true,
+            ..test_spectrogram(vec![0.0, -20.0, -40.0, -60.0], 2, 2)
+        };
+
+        // Lanczos3 blends the bins together
+        let smooth = spec.to_buffer(FrequencyScale::Linear, 8, 8).unwrap();
+        assert!(smooth
+            .iter()
+            .any(|v| ![0.0, -20.0, -40.0, -60.0].contains(v)));
+
+        // Each bin becomes a solid 4x4 block
+        let blocks = spec
+            .to_buffer_with_filter(FrequencyScale::Linear, 8, 8, ResizeFilter::NearestNeighbour)
+            .unwrap();
+        for y in 0..8 {
+            for x in 0..8 {
+                assert_eq!(blocks[y * 8 + x], spec.spec[(y / 4) * 2 + x / 4]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_resample_frequency() {
+        let data: Vec<f32> = (0..8192)
+            .map(|i| (i as f32 * 0.3).sin() + (i as f32 * 1.7).sin() * 0.5)
+            .collect();
+        let mut spec = SpecCompute::new(1024, 512, data, hann_function).compute();
+        assert_eq!(spec.height, 512);
+        let energy: f32 = spec.spec.iter().sum();
+        let width = spec.width;
+
+        spec.resample_frequency(64).unwrap();
+        assert_eq!(spec.height, 64);
+        assert_eq!(spec.width, width);
+        assert_eq!(spec.spec.len(), 64 * width);
+        let resampled: f32 = spec.spec.iter().sum::<f32>() * 512.0 / 64.0;
+        assert!((resampled - energy).abs() / energy < 1e-3);
+
+        // Up again, the values are repeated
+        spec.resample_frequency(128).unwrap();
+        assert_eq!(spec.height, 128);
+        assert!((spec.spec[0] - spec.spec[width]).abs() < 1e-5);
+        let upsampled: f32 = spec.spec.iter().sum::<f32>() * 512.0 / 128.0;
+        assert!((upsampled - energy).abs() / energy < 1e-3);
+
+        assert!(matches!(
+            spec.resample_frequency(0),
+            Err(SonogramError::InvalidNumBins)
+        ));
+        assert_eq!(spec.height, 128);
+    }
+
+    #[test]
+    fn test_to_db_non_finite() {
+        let clean = vec![1.0, 0.5, 0.1, 0.001, 0.25, 0.75];
+        let mut expected = clean.clone();
+        to_db(&mut expected);
+
+        let mut buf = clean.clone();
+        buf.extend([f32::NAN, f32::INFINITY, f32::NEG_INFINITY]);
+        to_db(&mut buf);
+        assert_eq!(buf[..6], expected[..]);
+        for val in &buf[6..] {
+            assert_eq!(*val, -80.0);
+        }
+
+        // The rest of the image still renders
+        let mut spec = test_spectrogram(vec![0.5; 64], 8, 8);
+        spec.spec[10] = 1.0;
+        spec.spec[20] = f32::NAN;
+        let buf = spec.to_buffer(FrequencyScale::Linear, 8, 8).unwrap();
+        assert!(buf.iter().all(|val| val.is_finite()));
+        let gradient = ColourGradient::black_white_theme();
+        let img = spec
+            .to_rgba_in_memory(FrequencyScale::Linear, &gradient, 8, 8)
+            .unwrap();
+        assert!(img.chunks(4).all(|pixel| pixel[3] == 255));
+    }
+
+    #[test]
+    fn test_row_iter_db() {
+        let data: Vec<f32> = (0..4096).map(|i| (i as f32 * 0.2).sin()).collect();
+        let spec = SpecCompute::new(128, 128, data, hann_function).compute();
+
+        // The same as the image, at the native size
+        let buf = spec
+            .to_buffer(FrequencyScale::Linear, spec.width, spec.height)
+            .unwrap();
+        for (row, buf_row) in spec.row_iter_db().zip(buf.chunks(spec.width)) {
+            for (val, pixel) in row.iter().zip(buf_row) {
+                assert!((val - pixel).abs() < 0.01);
+            }
+        }
+        assert_eq!(spec.row_iter_db().count(), spec.height);
+
+        for (row, power) in spec.row_iter().zip(spec.row_iter_power()) {
+            for (val, p) in row.iter().zip(power) {
+                assert_eq!(val * val, p);
+            }
+        }
+    }
+
+    #[test]
+    fn test_to_npy() {
+        let spec = test_spectrogram(vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0], 3, 2);
+        let fname = std::env::temp_dir().join(format!("sonogram_{}.npy", std::process::id()));
+        spec.to_npy(&fname).unwrap();
+        let bytes = std::fs::read(&fname).unwrap();
+        std::fs::remove_file(&fname).unwrap();
+
+        assert_eq!(&bytes[..8], b"\x93NUMPY\x01\x00");
+        let header_len = u16::from_le_bytes([bytes[8], bytes[9]]) as usize;
+        assert_eq!((10 + header_len) % 64, 0);
+        let header = std::str::from_utf8(&bytes[10..10 + header_len]).unwrap();
+        assert!(header.contains("'descr': '<f4'"));
+        assert!(header.contains("'shape': (2, 3)"));
+        assert!(header.ends_with('\n'));
+
+        let data: Vec<f32> = bytes[10 + header_len..]
+            .chunks(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+        assert_eq!(data, spec.spec);
+    }
+
+    #[test]
+    fn test_apply_mask() {
+        let mut spec = test_spectrogram(vec![1.0, 2.0, 3.0, 4.0], 2, 2);
+
+        spec.apply_mask(&[1.0, 0.0, 0.5, 1.0]).unwrap();
+        assert_eq!(spec.spec, vec![1.0, 0.0, 1.5, 4.0]);
+
+        assert!(matches!(
+            spec.apply_mask(&[1.0; 3]),
+            Err(SonogramError::InvalidBufferSize)
+        ));
+    }
+
+    #[test]
+    fn test_cells() {
+        // 4000 Hz nyquist over 2 rows, 2000 Hz per row
+        let spec = Spectrogram {
+            step_size: 80,
+            time_reference: TimeReference::Start,
+            ..test_spectrogram(vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0], 3, 2)
+        };
+
+        let cells: Vec<(f32, f32, f32)> = spec.cells(8000).collect();
+        assert_eq!(
+            cells,
+            vec![
+                (0.0, 2000.0, 1.0),
+                (0.01, 2000.0, 2.0),
+                (0.02, 2000.0, 3.0),
+                (0.0, 0.0, 4.0),
+                (0.01, 0.0, 5.0),
+                (0.02, 0.0, 6.0),
+            ]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "png")]
+    fn test_to_png_bytes_themed() {
+        let data: Vec<f32> = (0..4096).map(|i| (i as f32 * 0.3).sin()).collect();
+        let spec = SpecCompute::new(64, 32, data, hann_function).compute();
+
+        let themed = spec
+            .to_png_bytes_themed(FrequencyScale::Linear, ColourTheme::Rainbow, 40, 20)
+            .unwrap();
+        let gradient = ColourGradient::create(ColourTheme::Rainbow);
+        let explicit = spec
+            .to_png_in_memory(FrequencyScale::Linear, &gradient, 40, 20)
+            .unwrap();
+        assert_eq!(themed, explicit);
+        assert_eq!(&themed[1..4], b"PNG");
+    }
+
+    #[test]
+    fn test_shared_gradient() {
+        let data: Vec<f32> = (0..4096).map(|i| (i as f32 * 0.3).sin()).collect();
+        let spec = SpecCompute::new(64, 32, data, hann_function).compute();
+        let mut gradient = ColourGradient::default_theme();
+        gradient.set_min(-1.0);
+        gradient.set_max(1.0);
+        let expected = spec
+            .to_rgba_in_memory(FrequencyScale::Linear, &gradient, 40, 20)
+            .unwrap();
+
+        // Rendering changes neither the spectrogram nor the gradient, so both
+        // can be shared between threads
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    let img = spec
+                        .to_rgba_in_memory(FrequencyScale::Linear, &gradient, 40, 20)
+                        .unwrap();
+                    assert_eq!(img, expected);
+                });
+            }
+        });
+        assert_eq!(
+            gradient.get_colour(1.0),
+            gradient.get_colour_in_range(1.0, -1.0, 1.0)
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "rayon")]
+    fn test_buf_to_img_parallel() {
+        let spec = SpecCompute::new(64, 32, vec![0.5; 64], hann_function).compute();
+        let buf: Vec<f32> = (0..50_000)
+            .map(|i| (i as f32 * 0.37).sin() * 40.0)
+            .collect();
+        let mut gradient = ColourGradient::create(ColourTheme::Default);
+
+        for high_precision in [false, true] {
+            gradient.set_high_precision(high_precision);
+            let render = |threads: usize| {
+                let mut img = vec![0u8; buf.len() * 4];
+                rayon::ThreadPoolBuilder::new()
+                    .num_threads(threads)
+                    .build()
+                    .unwrap()
+                    .install(|| spec.buf_to_img(&buf, &mut img, &gradient));
+                img
+            };
+            let serial = render(1);
+            assert_eq!(render(4), serial);
+
+            if high_precision {
+                let (min, max) = get_finite_min_max(&buf).unwrap();
+                let mut img = vec![0u8; buf.len() * 4];
+                colour_pixels(&buf, &mut img, |val| {
+                    gradient.get_colour_in_range(val, min, max)
+                });
+                assert_eq!(img, serial);
+            }
+        }
+    }
+
+    #[test]
+    fn test_value_at() {
+        // 4000 Hz nyquist over 2 rows, 2000 Hz per row, columns 10 ms apart
+        let spec = Spectrogram {
+            step_size: 80,
+            time_reference: TimeReference::Start,
+            ..test_spectrogram(vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0], 3, 2)
+        };
+
+        // On the cells, the same values as `cells`
+        for (time, freq, val) in spec.cells(8000) {
+            assert_eq!(spec.value_at(time, freq, 8000), Some(val));
+        }
+
+        // Between the cells
+        assert_eq!(spec.value_at(0.005, 2000.0, 8000), Some(1.5));
+        assert_eq!(spec.value_at(0.0, 1000.0, 8000), Some(2.5));
+        assert_eq!(spec.value_at(0.015, 500.0, 8000), Some(4.75));
+
+        // At twice the sample rate the frequencies are doubled
+        assert_eq!(spec.value_at(0.0, 2000.0, 16000), Some(2.5));
+
+        // Outside the spectrogram
+        assert_eq!(spec.value_at(-0.001, 1000.0, 8000), None);
+        assert_eq!(spec.value_at(0.021, 1000.0, 8000), None);
+        assert_eq!(spec.value_at(0.01, 2001.0, 8000), None);
+        assert_eq!(spec.value_at(0.01, -1.0, 8000), None);
+    }
+
+    #[test]
+    fn test_time_reference() {
+        let data: Vec<f32> = (0..4096).map(|i| (i as f32 * 0.3).sin()).collect();
+        let mut spec = SpecCompute::new(256, 128, data.clone(), hann_function).compute();
+        assert_eq!(spec.column_to_time(0), 128.0 / 11025.0);
+        assert_eq!(spec.column_to_time(2), 384.0 / 11025.0);
+
+        spec.set_time_reference(TimeReference::Start);
+        assert_eq!(spec.column_to_time(0), 0.0);
+        assert_eq!(spec.column_to_time(2), 256.0 / 11025.0);
+
+        spec.set_time_reference(TimeReference::End);
+        assert_eq!(spec.column_to_time(0), 256.0 / 11025.0);
+        assert_eq!(spec.cells(11025).next().unwrap().0, 256.0 / 11025.0);
+
+        // Centered windows start half a window before the data
+        let mut spec_compute = SpecCompute::new(256, 128, data, hann_function);
+        spec_compute.set_center_windows(true);
+        let mut spec = spec_compute.compute();
+        assert_eq!(spec.column_to_time(0), 0.0);
+        spec.set_time_reference(TimeReference::Start);
+        assert_eq!(spec.column_to_time(0), -128.0 / 11025.0);
+        assert_eq!(spec.time_to_column(spec.column_to_time(3)), 3.0);
+    }
+
+    #[test]
+    #[cfg(feature = "image")]
+    fn test_to_dynamic_image() {
+        let data: Vec<f32> = (0..4096).map(|i| (i as f32 * 0.3).sin()).collect();
+        let spec = SpecCompute::new(64, 32, data, hann_function).compute();
+        let gradient = ColourGradient::default_theme();
+
+        let rgba = spec
+            .to_rgba_in_memory(FrequencyScale::Linear, &gradient, 40, 10)
+            .unwrap();
+        let img = spec
+            .to_dynamic_image(FrequencyScale::Linear, &gradient, 40, 10)
+            .unwrap();
+        assert_eq!(img.dimensions(), (40, 10));
+        assert_eq!(img.into_raw(), rgba);
+    }
+
+    #[test]
+    fn test_shared_planner() {
+        let data: Vec<f32> = (0..1024).map(|i| (i as f32 * 0.1).sin()).collect();
+        let mut planner = FftPlanner::<f32>::new();
+
+        let a = SpecCompute::new(128, 64, data.clone(), rectangular).compute();
+        let b =
+            SpecCompute::with_planner(128, 64, data.clone(), rectangular, &mut planner).compute();
+        let c = SpecCompute::with_planner(128, 64, data, rectangular, &mut planner).compute();
+
+        assert_eq!(a.spec, b.spec);
+        assert_eq!(b.spec, c.spec);
+    }
 }
@@ -0,0 +1,114 @@
+/*
+ * Copyright (C) Simon Werner, 2022.
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! The mel scale and mel filterbank
+//!
+//! The mel scale is a perceptual scale of pitch, equal distances on the mel
+//! scale sound equally far apart to a listener.
+
+///
+/// Convert a frequency in Hz to mels.
+///
+pub fn hz_to_mel(hz: f32) -> f32 {
+    2595.0 * (1.0 + hz / 700.0).log10()
+}
+
+///
+/// Convert mels to a frequency in Hz.
+///
+pub fn mel_to_hz(mel: f32) -> f32 {
+    700.0 * (10f32.powf(mel / 2595.0) - 1.0)
+}
+
+///
+/// Create a bank of triangular filters, evenly spaced on the mel scale from
+/// 0 Hz to the nyquist frequency.  Each filter peaks at 1.0 and overlaps
+/// half of each neighbour.
+///
+/// # Arguments
+///
+///  * `sample_rate` - The sample rate, in Hz, of the data.
+///  * `num_bins` - The number of FFT bins.
+///  * `num_filters` - The number of filters.
+///
+/// # Returns
+///
+/// The weights of each filter, for FFT bins `0..num_bins / 2`.
+///
+pub fn mel_filterbank(sample_rate: u32, num_bins: usize, num_filters: usize) -> Vec<Vec<f32>> {
+    let nyquist = sample_rate as f32 / 2.0;
+    let mel_max = hz_to_mel(nyquist);
+
+    // The edges of the filters, filter i spans edges i..i+2
+    let edges: Vec<f32> = (0..num_filters + 2)
+        .map(|i| mel_to_hz(mel_max * i as f32 / (num_filters + 1) as f32))
+        .collect();
+
+    let bin_hz = sample_rate as f32 / num_bins as f32;
+    edges
+        .windows(3)
+        .map(|edge| {
+            let (low, centre, high) = (edge[0], edge[1], edge[2]);
+            (0..num_bins / 2)
+                .map(|k| {
+                    let f = k as f32 * bin_hz;
+                    if f <= low || f >= high {
+                        0.0
+                    } else if f <= centre {
+                        (f - low) / (centre - low)
+                    } else {
+                        (high - f) / (high - centre)
+                    }
+                })
+                .collect()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mel() {
+        assert!((hz_to_mel(1000.0) - 1000.0).abs() < 0.5);
+        assert!((mel_to_hz(hz_to_mel(4321.0)) - 4321.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn test_mel_filterbank() {
+        let filters = mel_filterbank(16000, 1024, 40);
+        assert_eq!(filters.len(), 40);
+        assert!(filters.iter().all(|f| f.len() == 512));
+
+        // Between the first and last filter peaks the overlapping triangles sum to 1.0
+        let first_peak = filters[0].iter().position(|&w| w > 0.0).unwrap();
+        let last_peak = filters[39]
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(b.1))
+            .unwrap()
+            .0;
+        for k in first_peak + 4..last_peak {
+            let sum: f32 = filters.iter().map(|f| f[k]).sum();
+            assert!((sum - 1.0).abs() < 0.001);
+        }
+
+        // The last filter reaches up to nyquist
+        assert!(filters[39][500] > 0.0);
+    }
+}
@@ -0,0 +1,664 @@
+/*
+ * Copyright (C) Simon Werner, 2022.
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Overlays drawn on top of a rendered spectrogram
+//!
+//! The functions in this module draw directly into the RGBA buffer created
+//! by [Spectrogram::to_rgba_in_memory].
+
+#[cfg(feature = "png")]
+use std::fs::File;
+#[cfg(feature = "png")]
+use std::io::BufWriter;
+#[cfg(feature = "png")]
+use std::path::Path;
+
+#[cfg(feature = "png")]
+use crate::write_png;
+use crate::{
+    get_finite_min_max, ColourGradient, FrequencyScale, RGBAColour, SonogramError, Spectrogram,
+};
+
+/// The gap, in pixels, between the spectrogram and the legend.
+const LEGEND_GAP: usize = 8;
+
+/// The length, in pixels, of the legend tick marks.
+const TICK_LEN: usize = 4;
+
+/// The length, in pixels, of each arm of a marker cross.
+const MARKER_RADIUS: isize = 3;
+
+/// The scale of the legend label font, each glyph is 3 x 5 pixels unscaled.
+const FONT_SCALE: usize = 2;
+
+/// The width, in pixels, of each character of a label, including spacing.
+const CHAR_WIDTH: usize = 4 * FONT_SCALE;
+
+/// 3 x 5 pixel glyphs for the characters used in the legend labels, each row
+/// is 3 bits with the most significant bit on the left.
+const GLYPHS: [(char, [u8; 5]); 12] = [
+    ('0', [0b111, 0b101, 0b101, 0b101, 0b111]),
+    ('1', [0b010, 0b110, 0b010, 0b010, 0b111]),
+    ('2', [0b111, 0b001, 0b111, 0b100, 0b111]),
+    ('3', [0b111, 0b001, 0b111, 0b001, 0b111]),
+    ('4', [0b101, 0b101, 0b111, 0b001, 0b001]),
+    ('5', [0b111, 0b100, 0b111, 0b001, 0b111]),
+    ('6', [0b111, 0b100, 0b111, 0b101, 0b111]),
+    ('7', [0b111, 0b001, 0b010, 0b010, 0b010]),
+    ('8', [0b111, 0b101, 0b111, 0b101, 0b111]),
+    ('9', [0b111, 0b101, 0b111, 0b001, 0b111]),
+    ('-', [0b000, 0b000, 0b111, 0b000, 0b000]),
+    ('.', [0b000, 0b000, 0b000, 0b000, 0b010]),
+];
+
+impl Spectrogram {
+    ///
+    /// Draw horizontal gridlines at every multiple of `interval_hz` over an
+    /// RGBA image of the spectrogram.  The lines are placed using the same
+    /// frequency scale the image was rendered with, and are blended with the
+    /// image using the alpha channel of `colour`.
+    ///
+    /// # Arguments
+    ///
+    ///  * `img` - The RGBA image, as created by [Spectrogram::to_rgba_in_memory].
+    ///  * `freq_scale` - The frequency scale the image was rendered with.
+    ///  * `w_img` - The image width.
+    ///  * `h_img` - The image height.
+    ///  * `interval_hz` - The spacing between gridlines, in Hz.
+    ///  * `colour` - The colour of the gridlines.
+    ///
+    pub fn draw_freq_grid(
+        &self,
+        img: &mut [u8],
+        freq_scale: FrequencyScale,
+        w_img: usize,
+        h_img: usize,
+        interval_hz: f32,
+        colour: &RGBAColour,
+    ) {
+        if interval_hz <= 0.0 {
+            return;
+        }
+
+        for y in 0..h_img {
+            // The frequency at the top and bottom edge of the row
+            let f_top = self.image_row_to_freq(freq_scale, h_img, y as f32);
+            let f_bottom = self.image_row_to_freq(freq_scale, h_img, (y + 1) as f32);
+
+            // Draw the line if a multiple of the interval (but not DC) lies in this row
+            let line = (f_top / interval_hz).floor();
+            if line < 1.0 || line * interval_hz <= f_bottom {
+                continue;
+            }
+
+            let row = &mut img[y * w_img * 4..(y + 1) * w_img * 4];
+            for pixel in row.chunks_mut(4) {
+                blend_pixel(pixel, colour);
+            }
+        }
+    }
+
+    ///
+    /// Save the calculated spectrogram as a PNG image, with horizontal
+    /// gridlines every `interval_hz`.  See [Spectrogram::to_png] and
+    /// [Spectrogram::draw_freq_grid].
+    ///
+    #[cfg(feature = "png")]
+    #[allow(clippy::too_many_arguments)]
+    pub fn to_png_with_grid(
+        &self,
+        fname: &Path,
+        freq_scale: FrequencyScale,
+        gradient: &ColourGradient,
+        w_img: usize,
+        h_img: usize,
+        interval_hz: f32,
+        colour: &RGBAColour,
+    ) -> Result<(), SonogramError> {
+        let mut img = self.to_rgba_in_memory(freq_scale, gradient, w_img, h_img)?;
+        self.draw_freq_grid(&mut img, freq_scale, w_img, h_img, interval_hz, colour);
+
+        let file = File::create(fname)?;
+        write_png(
+            BufWriter::new(file),
+            &img,
+            w_img,
+            h_img,
+            png::BitDepth::Eight,
+        )?;
+
+        Ok(())
+    }
+
+    ///
+    /// Draw a small cross over an RGBA image of the spectrogram at each of the
+    /// `markers`, e.g. the peaks found by [Spectrogram::find_peaks] or the
+    /// onsets from [Spectrogram::detect_onsets].  The markers are placed using
+    /// the same frequency scale the image was rendered with, and are blended
+    /// with the image using the alpha channel of `colour`.  Markers outside
+    /// the image are skipped, and crosses at the edge are clipped.
+    ///
+    /// # Arguments
+    ///
+    ///  * `img` - The RGBA image, as created by [Spectrogram::to_rgba_in_memory].
+    ///  * `freq_scale` - The frequency scale the image was rendered with.
+    ///  * `w_img` - The image width.
+    ///  * `h_img` - The image height.
+    ///  * `markers` - The `(time_sec, freq_hz)` of each marker, the time is
+    ///    as given by [Spectrogram::column_to_time].  This is the middle of
+    ///    the window by default, rather than the start as in versions up to
+    ///    0.7, see [TimeReference](crate::TimeReference).
+    ///  * `colour` - The colour of the markers.
+    ///
+    pub fn draw_markers(
+        &self,
+        img: &mut [u8],
+        freq_scale: FrequencyScale,
+        w_img: usize,
+        h_img: usize,
+        markers: &[(f32, f32)],
+        colour: &RGBAColour,
+    ) {
+        if self.width == 0 || self.step_size == 0 {
+            return;
+        }
+
+        for &(time, freq) in markers {
+            // The middle of the time column, and the pixel holding the frequency
+            let column = self.time_to_column(time);
+            let x = (column + 0.5) * w_img as f32 / self.width as f32;
+            let y = match self.freq_to_image_row(freq_scale, h_img, freq) {
+                Some(y) => y.min(h_img as f32 - 1.0),
+                None => continue,
+            };
+            if !(0.0..w_img as f32).contains(&x) {
+                continue;
+            }
+
+            // The horizontal arm, then the vertical arm without the centre
+            let (x, y) = (x as isize, y as isize);
+            let arm = -MARKER_RADIUS..=MARKER_RADIUS;
+            let pixels = arm
+                .clone()
+                .map(|d| (x + d, y))
+                .chain(arm.filter(|&d| d != 0).map(|d| (x, y + d)));
+            for (px, py) in pixels {
+                if (0..w_img as isize).contains(&px) && (0..h_img as isize).contains(&py) {
+                    let i = (py as usize * w_img + px as usize) * 4;
+                    blend_pixel(&mut img[i..i + 4], colour);
+                }
+            }
+        }
+    }
+
+    ///
+    /// Save the calculated spectrogram as a PNG image, with a cross at each
+    /// of the `markers`.  See [Spectrogram::to_png] and
+    /// [Spectrogram::draw_markers].
+    ///
+    #[cfg(feature = "png")]
+    #[allow(clippy::too_many_arguments)]
+    pub fn to_png_with_markers(
+        &self,
+        fname: &Path,
+        freq_scale: FrequencyScale,
+        gradient: &ColourGradient,
+        w_img: usize,
+        h_img: usize,
+        markers: &[(f32, f32)],
+        colour: &RGBAColour,
+    ) -> Result<(), SonogramError> {
+        let mut img = self.to_rgba_in_memory(freq_scale, gradient, w_img, h_img)?;
+        self.draw_markers(&mut img, freq_scale, w_img, h_img, markers, colour);
+
+        let file = File::create(fname)?;
+        write_png(
+            BufWriter::new(file),
+            &img,
+            w_img,
+            h_img,
+            png::BitDepth::Eight,
+        )?;
+
+        Ok(())
+    }
+
+    ///
+    /// Render the spectrogram as an RGBA image with the colour legend in a
+    /// panel on the right.  The legend is `legend_width` pixels wide and as
+    /// high as the spectrogram, with the maximum, middle and minimum values
+    /// labelled.  The panel has a white background.
+    ///
+    /// # Arguments
+    ///
+    ///  * `freq_scale` - The type of frequency scale to use for the spectrogram.
+    ///  * `gradient` - The colour gradient to use for the spectrogram.
+    ///  * `w_img` - The width of the spectrogram in the image.
+    ///  * `h_img` - The output image height.
+    ///  * `legend_width` - The width of the legend colour bar.
+    ///
+    /// # Returns
+    ///
+    /// The image and its width, the height is `h_img`.
+    ///
+    pub fn to_rgba_with_legend(
+        &self,
+        freq_scale: FrequencyScale,
+        gradient: &ColourGradient,
+        w_img: usize,
+        h_img: usize,
+        legend_width: usize,
+    ) -> Result<(Vec<u8>, usize), SonogramError> {
+        let buf = self.to_buffer(freq_scale, w_img, h_img)?;
+        let (min, max) = get_finite_min_max(&buf).unwrap_or((0.0, 0.0));
+
+        let labels = [
+            (max, 0),
+            ((max + min) / 2.0, (h_img - 1) / 2),
+            (min, h_img - 1),
+        ]
+        .iter()
+        .map(|&(val, y)| (format!("{}", val.round() + 0.0), y)) // Adding 0.0 turns -0.0 into 0.0
+        .collect();
+        Ok(self.render_with_legend(&buf, gradient, w_img, h_img, legend_width, labels))
+    }
+
+    ///
+    /// The same as [Spectrogram::to_rgba_with_legend], but the legend is
+    /// labelled at round dB values, every `tick_db`, like Audacity.  E.g.
+    /// with a `tick_db` of 10 a spectrogram from -73 to 0 dB is labelled 0,
+    /// -10, ... -70.  If fewer than two ticks fit in the range, or they would
+    /// be closer than a pixel apart, only the maximum and minimum are
+    /// labelled.  Choose `tick_db` so the labels are well spaced for the
+    /// image height.
+    ///
+    /// # Arguments
+    ///
+    ///  * `freq_scale` - The type of frequency scale to use for the spectrogram.
+    ///  * `gradient` - The colour gradient to use for the spectrogram.
+    ///  * `w_img` - The width of the spectrogram in the image.
+    ///  * `h_img` - The output image height.
+    ///  * `legend_width` - The width of the legend colour bar.
+    ///  * `tick_db` - The interval between the labels, in dB.
+    ///
+    /// # Returns
+    ///
+    /// The image and its width, the height is `h_img`.
+    ///
+    pub fn to_rgba_with_db_legend(
+        &self,
+        freq_scale: FrequencyScale,
+        gradient: &ColourGradient,
+        w_img: usize,
+        h_img: usize,
+        legend_width: usize,
+        tick_db: f32,
+    ) -> Result<(Vec<u8>, usize), SonogramError> {
+        let buf = self.to_buffer(freq_scale, w_img, h_img)?;
+        let (min, max) = get_finite_min_max(&buf).unwrap_or((0.0, 0.0));
+
+        // The round values from the top of the legend down
+        let num_ticks = ((max / tick_db).floor() - (min / tick_db).ceil() + 1.0).max(0.0);
+        let labels = if num_ticks >= 2.0 && num_ticks <= h_img as f32 {
+            let top = (max / tick_db).floor();
+            (0..num_ticks as usize)
+                .map(|i| {
+                    let val = (top - i as f32) * tick_db;
+                    let y = ((max - val) / (max - min) * (h_img - 1) as f32).round();
+                    (format!("{}", val + 0.0), y as usize)
+                })
+                .collect()
+        } else {
+            vec![
+                (format!("{}", max.round() + 0.0), 0),
+                (format!("{}", min.round() + 0.0), h_img - 1),
+            ]
+        };
+        Ok(self.render_with_legend(&buf, gradient, w_img, h_img, legend_width, labels))
+    }
+
+    ///
+    /// Render the resized buffer with the colour legend in a panel on the
+    /// right, each label is drawn with a tick at its row of the legend.
+    ///
+    fn render_with_legend(
+        &self,
+        buf: &[f32],
+        gradient: &ColourGradient,
+        w_img: usize,
+        h_img: usize,
+        legend_width: usize,
+        labels: Vec<(String, usize)>,
+    ) -> (Vec<u8>, usize) {
+        let mut spec_img = vec![0u8; w_img * h_img * 4];
+        self.buf_to_img(buf, &mut spec_img, gradient);
+        let (min, max) = get_finite_min_max(buf).unwrap_or((0.0, 0.0));
+
+        let label_chars = labels.iter().map(|(l, _)| l.len()).max().unwrap_or(0);
+        let legend_x = w_img + LEGEND_GAP;
+        let label_x = legend_x + legend_width + TICK_LEN + FONT_SCALE;
+        let width = label_x + label_chars * CHAR_WIDTH;
+
+        // A white canvas, with the spectrogram on the left
+        let mut img = vec![255u8; width * h_img * 4];
+        for (dst, src) in img.chunks_mut(width * 4).zip(spec_img.chunks(w_img * 4)) {
+            dst[..w_img * 4].copy_from_slice(src);
+        }
+
+        // The legend, with the maximum at the top
+        let legend = gradient.to_legend_in_range(legend_width, h_img, min, max);
+        for (y, row) in legend.chunks(legend_width.max(1)).enumerate() {
+            for (x, colour) in row.iter().enumerate() {
+                let i = (y * width + legend_x + x) * 4;
+                img[i..i + 4].copy_from_slice(&[colour.r, colour.g, colour.b, colour.a]);
+            }
+        }
+
+        // The ticks and labels
+        let black = RGBAColour::new(0, 0, 0, 255);
+        let glyph_height = 5 * FONT_SCALE;
+        for (label, y) in &labels {
+            for x in legend_x + legend_width..legend_x + legend_width + TICK_LEN {
+                set_pixel(&mut img, width, x, *y, &black);
+            }
+
+            let top = y
+                .saturating_sub(glyph_height / 2)
+                .min(h_img.saturating_sub(glyph_height));
+            for (n, c) in label.chars().enumerate() {
+                draw_char(
+                    &mut img,
+                    width,
+                    h_img,
+                    label_x + n * CHAR_WIDTH,
+                    top,
+                    c,
+                    &black,
+                );
+            }
+        }
+
+        (img, width)
+    }
+
+    ///
+    /// Save the calculated spectrogram as a PNG image, with the colour legend
+    /// in a panel on the right.  The image is wider than `w_img`, to fit the
+    /// legend and its labels.  See [Spectrogram::to_png].
+    ///
+    /// # Arguments
+    ///
+    ///  * `fname` - The path to the PNG to save to the filesystem.
+    ///  * `freq_scale` - The type of frequency scale to use for the spectrogram.
+    ///  * `gradient` - The colour gradient to use for the spectrogram.
+    ///  * `w_img` - The width of the spectrogram in the image.
+    ///  * `h_img` - The output image height.
+    ///  * `legend_width` - The width of the legend colour bar.
+    ///
+    #[cfg(feature = "png")]
+    pub fn to_png_with_legend(
+        &self,
+        fname: &Path,
+        freq_scale: FrequencyScale,
+        gradient: &ColourGradient,
+        w_img: usize,
+        h_img: usize,
+        legend_width: usize,
+    ) -> Result<(), SonogramError> {
+        let (img, width) =
+            self.to_rgba_with_legend(freq_scale, gradient, w_img, h_img, legend_width)?;
+
+        let file = File::create(fname)?;
+        write_png(
+            BufWriter::new(file),
+            &img,
+            width,
+            h_img,
+            png::BitDepth::Eight,
+        )?;
+
+        Ok(())
+    }
+
+    ///
+    /// Save the calculated spectrogram as a PNG image, with the colour legend
+    /// labelled every `tick_db` in a panel on the right.  See
+    /// [Spectrogram::to_rgba_with_db_legend] and [Spectrogram::to_png].
+    ///
+    #[cfg(feature = "png")]
+    #[allow(clippy::too_many_arguments)]
+    pub fn to_png_with_db_legend(
+        &self,
+        fname: &Path,
+        freq_scale: FrequencyScale,
+        gradient: &ColourGradient,
+        w_img: usize,
+        h_img: usize,
+        legend_width: usize,
+        tick_db: f32,
+    ) -> Result<(), SonogramError> {
+        let (img, width) =
+            self.to_rgba_with_db_legend(freq_scale, gradient, w_img, h_img, legend_width, tick_db)?;
+
+        let file = File::create(fname)?;
+        write_png(
+            BufWriter::new(file),
+            &img,
+            width,
+            h_img,
+            png::BitDepth::Eight,
+        )?;
+
+        Ok(())
+    }
+}
+
+/// Set the pixel at (`x`, `y`) of an RGBA image that is `width` pixels wide.
+fn set_pixel(img: &mut [u8], width: usize, x: usize, y: usize, colour: &RGBAColour) {
+    let i = (y * width + x) * 4;
+    img[i..i + 4].copy_from_slice(&[colour.r, colour.g, colour.b, colour.a]);
+}
+
+/// Draw a character of the legend font with its top left corner at (`x`, `y`).
+fn draw_char(
+    img: &mut [u8],
+    width: usize,
+    height: usize,
+    x: usize,
+    y: usize,
+    c: char,
+    colour: &RGBAColour,
+) {
+    let glyph = match GLYPHS.iter().find(|(g, _)| *g == c) {
+        Some((_, glyph)) => glyph,
+        None => return,
+    };
+
+    for (row, bits) in glyph.iter().enumerate() {
+        for col in 0..3 {
+            if bits & (0b100 >> col) == 0 {
+                continue;
+            }
+            for dy in 0..FONT_SCALE {
+                for dx in 0..FONT_SCALE {
+                    let (px, py) = (x + col * FONT_SCALE + dx, y + row * FONT_SCALE + dy);
+                    if px < width && py < height {
+                        set_pixel(img, width, px, py, colour);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Alpha blend `colour` over the RGBA `pixel`.
+fn blend_pixel(pixel: &mut [u8], colour: &RGBAColour) {
+    let bg = RGBAColour::new(pixel[0], pixel[1], pixel[2], pixel[3]);
+    let out = colour.blend_over(&bg);
+    pixel.copy_from_slice(&[out.r, out.g, out.b, out.a]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_spectrogram, TimeReference};
+
+    #[test]
+    fn test_to_rgba_with_legend() {
+        let spec = Spectrogram {
+            is_db: true,
+            ..test_spectrogram(vec![0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0], 2, 4)
+        };
+        let gradient = ColourGradient::black_white_theme();
+
+        let (img, width) = spec
+            .to_rgba_with_legend(FrequencyScale::Linear, &gradient, 4, 20, 6)
+            .unwrap();
+
+        // The spectrogram, a gap, the legend, the tick and the labels
+        let label_x = 4 + LEGEND_GAP + 6 + TICK_LEN + FONT_SCALE;
+        assert!(width > label_x);
+        assert_eq!((width - label_x) % CHAR_WIDTH, 0);
+        assert_eq!(img.len(), width * 20 * 4);
+
+        // The spectrogram is unchanged, the legend is white at the top (the
+        // maximum) and black at the bottom
+        let spec_img = spec
+            .to_rgba_in_memory(FrequencyScale::Linear, &gradient, 4, 20)
+            .unwrap();
+        assert_eq!(img[..16], spec_img[..16]);
+        let pixel = |x: usize, y: usize| img[(y * width + x) * 4..(y * width + x + 1) * 4].to_vec();
+        assert_eq!(pixel(4 + LEGEND_GAP, 0), vec![255, 255, 255, 255]);
+        assert_eq!(pixel(4 + LEGEND_GAP + 5, 19), vec![0, 0, 0, 255]);
+
+        // The ticks
+        assert_eq!(pixel(4 + LEGEND_GAP + 6, 0), vec![0, 0, 0, 255]);
+        assert_eq!(pixel(4 + LEGEND_GAP + 6, 5), vec![255, 255, 255, 255]);
+    }
+
+    #[test]
+    fn test_to_rgba_with_db_legend() {
+        // From -73 dB to 0 dB, over 74 rows so each row is 1 dB
+        let spec = Spectrogram {
+            is_db: true,
+            ..test_spectrogram((0..74).map(|i| -(i as f32)).collect(), 1, 74)
+        };
+        let gradient = ColourGradient::black_white_theme();
+        let (w_img, h_img, legend_width) = (4, 74, 6);
+        let tick_x = w_img + LEGEND_GAP + legend_width;
+        let ticks = |img: &[u8], width: usize| -> Vec<usize> {
+            (0..h_img)
+                .filter(|y| img[(y * width + tick_x) * 4] == 0)
+                .collect()
+        };
+
+        // A tick every 10 dB, the widest label is "-70"
+        let (img, width) = spec
+            .to_rgba_with_db_legend(
+                FrequencyScale::Linear,
+                &gradient,
+                w_img,
+                h_img,
+                legend_width,
+                10.0,
+            )
+            .unwrap();
+        assert_eq!(ticks(&img, width), vec![0, 10, 20, 30, 40, 50, 60, 70]);
+        assert_eq!(width, tick_x + TICK_LEN + FONT_SCALE + 3 * CHAR_WIDTH);
+
+        // Too few round values in the range, fall back to the maximum and minimum
+        let (img, width) = spec
+            .to_rgba_with_db_legend(
+                FrequencyScale::Linear,
+                &gradient,
+                w_img,
+                h_img,
+                legend_width,
+                80.0,
+            )
+            .unwrap();
+        assert_eq!(ticks(&img, width), vec![0, 73]);
+    }
+
+    #[test]
+    fn test_draw_markers() {
+        // 4000 Hz nyquist over 8 rows, and 4 columns 2 ms apart
+        let spec = Spectrogram {
+            time_reference: TimeReference::Start,
+            ..test_spectrogram(vec![1.0; 32], 4, 8)
+        };
+        let white = RGBAColour::new(255, 255, 255, 255);
+        let (w_img, h_img) = (40, 16);
+        let lit = |img: &[u8]| -> Vec<(usize, usize)> {
+            (0..w_img * h_img)
+                .filter(|i| img[i * 4] == 255)
+                .map(|i| (i % w_img, i / w_img))
+                .collect()
+        };
+
+        // The third column at 1300 Hz is centred on pixel (25, 10), 250 Hz
+        // per pixel row
+        let mut img = vec![0u8; w_img * h_img * 4];
+        spec.draw_markers(
+            &mut img,
+            FrequencyScale::Linear,
+            w_img,
+            h_img,
+            &[(0.004, 1300.0)],
+            &white,
+        );
+        let pixels = lit(&img);
+        assert_eq!(pixels.len(), 13);
+        assert!(pixels.contains(&(25, 10)));
+        assert!(pixels.contains(&(22, 10)) && pixels.contains(&(28, 10)));
+        assert!(pixels.contains(&(25, 7)) && pixels.contains(&(25, 13)));
+
+        // Markers outside the image are skipped, at the edge they are clipped,
+        // only the 0 Hz marker in the bottom row at x = 5 is drawn
+        let mut img = vec![0u8; w_img * h_img * 4];
+        spec.draw_markers(
+            &mut img,
+            FrequencyScale::Linear,
+            w_img,
+            h_img,
+            &[(-0.005, 1000.0), (0.01, 1000.0), (0.0, 5000.0), (0.0, 0.0)],
+            &white,
+        );
+        let pixels = lit(&img);
+        assert_eq!(pixels.len(), 7 + 3);
+        assert!(pixels.contains(&(2, 15)) && pixels.contains(&(5, 12)));
+    }
+
+    #[test]
+    fn test_draw_freq_grid() {
+        // 4000 Hz nyquist over 8 rows, 500 Hz per row
+        let spec = test_spectrogram(vec![1.0; 8], 1, 8);
+        let white = RGBAColour::new(255, 255, 255, 255);
+
+        let mut img = vec![0u8; 8 * 4];
+        spec.draw_freq_grid(&mut img, FrequencyScale::Linear, 1, 8, 1000.0, &white);
+        let lines: Vec<usize> = (0..8).filter(|y| img[y * 4] == 255).collect();
+        assert_eq!(lines, vec![0, 2, 4, 6]);
+
+        // The log scale stretches the low frequencies, 3000 Hz and 4000 Hz
+        // fall in the same row
+        let mut img = vec![0u8; 8 * 4];
+        spec.draw_freq_grid(&mut img, FrequencyScale::Log, 1, 8, 1000.0, &white);
+        let lines: Vec<usize> = (0..8).filter(|y| img[y * 4] == 255).collect();
+        assert_eq!(lines, vec![1, 2, 4]);
+    }
+}
@@ -17,11 +17,63 @@
  * along with this program; if not, see <http://www.gnu.org/licenses/>.
  */
 
+use std::f32;
+use std::f32::consts::PI;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::{cmp::min, f32};
 
-use crate::{Spectrogram, WindowFn};
-use rustfft::{num_complex::Complex, FftPlanner};
+use crate::{rectangular, SonogramError, Spectrogram, TimeReference, WindowFn};
+use rustfft::{num_complex::Complex, Fft, FftPlanner};
+
+/// The number of times the progress is reported during a computation.
+const PROGRESS_UPDATES: usize = 100;
+
+/// Constant-Q kernel values smaller than this, relative to the peak of the
+/// kernel, are dropped to keep the kernels sparse.
+const CQT_KERNEL_THRESHOLD: f32 = 0.0054;
+
+///
+/// How the FFT output magnitudes are normalised.  Without normalisation the
+/// magnitude of a tone grows with the number of FFT bins.
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FftNormalisation {
+    None,        // The raw rustfft output, this is the default.
+    NumBins,     // Divide by `num_bins`, a tone has the same magnitude for any FFT size.
+    SqrtNumBins, // Divide by `sqrt(num_bins)`, preserves the energy (unitary FFT).
+}
+
+///
+/// How the magnitudes are converted to dB, when `db_output` is set.
+///
+///  * `PeakRelative` - `10 * log10(|X|^2) - 10 * log10(peak^2)`, where `|X|` is
+///    the (normalised) FFT magnitude and `peak` is the largest magnitude in
+///    the spectrogram.  The peak is 0 dB and the values are floored at 80 dB
+///    below it.  This is the same conversion as the rendered images, and is
+///    the default.
+///  * `Dbfs` - `20 * log10(2 * |X| / sum(w))`, where `|X|` is the raw FFT
+///    magnitude and `sum(w)` is the sum of the window function.  A full
+///    scale sine (amplitude 1.0) reads 0 dB and a half scale sine -6.02 dB,
+///    whatever the window, FFT size or [FftNormalisation].  The values are
+///    floored at -200 dB.  The DC and nyquist bins read 6 dB high, as is
+///    usual for a one-sided amplitude spectrum.
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DbConvention {
+    PeakRelative, // Relative to the peak, this is the default.
+    Dbfs,         // dB relative to a full scale sine.
+}
+
+///
+/// How the data is padded past its ends, for the last partial window and
+/// when the windows are centered.
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PaddingMode {
+    Zero,    // Pad with zeros, this is the default.
+    Reflect, // Mirror the data about the end sample, avoids a click at the boundary.
+    Edge,    // Repeat the end sample.
+}
 
 ///
 /// This contains all the initialised data.  This can then produce the spectrogram,
@@ -41,11 +93,24 @@ use rustfft::{num_complex::Complex, FftPlanner};
 /// ```
 ///
 pub struct SpecCompute {
-    num_bins: usize,     // The num of fft bins in the spectrogram.
-    data: Vec<f32>,      // The time domain data for the FFT.  Normalised to meet -1.0..1.0.
+    num_bins: usize,                         // The num of fft bins in the spectrogram.
+    data: Vec<f32>, // The time domain data for the FFT.  Normalised to meet -1.0..1.0.
+    complex_data: Option<Vec<Complex<f32>>>, // Complex (IQ) data, used instead of `data`
     window_fn: WindowFn, // The Window Function to apply to each fft window.
+    window_coeffs: Option<Vec<f32>>, // Window coefficients, used instead of `window_fn`
     step_size: usize, // The step size in the window function, must be less than the window function
-    fft_fn: Arc<dyn rustfft::Fft<f32>>,
+    fft_fn: Arc<dyn Fft<f32>>,
+    include_partial_window: bool, // Zero-pad and include the last partial window
+    center_windows: bool,         // Pad the start so the first window is centered on sample 0
+    padding_mode: PaddingMode,    // How to pad past the ends of the data
+    sample_rate: u32,             // The sample rate of the data, in Hz
+    db_output: bool,              // Convert the spectrogram to dB after computing
+    db_convention: DbConvention,  // How to convert the spectrogram to dB
+    normalisation: FftNormalisation, // How to normalise the FFT output
+    two_sided: bool,              // Keep the negative frequencies as well
+    overlap_carry: bool,          // Keep the unused samples for the next block of data
+    carry: Vec<f32>,              // The samples after the last window of the previous block
+    num_threads: usize,           // The number of threads to compute with, zero for all cores
 }
 
 impl SpecCompute {
@@ -55,23 +120,296 @@ impl SpecCompute {
     pub fn new(num_bins: usize, step_size: usize, data: Vec<f32>, window_fn: WindowFn) -> Self {
         // Compute the FFT plan
         let mut planner = FftPlanner::<f32>::new();
+        Self::with_planner(num_bins, step_size, data, window_fn, &mut planner)
+    }
+
+    ///
+    /// Create a new Spectrograph from data, using an existing [FftPlanner].
+    /// The planner caches the FFT plans it creates, so reusing one planner
+    /// across many instances with the same `num_bins` only plans the FFT once.
+    ///
+    pub fn with_planner(
+        num_bins: usize,
+        step_size: usize,
+        data: Vec<f32>,
+        window_fn: WindowFn,
+        planner: &mut FftPlanner<f32>,
+    ) -> Self {
         let fft_fn = planner.plan_fft_forward(num_bins);
+        Self::with_fft(num_bins, step_size, data, window_fn, fft_fn)
+    }
+
+    ///
+    /// Create a new Spectrograph from data, using an already planned FFT.
+    /// The plan must be a forward FFT of length `num_bins`.
+    ///
+    pub fn with_fft(
+        num_bins: usize,
+        step_size: usize,
+        data: Vec<f32>,
+        window_fn: WindowFn,
+        fft_fn: Arc<dyn Fft<f32>>,
+    ) -> Self {
+        assert_eq!(fft_fn.len(), num_bins);
 
         SpecCompute {
             num_bins,
             step_size,
             data,
+            complex_data: None,
             window_fn,
+            window_coeffs: None,
             fft_fn,
+            include_partial_window: false,
+            center_windows: false,
+            padding_mode: PaddingMode::Zero,
+            sample_rate: 11025,
+            db_output: false,
+            db_convention: DbConvention::PeakRelative,
+            normalisation: FftNormalisation::None,
+            two_sided: false,
+            overlap_carry: false,
+            carry: vec![],
+            num_threads: 1,
         }
     }
 
+    ///
+    /// The length of each window, in seconds, at the sample rate set with
+    /// [SpecCompute::set_sample_rate].
+    ///
+    pub fn window_seconds(&self) -> f32 {
+        self.num_bins as f32 / self.sample_rate as f32
+    }
+
+    ///
+    /// Change the step size, in samples, between the windows, e.g. to try
+    /// another hop while tuning the parameters.  The next compute uses the new
+    /// step, without reloading or re-processing the data.  The number of FFT
+    /// bins can't be changed like this, the FFT plan depends on it, so a new
+    /// [SpecCompute] is needed for a different window length.
+    ///
+    /// # Arguments
+    ///
+    ///  * `step_size` - The number of samples between the start of each
+    ///    window, [SonogramError::InvalidStepSize] is returned if it is zero.
+    ///
+    pub fn set_step_size(&mut self, step_size: usize) -> Result<(), SonogramError> {
+        if step_size == 0 {
+            return Err(SonogramError::InvalidStepSize);
+        }
+        self.step_size = step_size;
+        Ok(())
+    }
+
+    ///
+    /// Window the data with the given coefficients, rather than the window
+    /// function, e.g. for a custom window shape.  Each sample of a window is
+    /// multiplied by the coefficient at the same index.  The constant-Q
+    /// transform still uses the window function, as its kernels are not
+    /// `num_bins` long.
+    ///
+    /// # Arguments
+    ///
+    ///  * `coeffs` - The window coefficients, there must be `num_bins` of
+    ///    them, otherwise [SonogramError::InvalidWindowLength] is returned.
+    ///
+    pub fn set_window_coeffs(&mut self, coeffs: Vec<f32>) -> Result<(), SonogramError> {
+        if coeffs.len() != self.num_bins {
+            return Err(SonogramError::InvalidWindowLength);
+        }
+        self.window_coeffs = Some(coeffs);
+        Ok(())
+    }
+
+    ///
+    /// Compute the spectrogram with `num_threads` threads, or one thread per
+    /// core when `num_threads` is zero.  The default is one thread.
+    ///
+    /// rustfft computes each transform on a single thread, so rather than
+    /// splitting up one FFT the windows are shared out between the threads,
+    /// each with its own buffers and the same FFT plan.  This scales with
+    /// the number of cores for any `num_bins`, as there are always many more
+    /// windows than threads.
+    ///
+    /// The threads are a rayon thread pool, so this needs the `rayon`
+    /// feature.  Without it the spectrogram is computed on the calling
+    /// thread.
+    ///
+    pub fn set_num_threads(&mut self, num_threads: usize) {
+        self.num_threads = num_threads;
+    }
+
+    ///
+    /// The number of threads to use, resolving zero to the number of cores.
+    ///
+    #[cfg(feature = "rayon")]
+    fn threads(&self) -> usize {
+        match self.num_threads {
+            0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+            n => n,
+        }
+    }
+
+    ///
+    /// By default only the positive frequencies are kept, and the spectrogram
+    /// has `num_bins / 2` rows.  When `two_sided` is true all `num_bins` bins
+    /// are kept, which is needed for complex (IQ) input.  The bins are
+    /// shifted so that DC is in the centre: the first row is the highest
+    /// positive frequency, DC is row `num_bins - num_bins / 2 - 1`, and the
+    /// last row is the most negative frequency, `-num_bins / 2`.
+    ///
+    pub fn set_two_sided(&mut self, two_sided: bool) {
+        self.two_sided = two_sided;
+    }
+
+    ///
+    /// Set how the FFT output magnitudes are normalised, by default they are
+    /// not normalised.  See [FftNormalisation].
+    ///
+    pub fn set_fft_normalisation(&mut self, normalisation: FftNormalisation) {
+        self.normalisation = normalisation;
+    }
+
+    ///
+    /// By default the computed spectrogram holds the linear magnitude of
+    /// each bin, and the conversion to dB happens when it is rendered.  When
+    /// `db_output` is true the values are converted to dB as soon as they
+    /// are computed, so the raw data, the CSV and the PNG output all use the
+    /// same dB values.
+    ///
+    pub fn set_db_output(&mut self, db_output: bool) {
+        self.db_output = db_output;
+    }
+
+    ///
+    /// Select the dB convention used when `db_output` is set, see
+    /// [DbConvention] for the formulas.  The default is
+    /// [DbConvention::PeakRelative].
+    ///
+    pub fn set_db_convention(&mut self, convention: DbConvention) {
+        self.db_convention = convention;
+    }
+
+    ///
+    /// Set the sample rate, in Hz, of the data.  This is only used to map the
+    /// spectrogram bins to frequencies, by default it is 11025 Hz.
+    ///
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+    }
+
+    ///
+    /// By default the trailing samples that do not fill a complete window are
+    /// dropped.  When `include` is true the tail is zero-padded so that the last
+    /// partial window is included in the spectrogram, and every sample is
+    /// covered by at least one window.  This adds one column, unless the data
+    /// ends exactly on a window boundary and there is no partial window.
+    ///
+    pub fn set_include_partial_window(&mut self, include: bool) {
+        self.include_partial_window = include;
+    }
+
+    ///
+    /// When `center` is true the data is padded with `num_bins / 2` zeros at
+    /// both ends, so the first window is centered on the first sample and
+    /// each column is centered on `column * step_size`.  This matches the
+    /// `center=True` default of librosa.  The spectrogram gets about
+    /// `num_bins / step_size` more columns.
+    ///
+    pub fn set_center_windows(&mut self, center: bool) {
+        self.center_windows = center;
+    }
+
+    ///
+    /// Set how the data is padded past its ends, for the last partial window,
+    /// see [SpecCompute::set_include_partial_window], and when the windows
+    /// are centered.  The default is [PaddingMode::Zero].
+    ///
+    pub fn set_padding_mode(&mut self, mode: PaddingMode) {
+        self.padding_mode = mode;
+    }
+
+    ///
+    /// Compute a spectrogram from frames that are already windowed.  Each frame
+    /// becomes one column of the spectrogram, no window function is applied.
+    ///
+    /// # Arguments
+    ///
+    ///  * `frames` - The frames to transform, each must be `num_bins` long.
+    ///  * `num_bins` - Number of bins in the discrete fourier transform (FFT).
+    ///
+    pub fn from_frames(
+        frames: Vec<Vec<f32>>,
+        num_bins: usize,
+    ) -> Result<Spectrogram, SonogramError> {
+        if num_bins == 0 || frames.iter().any(|frame| frame.len() != num_bins) {
+            return Err(SonogramError::InvalidFrameLength);
+        }
+
+        // Back-to-back frames with no overlap, so every frame is one window
+        let mut spec_compute = SpecCompute::new(num_bins, num_bins, frames.concat(), rectangular);
+        spec_compute.set_include_partial_window(true);
+
+        Ok(spec_compute.compute())
+    }
+
     ///
     /// Update the sample data with a new set.  Note, none of the settings
     /// from the builder are applied, all the samples are used in their raw form.
+    /// With [SpecCompute::set_overlap_carry] the samples left over from the
+    /// previous block are put in front of the new data.
     ///
     pub fn set_data(&mut self, data: Vec<f32>) {
-        self.data = data;
+        self.data = if self.overlap_carry {
+            let mut carried = std::mem::take(&mut self.carry);
+            carried.extend(data);
+            carried
+        } else {
+            data
+        };
+        self.complex_data = None;
+    }
+
+    ///
+    /// Get the samples that will be transformed, after the builder has
+    /// downsampled, filtered, normalised and scaled them, e.g. to write them
+    /// out as raw PCM with [f32::to_le_bytes] and listen to the result.  With
+    /// [SpecCompute::set_overlap_carry] these include the samples carried
+    /// over from the previous block.  The slice is empty for complex data.
+    ///
+    pub fn export_processed_samples(&self) -> &[f32] {
+        &self.data
+    }
+
+    ///
+    /// Compute a long recording in blocks, with bounded memory.  After each
+    /// `compute` the samples that were not yet used by a window, at least
+    /// `num_bins - step_size` of them, are kept and put in front of the next
+    /// block given to [SpecCompute::set_data].  The windows then continue
+    /// across the block boundary exactly as if the data was in one piece, so
+    /// the results can be joined with [Spectrogram::append] without a seam.
+    ///
+    /// Only real data is carried over.  Use this with
+    /// [center_windows](SpecCompute::set_center_windows) and
+    /// [include_partial_window](SpecCompute::set_include_partial_window) both
+    /// off, and convert to dB after appending, so every block has the same
+    /// reference.
+    ///
+    pub fn set_overlap_carry(&mut self, overlap_carry: bool) {
+        self.overlap_carry = overlap_carry;
+        self.carry.clear();
+    }
+
+    ///
+    /// Update the sample data with a new set of complex (IQ) samples.  These
+    /// are transformed directly, rather than treating each sample as a real
+    /// value.  You probably want to use [SpecCompute::set_two_sided] with
+    /// complex data.
+    ///
+    pub fn set_complex_data(&mut self, data: Vec<Complex<f32>>) {
+        self.data = vec![];
+        self.complex_data = Some(data);
     }
 
     ///
@@ -80,57 +418,1405 @@ impl SpecCompute {
     /// # Arguments
     ///
     ///  * `n_fft` - How many fourier transform frequency bins to use. Must be a
-    ///                 power of 2.
+    ///    power of 2.
     ///
     pub fn compute(&mut self) -> Spectrogram {
-        let width = (self.data.len() - self.num_bins) / self.step_size;
-        let height = self.num_bins / 2;
+        self.compute_with_progress(|_| {})
+    }
+
+    ///
+    /// Do the discrete fourier transform to create the spectrogram, the same
+    /// as [SpecCompute::compute], reporting the progress as it goes.
+    ///
+    /// # Arguments
+    ///
+    ///  * `on_progress` - Called with the fraction (0.0 to 1.0) of the
+    ///    columns that have been computed.  It is called at most about 100
+    ///    times, and always once with 1.0 when the computation is done.
+    ///
+    pub fn compute_with_progress(&mut self, on_progress: impl FnMut(f32)) -> Spectrogram {
+        self.compute_inner(on_progress, None)
+            .expect("the computation has no cancel flag")
+    }
 
-        let mut spec = vec![0.0; self.num_bins * width];
+    ///
+    /// Do the discrete fourier transform to create the spectrogram, the same
+    /// as [SpecCompute::compute], unless it is cancelled.  The `cancel` flag
+    /// is checked before each column is computed, typically it is shared as
+    /// an `Arc<AtomicBool>` with another thread that sets it to `true` to
+    /// abort the computation.
+    ///
+    /// # Arguments
+    ///
+    ///  * `cancel` - Stop the computation when this is `true`.
+    ///
+    /// # Returns
+    ///
+    /// The spectrogram, or [SonogramError::Cancelled] if it was cancelled.
+    ///
+    pub fn compute_cancellable(
+        &mut self,
+        cancel: &AtomicBool,
+    ) -> Result<Spectrogram, SonogramError> {
+        self.compute_inner(|_| {}, Some(cancel))
+            .ok_or(SonogramError::Cancelled)
+    }
 
-        let mut p = 0; // Index to the beginning of the window
+    ///
+    /// The spectrogram computation, returns `None` if it was cancelled.
+    ///
+    fn compute_inner(
+        &mut self,
+        mut on_progress: impl FnMut(f32),
+        cancel: Option<&AtomicBool>,
+    ) -> Option<Spectrogram> {
+        let width = self.num_windows();
+        let progress_interval = usize::max(width / PROGRESS_UPDATES, 1);
+        let height = self.height();
 
-        // Once, Allocate buffers that will be used for computation
-        let mut inplace_buf: Vec<Complex<f32>> = vec![Complex::new(0., 0.); self.num_bins];
-        let mut scratch_buf: Vec<Complex<f32>> =
-            vec![Complex::new(0., 0.); self.fft_fn.get_inplace_scratch_len()];
+        let mut spec = vec![0.0; height * width];
 
-        // Create slices into the buffers backing the Vecs to be reused on each loop
-        let inplace_slice = &mut inplace_buf[..];
-        let scratch_slice = &mut scratch_buf[..];
+        let norm = self.fft_norm();
 
-        for w in 0..width {
-            // Extract the next `num_bins` complex floats into the FFT inplace compute buffer
-            self.data[p..]
-                .iter()
-                .take(self.num_bins)
-                .enumerate()
-                .map(|(i, val)| val * (self.window_fn)(i, self.num_bins)) // Apply the window function
-                .map(|val| Complex::new(val, 0.0))
-                .zip(inplace_slice.iter_mut())
-                .for_each(|(c, v)| *v = c);
+        // The windows are computed in blocks, with the progress reported
+        // after each block
+        let mut done = 0;
+        self.compute_blocks(
+            (width, height),
+            progress_interval,
+            norm,
+            cancel,
+            |block_start, block| {
+                if cancel.is_some_and(|cancel| cancel.load(Ordering::Relaxed)) {
+                    return;
+                }
+
+                // Copy the columns to the output, the highest frequency is the first row
+                for (i, column) in block.chunks_exact(height).enumerate() {
+                    spec[block_start + i..]
+                        .iter_mut()
+                        .step_by(width)
+                        .zip(column)
+                        .for_each(|(a, b)| *a = *b);
+                }
+
+                done += block.len() / height;
+                if done < width {
+                    on_progress(done as f32 / width as f32);
+                }
+            },
+        );
+        if cancel.is_some_and(|cancel| cancel.load(Ordering::Relaxed)) {
+            return None;
+        }
+        on_progress(1.0);
+
+        let p = width * self.step_size; // Index to the beginning of the next window
+        if self.overlap_carry && self.complex_data.is_none() {
+            // Keep the samples from the start of the next window
+            let next = usize::min(p.saturating_sub(self.padding()), self.data.len());
+            self.carry = self.data[next..].to_vec();
+        }
 
-            // Call out to rustfft to actually compute the FFT
-            // This will take the inplace_slice as input, use scratch_slice during computation, and write FFT back into inplace_slice
-            let inplace = &mut inplace_slice[..min(self.num_bins, self.data.len() - p)];
-            self.fft_fn.process_with_scratch(inplace, scratch_slice);
+        let mut spectrogram = Spectrogram {
+            spec,
+            width,
+            height,
+            sample_rate: self.sample_rate,
+            step_size: self.step_size,
+            is_db: false,
+            two_sided: self.two_sided,
+            window_len: self.num_bins,
+            window_padding: self.padding(),
+            time_reference: TimeReference::default(),
+        };
+
+        if self.db_output {
+            self.convert_db(&mut spectrogram, 2.0 / (self.window_sum() * norm));
+        }
+
+        Some(spectrogram)
+    }
+
+    ///
+    /// Compute the spectrum of a single window of samples, without building
+    /// a [Spectrogram].  The window function and FFT normalisation are
+    /// applied the same as in [SpecCompute::compute], but the magnitudes are
+    /// always linear, `db_output` is ignored.  This is useful for a live
+    /// analyser that is fed one window at a time.
+    ///
+    /// # Arguments
+    ///
+    ///  * `samples` - The window of real samples, it must be `num_bins` long.
+    ///
+    /// # Returns
+    ///
+    /// The `num_bins / 2` magnitudes starting from DC, i.e. a spectrogram
+    /// column in reverse order.  If the output is two-sided all `num_bins`
+    /// magnitudes are returned, starting from the most negative frequency.
+    /// [SonogramError::InvalidBufferSize] is returned if `samples` is the
+    /// wrong length.
+    ///
+    pub fn compute_frame(&self, samples: &[f32]) -> Result<Vec<f32>, SonogramError> {
+        let mut frame = vec![0.0; self.height()];
+        self.compute_frame_into(samples, &mut frame, &mut Vec::new())?;
+        Ok(frame)
+    }
+
+    ///
+    /// The same as [SpecCompute::compute_frame], but the magnitudes are
+    /// written into `out` and the FFT buffers are kept in `buf`.  Reuse both
+    /// between calls to avoid allocating for every frame.
+    ///
+    /// # Arguments
+    ///
+    ///  * `samples` - The window of real samples, it must be `num_bins` long.
+    ///  * `out` - Where the magnitudes are written, it must be `num_bins / 2`
+    ///    long, or `num_bins` long if the output is two-sided.
+    ///  * `buf` - The working buffer for the FFT.  It is resized as needed, so
+    ///    it may start empty.
+    ///
+    /// # Returns
+    ///
+    /// [SonogramError::InvalidBufferSize] if `samples` or `out` is the wrong
+    /// length.
+    ///
+    pub fn compute_frame_into(
+        &self,
+        samples: &[f32],
+        out: &mut [f32],
+        buf: &mut Vec<Complex<f32>>,
+    ) -> Result<(), SonogramError> {
+        if samples.len() != self.num_bins || out.len() != self.height() {
+            return Err(SonogramError::InvalidBufferSize);
+        }
+
+        let scratch_len = self.fft_fn.get_inplace_scratch_len();
+        buf.resize(self.num_bins + scratch_len, Complex::new(0.0, 0.0));
+        let (inplace, scratch) = buf.split_at_mut(self.num_bins);
+        inplace
+            .iter_mut()
+            .zip(samples)
+            .for_each(|(v, val)| *v = Complex::new(*val, 0.0));
+        self.window_and_fft(inplace, &mut scratch[..scratch_len]);
+
+        self.magnitudes(inplace, self.fft_norm())
+            .zip(out.iter_mut())
+            .for_each(|(a, b)| *b = a);
+
+        Ok(())
+    }
+
+    ///
+    /// The number of rows in the output, `num_bins / 2`, or `num_bins` if the
+    /// output is two-sided.
+    ///
+    fn height(&self) -> usize {
+        if self.two_sided {
+            self.num_bins
+        } else {
+            self.num_bins / 2
+        }
+    }
+
+    ///
+    /// The scale applied to the FFT magnitudes for the normalisation.
+    ///
+    fn fft_norm(&self) -> f32 {
+        match self.normalisation {
+            FftNormalisation::None => 1.0,
+            FftNormalisation::NumBins => 1.0 / self.num_bins as f32,
+            FftNormalisation::SqrtNumBins => 1.0 / (self.num_bins as f32).sqrt(),
+        }
+    }
+
+    ///
+    /// The sum of the window function over `num_bins` samples.
+    ///
+    fn window_sum(&self) -> f32 {
+        (0..self.num_bins).map(|i| self.window_value(i)).sum()
+    }
+
+    ///
+    /// The window coefficient of sample `i` of each window.
+    ///
+    fn window_value(&self, i: usize) -> f32 {
+        match &self.window_coeffs {
+            Some(coeffs) => coeffs[i],
+            None => (self.window_fn)(i, self.num_bins),
+        }
+    }
+
+    ///
+    /// Convert the computed magnitudes to dB, using the [DbConvention].
+    /// `dbfs_scale` scales the magnitudes so a full scale sine is 1.0.
+    ///
+    fn convert_db(&self, spectrogram: &mut Spectrogram, dbfs_scale: f32) {
+        match self.db_convention {
+            DbConvention::PeakRelative => spectrogram.to_db_inplace(),
+            DbConvention::Dbfs => spectrogram.convert_to_dbfs(dbfs_scale),
+        }
+    }
+
+    ///
+    /// Compute all `width` windows, each a column `height` values long, in
+    /// blocks of `block_len` columns, and pass each block to `on_block` with
+    /// the index of its first column.  With the `rayon` feature and more than
+    /// one thread the blocks are computed in parallel by a pool of worker
+    /// threads, and arrive in any order.
+    ///
+    fn compute_blocks(
+        &self,
+        (width, height): (usize, usize),
+        block_len: usize,
+        norm: f32,
+        cancel: Option<&AtomicBool>,
+        mut on_block: impl FnMut(usize, &[f32]),
+    ) {
+        #[cfg(feature = "rayon")]
+        {
+            let threads = self.threads().min(width.div_ceil(block_len));
+            if threads > 1 {
+                if let Ok(pool) = rayon::ThreadPoolBuilder::new().num_threads(threads).build() {
+                    use rayon::prelude::*;
+
+                    // The workers send each block back to this thread, which
+                    // puts it in place and reports the progress
+                    let (tx, rx) = std::sync::mpsc::sync_channel(threads);
+                    pool.in_place_scope(|scope| {
+                        scope.spawn(move |_| {
+                            (0..width.div_ceil(block_len))
+                                .into_par_iter()
+                                .for_each_init(
+                                    || (self.fft_buffers(), tx.clone()),
+                                    |(bufs, tx), b| {
+                                        let first = b * block_len;
+                                        let len = usize::min(block_len, width - first);
+                                        let mut block = vec![0.0; len * height];
+                                        self.compute_windows(first, &mut block, bufs, norm, cancel);
+                                        // The receiver outlives the workers
+                                        let _ = tx.send((first, block));
+                                    },
+                                );
+                        });
+                        for (first, block) in rx {
+                            on_block(first, &block);
+                        }
+                    });
+                    return;
+                }
+            }
+        }
+
+        let mut bufs = self.fft_buffers();
+        let mut block = vec![0.0; height * usize::min(block_len, width)];
+        for first in (0..width).step_by(block_len) {
+            let len = usize::min(block_len, width - first);
+            let block = &mut block[..len * height];
+            self.compute_windows(first, block, &mut bufs, norm, cancel);
+            on_block(first, block);
+        }
+    }
+
+    ///
+    /// Allocate the buffers one thread needs for the FFT, the in-place buffer
+    /// and the scratch buffer.
+    ///
+    fn fft_buffers(&self) -> (Vec<Complex<f32>>, Vec<Complex<f32>>) {
+        (
+            vec![Complex::new(0., 0.); self.num_bins],
+            vec![Complex::new(0., 0.); self.fft_fn.get_inplace_scratch_len()],
+        )
+    }
+
+    ///
+    /// Compute the windows from `first` onward, one column of `out` for each
+    /// window.  Each column runs from the highest frequency to the lowest.
+    ///
+    fn compute_windows(
+        &self,
+        first: usize,
+        out: &mut [f32],
+        bufs: &mut (Vec<Complex<f32>>, Vec<Complex<f32>>),
+        norm: f32,
+        cancel: Option<&AtomicBool>,
+    ) {
+        let (inplace, scratch) = bufs;
+        let height = self.height();
+
+        for (w, column) in (first..).zip(out.chunks_exact_mut(height)) {
+            if cancel.is_some_and(|cancel| cancel.load(Ordering::Relaxed)) {
+                return;
+            }
+
+            self.fft_window(w, inplace, scratch);
 
             // Normalize the spectrogram and write to the output
-            inplace
-                .iter()
-                .take(height)
+            self.magnitudes(inplace, norm)
                 .rev()
-                .map(|c_val| c_val.norm())
-                .zip(spec[w..].iter_mut().step_by(width))
+                .zip(column.iter_mut())
                 .for_each(|(a, b)| *b = a);
+        }
+    }
+
+    ///
+    /// The normalised magnitudes of the FFT in `spectrum`, from the lowest
+    /// frequency to the highest.  If the output is two-sided the negative
+    /// frequencies are shifted below DC.
+    ///
+    fn magnitudes<'a>(
+        &self,
+        spectrum: &'a [Complex<f32>],
+        norm: f32,
+    ) -> impl DoubleEndedIterator<Item = f32> + 'a {
+        let (negative, positive) = if self.two_sided {
+            let (positive, negative) = spectrum.split_at(self.num_bins.div_ceil(2));
+            (negative, positive)
+        } else {
+            (&spectrum[..0], &spectrum[..self.num_bins / 2])
+        };
+        negative
+            .iter()
+            .chain(positive)
+            .map(move |c_val| c_val.norm() * norm)
+    }
 
-            p += self.step_size;
+    ///
+    /// Window the `w`th window of the data and compute its FFT, in `inplace`.
+    ///
+    fn fft_window(&self, w: usize, inplace: &mut [Complex<f32>], scratch: &mut [Complex<f32>]) {
+        self.read_window(w, inplace);
+        self.window_and_fft(inplace, scratch);
+    }
+
+    ///
+    /// Copy the samples of the `w`th window into `frame`, padding outside of
+    /// the data.  The window function is not applied.
+    ///
+    fn read_window(&self, w: usize, frame: &mut [Complex<f32>]) {
+        let start = (w * self.step_size) as isize - self.padding() as isize;
+        let mode = self.padding_mode;
+        match &self.complex_data {
+            Some(complex_data) => padded_window(complex_data, start, self.num_bins, mode)
+                .zip(frame.iter_mut())
+                .for_each(|(c, v)| *v = c),
+            None => padded_window(&self.data, start, self.num_bins, mode)
+                .map(|val| Complex::new(val, 0.0))
+                .zip(frame.iter_mut())
+                .for_each(|(c, v)| *v = c),
         }
+    }
+
+    ///
+    /// Apply the window function to the frame in `inplace` and compute its
+    /// FFT, in place.
+    ///
+    fn window_and_fft(&self, inplace: &mut [Complex<f32>], scratch: &mut [Complex<f32>]) {
+        inplace
+            .iter_mut()
+            .enumerate()
+            .for_each(|(i, val)| *val *= self.window_value(i));
 
-        Spectrogram {
+        // Call out to rustfft to actually compute the FFT
+        // This will take the inplace buffer as input, use the scratch buffer during computation, and write FFT back into inplace buffer
+        self.fft_fn.process_with_scratch(inplace, scratch);
+    }
+
+    ///
+    /// Compute the cross-spectrum between this data and the `other` data,
+    /// e.g. the two channels of a stereo recording, see [CrossSpectrum].  The
+    /// complex STFT of both is computed from the samples, with the same
+    /// windows as [SpecCompute::compute], so phase differences between the
+    /// channels are kept.
+    ///
+    /// # Arguments
+    ///
+    ///  * `other` - The second channel, `Y` in `X * conj(Y)`.
+    ///
+    /// # Returns
+    ///
+    /// The cross-spectrum, or [SonogramError::MismatchedSpectrograms] if the
+    /// two don't have the same number of bins, step size, number of windows
+    /// and sidedness.
+    ///
+    pub fn cross_spectrum(&self, other: &SpecCompute) -> Result<CrossSpectrum, SonogramError> {
+        let width = self.num_windows();
+        if self.num_bins != other.num_bins
+            || self.step_size != other.step_size
+            || self.two_sided != other.two_sided
+            || width != other.num_windows()
+        {
+            return Err(SonogramError::MismatchedSpectrograms);
+        }
+
+        // The FFT index of each row, the highest frequency is the first row
+        let n = self.num_bins;
+        let rows: Vec<usize> = if self.two_sided {
+            (n.div_ceil(2)..n).chain(0..n.div_ceil(2)).rev().collect()
+        } else {
+            (0..n / 2).rev().collect()
+        };
+        let height = rows.len();
+
+        let norm = self.fft_norm();
+        let mut x = vec![Complex::new(0.0, 0.0); n];
+        let mut y = vec![Complex::new(0.0, 0.0); n];
+        let mut scratch = vec![Complex::new(0.0, 0.0); self.fft_fn.get_inplace_scratch_len()];
+
+        let mut cross = vec![Complex::new(0.0, 0.0); height * width];
+        let mut sum_xy = vec![Complex::new(0.0, 0.0); height];
+        let mut sum_xx = vec![0.0; height];
+        let mut sum_yy = vec![0.0; height];
+        for w in 0..width {
+            self.fft_window(w, &mut x, &mut scratch);
+            other.fft_window(w, &mut y, &mut scratch);
+            for (row, &k) in rows.iter().enumerate() {
+                let xy = x[k] * y[k].conj() * norm * norm;
+                cross[row * width + w] = xy;
+                sum_xy[row] += xy;
+                sum_xx[row] += x[k].norm_sqr() * norm * norm;
+                sum_yy[row] += y[k].norm_sqr() * norm * norm;
+            }
+        }
+
+        // Index 0 is the lowest frequency, the same as `average_spectrum`
+        let coherence = (0..height)
+            .rev()
+            .map(|row| {
+                let power = sum_xx[row] * sum_yy[row];
+                if power > 0.0 {
+                    sum_xy[row].norm_sqr() / power
+                } else {
+                    0.0
+                }
+            })
+            .collect();
+
+        Ok(CrossSpectrum {
+            cross,
+            coherence,
+            width,
+            height,
+        })
+    }
+
+    ///
+    /// Compute a constant-Q transform (CQT), where the frequency bins are
+    /// spaced logarithmically with `bins_per_octave` in each octave, rather
+    /// than linearly.  This matches musical pitch, e.g. 12 bins per octave
+    /// gives one bin per semitone.  It uses the spectral kernel method of
+    /// Brown and Puckette, each frame is transformed with the `num_bins` FFT
+    /// and multiplied by a sparse kernel for each CQT bin.
+    ///
+    /// Each bin has the same Q factor, the frequency divided by the bandwidth,
+    /// so the kernels of the low bins are long.  A kernel can't be longer
+    /// than `num_bins`, so `num_bins` should be at least
+    /// `sample_rate / (f_min * (2^(1 / bins_per_octave) - 1))`, otherwise the
+    /// lowest bins are wider than their spacing.  The window function shapes
+    /// the kernels, the frames themselves are not windowed.
+    ///
+    /// The first row is the highest bin, just below the nyquist frequency.
+    /// The rows are not evenly spaced in Hz, so the frequency based analysis
+    /// functions do not apply to the result.  If `f_min` is at or above the
+    /// nyquist frequency there are no bins, the spectrogram has a height of 0.
+    ///
+    /// # Arguments
+    ///
+    ///  * `bins_per_octave` - The number of bins in each octave.
+    ///  * `f_min` - The frequency, in Hz, of the lowest bin.
+    ///
+    /// # Returns
+    ///
+    /// The spectrogram, [SonogramError::InvalidNumBins] if `bins_per_octave`
+    /// is zero, or [SonogramError::InvalidFrequency] if `f_min` is not a
+    /// positive number.
+    ///
+    pub fn compute_cqt(
+        &mut self,
+        bins_per_octave: usize,
+        f_min: f32,
+    ) -> Result<Spectrogram, SonogramError> {
+        if bins_per_octave == 0 {
+            return Err(SonogramError::InvalidNumBins);
+        }
+        if !(f_min.is_finite() && f_min > 0.0) {
+            return Err(SonogramError::InvalidFrequency);
+        }
+
+        let kernels = self.cqt_kernels(bins_per_octave, f_min);
+        let width = self.num_windows();
+        let height = kernels.len();
+        let mut spec = vec![0.0; height * width];
+
+        let mut inplace_buf = vec![Complex::new(0.0, 0.0); self.num_bins];
+        let mut scratch_buf = vec![Complex::new(0.0, 0.0); self.fft_fn.get_inplace_scratch_len()];
+
+        for w in 0..width {
+            self.read_window(w, &mut inplace_buf);
+            self.fft_fn
+                .process_with_scratch(&mut inplace_buf, &mut scratch_buf);
+
+            // The highest frequency is the first row
+            for (k, kernel) in kernels.iter().enumerate() {
+                let val: Complex<f32> = kernel.iter().map(|(j, c)| inplace_buf[*j] * c).sum();
+                spec[(height - 1 - k) * width + w] = val.norm();
+            }
+        }
+
+        let mut spectrogram = Spectrogram {
             spec,
             width,
             height,
+            sample_rate: self.sample_rate,
+            step_size: self.step_size,
+            is_db: false,
+            two_sided: false,
+            window_len: self.num_bins,
+            window_padding: self.padding(),
+            time_reference: TimeReference::default(),
+        };
+
+        // The kernels are divided by their length, so a sine reads half its
+        // amplitude times the mean of the window
+        if self.db_output {
+            let scale = 2.0 * self.num_bins as f32 / self.window_sum();
+            self.convert_db(&mut spectrogram, scale);
+        }
+
+        Ok(spectrogram)
+    }
+
+    ///
+    /// Compute a reassigned spectrogram, which is much sharper than the plain
+    /// magnitude spectrogram for chirps and partials.  As well as the STFT
+    /// with the window `h`, the STFTs with the time weighted window `t·h` and
+    /// the derivative of the window `dh/dt` are computed.  These estimate the
+    /// group delay and instantaneous frequency of each cell, and its energy is
+    /// moved to that time and frequency.  The value of each cell is the square
+    /// root of the energy moved there, so it is comparable with the
+    /// magnitudes of [SpecCompute::compute].
+    ///
+    /// Only the positive frequencies are computed, the result has
+    /// `num_bins / 2` rows.  A smooth window, e.g. [crate::hann_function],
+    /// should be used as its derivative is estimated numerically.
+    ///
+    pub fn compute_reassigned(&mut self) -> Spectrogram {
+        let n = self.num_bins;
+        let width = self.num_windows();
+        let height = n / 2;
+        let mut energy = vec![0.0; height * width];
+
+        // The window, the time weighted window and the window derivative
+        let window: Vec<f32> = (0..n).map(|i| self.window_value(i)).collect();
+        let center = (n - 1) as f32 / 2.0;
+        let time_window: Vec<f32> = window
+            .iter()
+            .enumerate()
+            .map(|(i, h)| (i as f32 - center) * h)
+            .collect();
+        let deriv_window: Vec<f32> = (0..n)
+            .map(|i| {
+                let prev = if i > 0 { window[i - 1] } else { 0.0 };
+                let next = window.get(i + 1).copied().unwrap_or(0.0);
+                (next - prev) / 2.0
+            })
+            .collect();
+
+        let norm = self.fft_norm();
+
+        let mut frame = vec![Complex::new(0.0, 0.0); n];
+        let mut bufs = vec![vec![Complex::new(0.0, 0.0); n]; 3];
+        let mut scratch = vec![Complex::new(0.0, 0.0); self.fft_fn.get_inplace_scratch_len()];
+
+        for w in 0..width {
+            self.read_window(w, &mut frame);
+            for (buf, win) in bufs.iter_mut().zip([&window, &time_window, &deriv_window]) {
+                for ((b, x), h) in buf.iter_mut().zip(&frame).zip(win.iter()) {
+                    *b = x * h;
+                }
+                self.fft_fn.process_with_scratch(buf, &mut scratch);
+            }
+
+            let cells = bufs[0].iter().zip(&bufs[1]).zip(&bufs[2]).take(height);
+            for (k, ((xh, xth), xdh)) in cells.enumerate() {
+                let power = xh.norm_sqr();
+                if power <= f32::MIN_POSITIVE {
+                    continue;
+                }
+
+                // The group delay, in samples, and the frequency offset, in
+                // radians per sample
+                let delay = (xth * xh.conj()).re / power;
+                let offset = -(xdh * xh.conj()).im / power;
+
+                let col = (w as f32 + delay / self.step_size as f32).round();
+                let bin = (k as f32 + offset * n as f32 / (2.0 * PI)).round();
+                if col >= 0.0 && col < width as f32 && bin >= 0.0 && bin < height as f32 {
+                    // The highest frequency is the first row
+                    let row = height - 1 - bin as usize;
+                    energy[row * width + col as usize] += power * norm * norm;
+                }
+            }
+        }
+
+        let mut spectrogram = Spectrogram {
+            spec: energy.iter().map(|e| e.sqrt()).collect(),
+            width,
+            height,
+            sample_rate: self.sample_rate,
+            step_size: self.step_size,
+            is_db: false,
+            two_sided: false,
+            window_len: self.num_bins,
+            window_padding: self.padding(),
+            time_reference: TimeReference::default(),
+        };
+
+        if self.db_output {
+            self.convert_db(&mut spectrogram, 2.0 / (self.window_sum() * norm));
         }
+
+        spectrogram
+    }
+
+    ///
+    /// The sparse spectral kernels of the constant-Q transform, from the
+    /// lowest frequency up.  Each kernel is the conjugate FFT of a windowed
+    /// complex sinusoid, centered in the frame, as `(fft bin, value)` pairs.
+    ///
+    fn cqt_kernels(&self, bins_per_octave: usize, f_min: f32) -> Vec<Vec<(usize, Complex<f32>)>> {
+        let sample_rate = self.sample_rate as f32;
+        let q = 1.0 / (2.0_f32.powf(1.0 / bins_per_octave as f32) - 1.0);
+
+        let mut buf = vec![Complex::new(0.0, 0.0); self.num_bins];
+        let mut scratch = vec![Complex::new(0.0, 0.0); self.fft_fn.get_inplace_scratch_len()];
+
+        (0..)
+            .map(|k| f_min * 2.0_f32.powf(k as f32 / bins_per_octave as f32))
+            .take_while(|&freq| freq < sample_rate / 2.0)
+            .map(|freq| {
+                let len = ((q * sample_rate / freq).ceil() as usize).clamp(1, self.num_bins);
+                let offset = (self.num_bins - len) / 2;
+
+                buf.fill(Complex::new(0.0, 0.0));
+                for n in 0..len {
+                    let phase = 2.0 * PI * freq * n as f32 / sample_rate;
+                    let amplitude = (self.window_fn)(n, len) / len as f32;
+                    buf[offset + n] = Complex::from_polar(amplitude, phase);
+                }
+                self.fft_fn.process_with_scratch(&mut buf, &mut scratch);
+
+                let max = buf.iter().map(|c| c.norm()).fold(0.0, f32::max);
+                buf.iter()
+                    .enumerate()
+                    .filter(|(_, c)| c.norm() > max * CQT_KERNEL_THRESHOLD)
+                    .map(|(j, c)| (j, c.conj() / self.num_bins as f32))
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// The number of windows (spectrogram columns) that fit in the data.
+    fn num_windows(&self) -> usize {
+        let len = match &self.complex_data {
+            Some(complex_data) => complex_data.len(),
+            None => self.data.len(),
+        };
+        if len == 0 {
+            return 0;
+        }
+        let len = len + 2 * self.padding();
+        if len < self.num_bins {
+            // There is only a partial window
+            return usize::from(self.include_partial_window);
+        }
+
+        // The complete windows, then the partial window if the data doesn't
+        // end on a window boundary
+        let complete = (len - self.num_bins) / self.step_size + 1;
+        let partial = !(len - self.num_bins).is_multiple_of(self.step_size);
+        complete + usize::from(self.include_partial_window && partial)
+    }
+
+    /// The number of samples padded before the start of the data.
+    fn padding(&self) -> usize {
+        if self.center_windows {
+            self.num_bins / 2
+        } else {
+            0
+        }
+    }
+}
+
+///
+/// The cross-spectrum of two channels, from [SpecCompute::cross_spectrum].
+/// It holds the cross-spectrum `S_xy = X * conj(Y)` of each cell, and the
+/// magnitude-squared coherence of each frequency bin over all the windows,
+/// `|sum(S_xy)|^2 / (sum(|X|^2) * sum(|Y|^2))`.  The coherence is 1.0 where
+/// the channels are linearly related, e.g. the same signal with a different
+/// gain or delay, and near 0.0 where they are independent.  With `K`
+/// windows independent noise gives a coherence of about `1 / K`, and a
+/// single window always gives 1.0, so it needs many windows to mean
+/// anything.
+///
+pub struct CrossSpectrum {
+    cross: Vec<Complex<f32>>, // The cross-spectrum of each cell, the same layout as the spectrogram
+    coherence: Vec<f32>,      // The coherence of each frequency bin, from DC up
+    width: usize,             // The number of windows
+    height: usize,            // The number of frequency bins
+}
+
+impl CrossSpectrum {
+    ///
+    /// The cross-spectrum of each cell, in the same layout as the
+    /// [Spectrogram], `height` rows of `width` values, the highest frequency
+    /// first.  The magnitude is the product of the two magnitudes and the
+    /// argument is the phase of `X` relative to `Y`.
+    ///
+    pub fn cross(&self) -> &[Complex<f32>] {
+        &self.cross
+    }
+
+    ///
+    /// The magnitude-squared coherence of each frequency bin, from 0.0 to
+    /// 1.0.  Index 0 is DC, or the most negative frequency if two-sided, the
+    /// same as [Spectrogram::average_spectrum].
+    ///
+    pub fn coherence(&self) -> &[f32] {
+        &self.coherence
+    }
+
+    /// The number of windows (columns).
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// The number of frequency bins (rows).
+    pub fn height(&self) -> usize {
+        self.height
+    }
+}
+
+///
+/// The spectrogram computation for each channel of multichannel data, see
+/// [crate::SpecOptionsBuilder::all_channels].  The channels share one FFT plan.
+///
+pub struct MultiSpecCompute {
+    channels: Vec<SpecCompute>,
+}
+
+impl MultiSpecCompute {
+    pub(crate) fn new(channels: Vec<SpecCompute>) -> Self {
+        MultiSpecCompute { channels }
+    }
+
+    ///
+    /// The number of channels.
+    ///
+    pub fn num_channels(&self) -> usize {
+        self.channels.len()
+    }
+
+    ///
+    /// The computation for each channel, e.g. to change the settings of one
+    /// channel.
+    ///
+    pub fn channels_mut(&mut self) -> &mut [SpecCompute] {
+        &mut self.channels
+    }
+
+    ///
+    /// Compute the spectrogram of each channel, in channel order.
+    ///
+    pub fn compute(&mut self) -> Vec<Spectrogram> {
+        self.channels.iter_mut().map(SpecCompute::compute).collect()
+    }
+
+    ///
+    /// Compute the cross-spectrum and coherence between two channels, see
+    /// [SpecCompute::cross_spectrum].
+    ///
+    /// # Arguments
+    ///
+    ///  * `x` - The index of the first channel, from 0.
+    ///  * `y` - The index of the second channel, from 0.
+    ///
+    pub fn cross_spectrum(&self, x: usize, y: usize) -> Result<CrossSpectrum, SonogramError> {
+        match (self.channels.get(x), self.channels.get(y)) {
+            (Some(x), Some(y)) => x.cross_spectrum(y),
+            _ => Err(SonogramError::InvalidChannel),
+        }
+    }
+}
+
+///
+/// Get `len` samples of the data starting at `start`, which may be before the
+/// start of the data.  Any samples outside of the data are padded using the
+/// padding `mode`.
+///
+fn padded_window<T: Copy + Default>(
+    data: &[T],
+    start: isize,
+    len: usize,
+    mode: PaddingMode,
+) -> impl Iterator<Item = T> + '_ {
+    let last = data.len() as isize - 1;
+    (start..start + len as isize).map(move |i| {
+        if (0..=last).contains(&i) {
+            return data[i as usize];
+        }
+        match mode {
+            _ if data.is_empty() => T::default(),
+            PaddingMode::Zero => T::default(),
+            PaddingMode::Edge => data[i.clamp(0, last) as usize],
+            PaddingMode::Reflect if last == 0 => data[0],
+            PaddingMode::Reflect => {
+                // Reflecting about both ends repeats every `2 * last` samples
+                let i = i.rem_euclid(2 * last);
+                data[(last - (last - i).abs()) as usize]
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{hann_function, test_spectrogram, FrequencyScale};
+
+    #[test]
+    fn test_compute_cqt() {
+        // A chromatic scale, 12 semitones up from A3
+        let note_len = 4096;
+        let data: Vec<f32> = (0..12 * note_len)
+            .map(|i| {
+                let freq = 220.0 * 2.0_f32.powf((i / note_len) as f32 / 12.0);
+                (2.0 * PI * freq * i as f32 / 8000.0).sin()
+            })
+            .collect();
+        let mut spec_compute = SpecCompute::new(2048, 2048, data, hann_function);
+        spec_compute.set_sample_rate(8000);
+        let spec = spec_compute.compute_cqt(12, 110.0).unwrap();
+
+        // From 110 Hz up to 4000 Hz
+        assert_eq!(spec.height, 63);
+
+        // The first window of each note is entirely within the note, the
+        // peak moves up one row for each semitone
+        for note in 0..12 {
+            let w = note * 2;
+            let (row, _) = (0..spec.height)
+                .map(|row| (row, spec.spec[row * spec.width + w]))
+                .fold((0, 0.0), |max, x| if x.1 > max.1 { x } else { max });
+            assert_eq!(spec.height - 1 - row, 12 + note);
+        }
+
+        // Nothing below the nyquist frequency
+        let spec = spec_compute.compute_cqt(12, 4000.0).unwrap();
+        assert_eq!(spec.height, 0);
+
+        assert!(matches!(
+            spec_compute.compute_cqt(0, 110.0),
+            Err(SonogramError::InvalidNumBins)
+        ));
+        for f_min in [0.0, -1.0, f32::NAN] {
+            assert!(matches!(
+                spec_compute.compute_cqt(12, f_min),
+                Err(SonogramError::InvalidFrequency)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_compute_reassigned() {
+        // A linear chirp from 500 Hz to 3500 Hz
+        let (sample_rate, len) = (8000.0, 16384);
+        let duration = len as f32 / sample_rate;
+        let data: Vec<f32> = (0..len)
+            .map(|i| {
+                let t = i as f32 / sample_rate;
+                let phase = 500.0 * t + 3000.0 / (2.0 * duration) * t * t;
+                (2.0 * PI * phase).sin()
+            })
+            .collect();
+        let mut spec_compute = SpecCompute::new(512, 64, data, hann_function);
+        let standard = spec_compute.compute();
+        let reassigned = spec_compute.compute_reassigned();
+        assert_eq!(reassigned.width, standard.width);
+        assert_eq!(reassigned.height, standard.height);
+
+        // The ridge is much thinner, count the rows of the middle column that
+        // hold 99% of its energy
+        let ridge_width = |spec: &Spectrogram| {
+            let w = spec.width / 2;
+            let mut power: Vec<f32> = (0..spec.height)
+                .map(|row| spec.spec[row * spec.width + w].powi(2))
+                .collect();
+            power.sort_by(|a, b| b.partial_cmp(a).unwrap());
+            let total: f32 = power.iter().sum();
+            let mut sum = 0.0;
+            power
+                .iter()
+                .take_while(|p| {
+                    sum += *p;
+                    sum - *p < 0.99 * total
+                })
+                .count()
+        };
+        assert!(ridge_width(&reassigned) * 2 < ridge_width(&standard));
+
+        // The energy is moved, not created
+        let energy = |spec: &Spectrogram| spec.spec.iter().map(|v| v * v).sum::<f32>();
+        assert!((energy(&reassigned) - energy(&standard)).abs() / energy(&standard) < 0.05);
+    }
+
+    #[test]
+    fn test_include_partial_window() {
+        // 1000 samples is not a multiple of the 64 sample step
+        let data: Vec<f32> = (0..1000).map(|i| (i as f32 * 0.3).sin()).collect();
+
+        let mut spec_compute = SpecCompute::new(128, 64, data, rectangular);
+        let dropped = spec_compute.compute();
+        assert_eq!(dropped.width, (1000 - 128) / 64 + 1);
+        assert!((dropped.width - 1) * 64 + 128 <= 1000);
+
+        // Exactly one more column, for the partial window
+        spec_compute.set_include_partial_window(true);
+        let padded = spec_compute.compute();
+        assert_eq!(padded.width, dropped.width + 1);
+        assert!((padded.width - 1) * 64 + 128 >= 1000);
+
+        // The last column contains the zero-padded tail, so it can't be empty
+        let last_col: f32 = (0..padded.height)
+            .map(|h| padded.spec[h * padded.width + padded.width - 1])
+            .sum();
+        assert!(last_col > 0.0);
+
+        // When the data ends on a window boundary there is no partial window
+        let mut spec_compute = SpecCompute::new(128, 64, vec![0.5; 128 + 10 * 64], rectangular);
+        assert_eq!(spec_compute.compute().width, 11);
+        spec_compute.set_include_partial_window(true);
+        assert_eq!(spec_compute.compute().width, 11);
+    }
+
+    #[test]
+    fn test_compute_with_progress() {
+        let data: Vec<f32> = (0..100_000).map(|i| (i as f32 * 0.3).sin()).collect();
+        let mut spec_compute = SpecCompute::new(64, 32, data, rectangular);
+
+        let mut progress = vec![];
+        let spec = spec_compute.compute_with_progress(|p| progress.push(p));
+
+        // Throttled to about 100 updates, increasing up to 1.0
+        assert!(spec.width > 3000);
+        assert!(progress.len() <= PROGRESS_UPDATES + 1);
+        assert!(progress.len() >= PROGRESS_UPDATES / 2);
+        assert!(progress.windows(2).all(|p| p[0] < p[1]));
+        assert_eq!(progress.last(), Some(&1.0));
+
+        // The same with several threads, where the blocks finish in any order
+        spec_compute.set_num_threads(4);
+        let mut threaded = vec![];
+        let multi = spec_compute.compute_with_progress(|p| threaded.push(p));
+        assert_eq!(multi.spec, spec.spec);
+        assert_eq!(threaded.len(), progress.len());
+        assert!(threaded.windows(2).all(|p| p[0] < p[1]));
+        assert_eq!(threaded.last(), Some(&1.0));
+    }
+
+    #[test]
+    fn test_non_power_of_two() {
+        // 2000 bins at 8000 Hz is 4 Hz per bin, with 1000 rows
+        for (freq, bin) in [(1000.0, 250), (3000.0, 750), (4.0, 1)] {
+            let data: Vec<f32> = (0..8000)
+                .map(|i| (2.0 * PI * freq * i as f32 / 8000.0).sin())
+                .collect();
+            let mut spec_compute = SpecCompute::new(2000, 500, data, hann_function);
+            spec_compute.set_sample_rate(8000);
+            spec_compute.set_fft_normalisation(FftNormalisation::NumBins);
+            let spec = spec_compute.compute();
+            assert_eq!(spec.height, 1000);
+
+            // The loudest row, counted from the top
+            let column: Vec<f32> = spec.spec.iter().step_by(spec.width).copied().collect();
+            let row = (0..column.len())
+                .max_by(|&a, &b| column[a].total_cmp(&column[b]))
+                .unwrap();
+            assert_eq!(row, spec.height - 1 - bin);
+            assert_eq!(spec.bin_to_freq(bin as f32), freq);
+
+            // A unit sine with a Hann window peaks at a quarter
+            assert!((column[row] - 0.25).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn test_non_power_of_two_render() {
+        // A large prime factor, 2018 = 2 * 1009
+        let data: Vec<f32> = (0..10_000).map(|i| (i as f32 * 0.3).sin()).collect();
+        let spec = SpecCompute::new(2018, 700, data, hann_function).compute();
+        assert_eq!(spec.height, 1009);
+
+        let gradient = crate::ColourGradient::default_theme();
+        for freq_scale in [FrequencyScale::Linear, FrequencyScale::Log] {
+            let img = spec
+                .to_rgba_in_memory(freq_scale, &gradient, 300, 123)
+                .unwrap();
+            assert_eq!(img.len(), 300 * 123 * 4);
+        }
+    }
+
+    #[test]
+    fn test_compute_frame() {
+        let data: Vec<f32> = (0..96)
+            .map(|i| (i as f32 * 0.7).sin() + 0.5 * (i as f32 * 2.1).cos())
+            .collect();
+        let mut spec_compute = SpecCompute::new(32, 16, data.clone(), hann_function);
+        spec_compute.set_fft_normalisation(FftNormalisation::NumBins);
+        let spec = spec_compute.compute();
+
+        // The frame runs from DC, the column from the highest frequency
+        let mut column: Vec<f32> = spec.row_iter().map(|row| row[2]).collect();
+        column.reverse();
+        let frame = spec_compute.compute_frame(&data[32..64]).unwrap();
+        assert_eq!(frame.len(), 16);
+        for (a, b) in frame.iter().zip(&column) {
+            assert!((a - b).abs() < 1e-6);
+        }
+
+        spec_compute.set_two_sided(true);
+        let spec = spec_compute.compute();
+        let mut column: Vec<f32> = spec.row_iter().map(|row| row[1]).collect();
+        column.reverse();
+        let frame = spec_compute.compute_frame(&data[16..48]).unwrap();
+        assert_eq!(frame.len(), 32);
+        for (a, b) in frame.iter().zip(&column) {
+            assert!((a - b).abs() < 1e-6);
+        }
+
+        assert!(matches!(
+            spec_compute.compute_frame(&data[..31]),
+            Err(SonogramError::InvalidBufferSize)
+        ));
+
+        // The buffers are reused between frames
+        let mut out = vec![0.0; 32];
+        let mut buf = Vec::new();
+        for start in [16, 48] {
+            let frame = spec_compute
+                .compute_frame(&data[start..start + 32])
+                .unwrap();
+            spec_compute
+                .compute_frame_into(&data[start..start + 32], &mut out, &mut buf)
+                .unwrap();
+            assert_eq!(out, frame);
+        }
+        assert!(matches!(
+            spec_compute.compute_frame_into(&data[16..48], &mut out[..16], &mut buf),
+            Err(SonogramError::InvalidBufferSize)
+        ));
+    }
+
+    #[test]
+    fn test_cross_spectrum() {
+        let mut seed: u32 = 1;
+        let mut noise = || {
+            (0..16384)
+                .map(|_| {
+                    seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+                    (seed >> 8) as f32 / (1 << 24) as f32 - 0.5
+                })
+                .collect::<Vec<f32>>()
+        };
+        let (a, b) = (noise(), noise());
+
+        // Identical channels are fully coherent, and S_xx = |X|^2
+        let mut x = SpecCompute::new(64, 32, a.clone(), hann_function);
+        let cross = x.cross_spectrum(&x).unwrap();
+        assert_eq!((cross.width(), cross.height()), (511, 32));
+        assert!(cross.coherence().iter().all(|c| (c - 1.0).abs() < 1e-4));
+        let spec = x.compute();
+        for (row, cross_row) in spec.row_iter().zip(cross.cross().chunks(cross.width())) {
+            for (mag, xy) in row.iter().zip(cross_row) {
+                assert!((mag * mag - xy.re).abs() < 1e-3 * (mag * mag).max(1.0));
+                assert!(xy.im.abs() < 1e-3);
+            }
+        }
+
+        // A scaled copy is still fully coherent
+        let scaled = SpecCompute::new(64, 32, a.iter().map(|v| 0.5 * v).collect(), hann_function);
+        let cross = x.cross_spectrum(&scaled).unwrap();
+        assert!(cross.coherence().iter().all(|c| (c - 1.0).abs() < 1e-4));
+
+        // Independent noise is not, about 1 / 510 for each bin
+        let y = SpecCompute::new(64, 32, b, hann_function);
+        let cross = x.cross_spectrum(&y).unwrap();
+        let mean = cross.coherence().iter().sum::<f32>() / cross.height() as f32;
+        assert!(mean < 0.01);
+        assert!(cross.coherence().iter().all(|&c| c < 0.05));
+
+        // The channels must line up
+        let short = SpecCompute::new(64, 32, a[..8192].to_vec(), hann_function);
+        assert!(matches!(
+            x.cross_spectrum(&short),
+            Err(SonogramError::MismatchedSpectrograms)
+        ));
+    }
+
+    #[test]
+    fn test_set_step_size() {
+        let data: Vec<f32> = (0..1024).map(|i| (i as f32 * 0.2).sin()).collect();
+        let mut spec_compute = SpecCompute::new(64, 64, data.clone(), hann_function);
+        let spec = spec_compute.compute();
+        assert_eq!(spec.width, 16);
+
+        // Half the step gives about twice the columns, the same as a new instance
+        spec_compute.set_step_size(32).unwrap();
+        let spec = spec_compute.compute();
+        assert_eq!(spec.width, 31);
+        assert_eq!(spec.step_size, 32);
+        let expected = SpecCompute::new(64, 32, data, hann_function).compute();
+        assert_eq!(spec.spec, expected.spec);
+
+        assert!(matches!(
+            spec_compute.set_step_size(0),
+            Err(SonogramError::InvalidStepSize)
+        ));
+    }
+
+    #[test]
+    fn test_export_processed_samples() {
+        let spec_compute = crate::SpecOptionsBuilder::new(4)
+            .load_data_from_memory_f32(vec![0.1, 0.3, -0.2, -0.4, 0.25, 0.75], 8000)
+            .downsample(2)
+            .normalise()
+            .build()
+            .unwrap();
+        assert_eq!(spec_compute.export_processed_samples(), &[0.4, -0.6, 1.0]);
+
+        let mut spec_compute = SpecCompute::new(4, 4, vec![], hann_function);
+        spec_compute.set_complex_data(vec![Complex::new(1.0, 0.0); 8]);
+        assert!(spec_compute.export_processed_samples().is_empty());
+    }
+
+    #[test]
+    fn test_num_threads() {
+        let data: Vec<f32> = (0..20_000).map(|i| (i as f32 * 0.3).sin()).collect();
+        let mut spec_compute = SpecCompute::new(256, 100, data, hann_function);
+        let single = spec_compute.compute();
+
+        for num_threads in [0, 2, 3, 7] {
+            spec_compute.set_num_threads(num_threads);
+            let multi = spec_compute.compute();
+            assert_eq!(multi.width, single.width);
+            assert_eq!(multi.spec, single.spec);
+        }
+
+        // More threads than windows
+        let mut spec_compute = SpecCompute::new(256, 128, vec![0.5; 512], hann_function);
+        let single = spec_compute.compute();
+        spec_compute.set_num_threads(8);
+        assert_eq!(spec_compute.compute().spec, single.spec);
+    }
+
+    #[test]
+    fn test_overlap_carry() {
+        let data: Vec<f32> = (0..4096).map(|i| (i as f32 * 0.3).sin()).collect();
+        let whole = SpecCompute::new(256, 128, data.clone(), hann_function).compute();
+
+        // Two halves, without the carry a window is lost at the join
+        let mut spec_compute = SpecCompute::new(256, 128, data[..2048].to_vec(), hann_function);
+        let mut joined = spec_compute.compute();
+        spec_compute.set_data(data[2048..].to_vec());
+        joined.append(&spec_compute.compute()).unwrap();
+        assert!(joined.width < whole.width);
+
+        // In uneven blocks with the carry it's the same as in one piece
+        let mut spec_compute = SpecCompute::new(256, 128, vec![], hann_function);
+        spec_compute.set_overlap_carry(true);
+        let mut joined = Spectrogram {
+            sample_rate: 11025,
+            step_size: 128,
+            ..test_spectrogram(vec![], 0, 128)
+        };
+        for block in data.chunks(1000) {
+            spec_compute.set_data(block.to_vec());
+            joined.append(&spec_compute.compute()).unwrap();
+        }
+        assert_eq!(joined.width, whole.width);
+        for (a, b) in joined.spec.iter().zip(&whole.spec) {
+            assert!((a - b).abs() < 1e-4);
+        }
+
+        // The spectrograms must match
+        let other = SpecCompute::new(128, 128, data, hann_function).compute();
+        assert!(matches!(
+            joined.append(&other),
+            Err(SonogramError::MismatchedSpectrograms)
+        ));
+    }
+
+    #[test]
+    fn test_compute_cancellable() {
+        let data: Vec<f32> = (0..10_000).map(|i| (i as f32 * 0.3).sin()).collect();
+        let mut spec_compute = SpecCompute::new(64, 32, data, rectangular);
+
+        let cancel = Arc::new(AtomicBool::new(false));
+        let spec = spec_compute.compute_cancellable(&cancel).unwrap();
+        assert_eq!(spec.spec, spec_compute.compute().spec);
+
+        cancel.store(true, Ordering::Relaxed);
+        assert!(matches!(
+            spec_compute.compute_cancellable(&cancel),
+            Err(SonogramError::Cancelled)
+        ));
+    }
+
+    #[test]
+    fn test_center_windows() {
+        // A click at the first sample
+        let mut data = vec![0.0; 1024];
+        data[0] = 1.0;
+
+        let mut spec_compute = SpecCompute::new(64, 32, data, hann_function);
+        let spec = spec_compute.compute();
+        assert_eq!(spec.width, (1024 - 64) / 32 + 1);
+
+        // The first window is centered on the click, rather than starting on it
+        spec_compute.set_center_windows(true);
+        let centered = spec_compute.compute();
+        assert_eq!(centered.width, 1024 / 32 + 1);
+        assert!(spec.spec[0] < 0.0001);
+        assert!((centered.spec[0] - 1.0).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_padded_window() {
+        let data = [1.0, 2.0, 3.0];
+        let window = |mode| padded_window(&data, -3, 9, mode).collect::<Vec<f32>>();
+
+        assert_eq!(
+            window(PaddingMode::Zero),
+            vec![0.0, 0.0, 0.0, 1.0, 2.0, 3.0, 0.0, 0.0, 0.0]
+        );
+        assert_eq!(
+            window(PaddingMode::Edge),
+            vec![1.0, 1.0, 1.0, 1.0, 2.0, 3.0, 3.0, 3.0, 3.0]
+        );
+        assert_eq!(
+            window(PaddingMode::Reflect),
+            vec![2.0, 3.0, 2.0, 1.0, 2.0, 3.0, 2.0, 1.0, 2.0]
+        );
+    }
+
+    #[test]
+    fn test_padding_mode() {
+        // A steady tone, the last window is mostly padding
+        let data: Vec<f32> = (0..900).map(|i| (i as f32 * 0.3).sin()).collect();
+        let mut spec_compute = SpecCompute::new(256, 256, data, hann_function);
+        spec_compute.set_include_partial_window(true);
+
+        let zero = spec_compute.compute().frame_energy();
+        spec_compute.set_padding_mode(PaddingMode::Reflect);
+        let reflect = spec_compute.compute().frame_energy();
+
+        // Zero padding loses energy, reflecting keeps it close to the full windows
+        let full = zero[0];
+        let last = zero.len() - 1;
+        assert!(zero[last] < 0.6 * full);
+        assert!((reflect[last] - full).abs() < 0.1 * full);
+    }
+
+    #[test]
+    fn test_from_frames() {
+        let data: Vec<f32> = (0..512).map(|i| (i as f32 * 0.3).sin()).collect();
+
+        // Window the frames ourselves
+        let frames: Vec<Vec<f32>> = data
+            .chunks(64)
+            .map(|frame| {
+                frame
+                    .iter()
+                    .enumerate()
+                    .map(|(i, val)| val * hann_function(i, 64))
+                    .collect()
+            })
+            .collect();
+        let spec = SpecCompute::from_frames(frames.clone(), 64).unwrap();
+        assert_eq!(spec.width, 8);
+
+        // Matches the compute path with the same windows
+        let mut spec_compute = SpecCompute::new(64, 64, data, hann_function);
+        spec_compute.set_include_partial_window(true);
+        assert_eq!(spec.spec, spec_compute.compute().spec);
+
+        let mut frames = frames;
+        frames[3].pop();
+        assert!(SpecCompute::from_frames(frames, 64).is_err());
+    }
+
+    #[test]
+    fn test_two_sided() {
+        // A real tone at bin 4 shows at both +4 and -4 around DC
+        let data: Vec<f32> = (0..64)
+            .map(|i| (2.0 * std::f32::consts::PI * 4.0 * i as f32 / 32.0).cos() + 1.0)
+            .collect();
+        let mut spec_compute = SpecCompute::new(32, 32, data, rectangular);
+        spec_compute.set_include_partial_window(true);
+        spec_compute.set_two_sided(true);
+        let spec = spec_compute.compute();
+        assert_eq!(spec.height, 32);
+        assert_eq!(spec.width, 2);
+
+        let column: Vec<f32> = spec.row_iter().map(|row| row[0]).collect();
+        let dc_row = 32 - 16 - 1;
+        assert!((column[dc_row] - 32.0).abs() < 0.001);
+        assert!((column[dc_row - 4] - 16.0).abs() < 0.001);
+        assert!((column[dc_row + 4] - 16.0).abs() < 0.001);
+        assert!((column.iter().sum::<f32>() - 64.0).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_complex_data() {
+        // A complex tone only has a positive frequency
+        let data: Vec<Complex<f32>> = (0..32)
+            .map(|i| Complex::from_polar(1.0, 2.0 * std::f32::consts::PI * 4.0 * i as f32 / 32.0))
+            .collect();
+        let mut spec_compute = SpecCompute::new(32, 32, vec![], rectangular);
+        spec_compute.set_complex_data(data);
+        spec_compute.set_include_partial_window(true);
+        spec_compute.set_two_sided(true);
+        let spec = spec_compute.compute();
+
+        let column: Vec<f32> = spec.row_iter().map(|row| row[0]).collect();
+        let dc_row = 32 - 16 - 1;
+        assert!((column[dc_row - 4] - 32.0).abs() < 0.001);
+        assert!(column[dc_row + 4] < 0.001);
+        assert!((column.iter().sum::<f32>() - 32.0).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_fft_normalisation() {
+        // A 1024 Hz tone falls exactly on a bin for both FFT sizes
+        let data: Vec<f32> = (0..16384)
+            .map(|i| (2.0 * std::f32::consts::PI * 1024.0 * i as f32 / 8192.0).sin())
+            .collect();
+
+        let peak = |num_bins: usize, normalisation: FftNormalisation| {
+            let mut spec_compute = SpecCompute::new(num_bins, num_bins, data.clone(), rectangular);
+            spec_compute.set_fft_normalisation(normalisation);
+            let spec = spec_compute.compute();
+            spec.spec.iter().copied().fold(0.0, f32::max)
+        };
+
+        // Unnormalised the magnitude grows with the FFT size
+        let small = peak(1024, FftNormalisation::None);
+        let large = peak(4096, FftNormalisation::None);
+        assert!((large / small - 4.0).abs() < 0.01);
+
+        let small = peak(1024, FftNormalisation::NumBins);
+        let large = peak(4096, FftNormalisation::NumBins);
+        assert!((small - 0.5).abs() < 0.01);
+        assert!((large - 0.5).abs() < 0.01);
     }
 }
@@ -40,3 +40,66 @@ pub fn blackman_harris(n: usize, samples: usize) -> f32 {
 
     A0 - A1 * f32::cos(arg) + A2 * f32::cos(2.0 * arg) - A3 * f32::cos(3.0 * arg)
 }
+
+///
+/// Compute how far the window function deviates from the constant
+/// overlap-add (COLA) condition when it is applied every `hop` samples.  The
+/// overlapping windows are summed and the result is the peak-to-peak ripple
+/// of that sum, relative to its mean.  Zero means the window and hop satisfy
+/// COLA perfectly.
+///
+/// # Arguments
+///
+///  * `window` - The window function, e.g. [hann_function].  Any closure
+///    taking the sample index and window length works too, so custom window
+///    coefficients can be checked with `|i, _| coeffs[i]`.
+///  * `win_len` - The window length, in samples.
+///  * `hop` - The number of samples between each window.
+///
+pub fn cola_deviation(window: impl Fn(usize, usize) -> f32, win_len: usize, hop: usize) -> f32 {
+    if hop == 0 || win_len == 0 || hop > win_len {
+        return f32::INFINITY;
+    }
+
+    let sums: Vec<f32> = (0..hop)
+        .map(|n| (n..win_len).step_by(hop).map(|i| window(i, win_len)).sum())
+        .collect();
+
+    let min = sums.iter().copied().fold(f32::MAX, f32::min);
+    let max = sums.iter().copied().fold(f32::MIN, f32::max);
+    let mean = sums.iter().sum::<f32>() / hop as f32;
+
+    (max - min) / mean
+}
+
+///
+/// Check the window function and hop satisfy the constant overlap-add (COLA)
+/// condition, within 1%.  This is required to resynthesize audio from the
+/// spectrogram without amplitude ripple.  See [cola_deviation].
+///
+pub fn check_cola(window: impl Fn(usize, usize) -> f32, win_len: usize, hop: usize) -> bool {
+    cola_deviation(window, win_len, hop) <= 0.01
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_cola() {
+        // 50% overlap
+        assert!(check_cola(hann_function, 1024, 512));
+        assert!(check_cola(rectangular, 1024, 1024));
+
+        // 30% overlap
+        assert!(!check_cola(hann_function, 1024, 717));
+        assert!(!check_cola(hann_function, 1024, 0));
+
+        // Custom window coefficients, a periodic Hann window is exactly COLA
+        let coeffs: Vec<f32> = (0..1024)
+            .map(|i| 0.5 * (1.0 - (2.0 * PI * i as f32 / 1024.0).cos()))
+            .collect();
+        assert!(cola_deviation(|i, _| coeffs[i], 1024, 256) < 1e-4);
+        assert!(!check_cola(|i, _| coeffs[i], 1024, 700));
+    }
+}